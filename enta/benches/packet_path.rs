@@ -0,0 +1,188 @@
+//! Cost of moving packets from the TUN device to the stream to ENTG, with the
+//! packets copied into a new allocation and then into a frame as ENTA used to
+//! do, with the packets read into shared chunks and framed in a buffer which
+//! is reused for every frame, as for TLS streams, and with the packets read
+//! into shared chunks and written as they are with vectored writes, as for TCP
+//! streams.
+//! A fake device returning one MTU-sized packet per read stands in for the TUN
+//! device and the frames are discarded, so only the packet path is measured:
+//!
+//!     cargo bench -p enta
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite, LengthDelimitedCodec};
+
+#[path = "../src/packet.rs"]
+#[allow(dead_code)]
+mod packet;
+
+use packet::{FrameWriter, PacketReader};
+
+const PACKET_SIZE: usize = 1400;
+const PACKETS: usize = 10000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns `PACKETS` packets, one per read, like a TUN device
+struct Device {
+    packet: [u8; PACKET_SIZE],
+    remaining: usize,
+}
+
+impl Device {
+    fn new() -> Device {
+        Device {
+            packet: [0x45; PACKET_SIZE],
+            remaining: PACKETS,
+        }
+    }
+}
+
+impl AsyncRead for Device {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            buf.put_slice(&self.packet);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Discards what is written to it like `tokio::io::sink()`, but takes vectored
+/// writes
+struct VectoredSink;
+
+impl AsyncWrite for VectoredSink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Splits the reads of the device into packets like the codec of the tun crate
+struct TunPacketCodec;
+
+impl Decoder for TunPacketCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let packet = buf.split();
+        buf.reserve(PACKET_SIZE);
+        Ok(Some(packet))
+    }
+}
+
+async fn copy_path() {
+    let mut device = FramedRead::new(Device::new(), TunPacketCodec);
+    let mut entg = FramedWrite::new(tokio::io::sink(), LengthDelimitedCodec::new());
+    while let Some(packet) = device.next().await {
+        let packet = Bytes::from(packet.unwrap().to_vec());
+        entg.send(packet).await.unwrap();
+    }
+}
+
+async fn pool_path() {
+    let mut device = PacketReader::new(Device::new());
+    let mut entg = FrameWriter::new(tokio::io::sink(), None);
+    while let Some(packet) = device.read().await.unwrap() {
+        entg.send(packet).await.unwrap();
+    }
+}
+
+async fn vectored_path() {
+    let mut device = PacketReader::new(Device::new());
+    let mut entg = FrameWriter::new(VectoredSink, None);
+    while let Some(packet) = device.read().await.unwrap() {
+        entg.send(packet).await.unwrap();
+    }
+}
+
+fn packet_path(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    for (name, allocations) in [
+        ("copy", count_allocations(|| rt.block_on(copy_path()))),
+        ("pool", count_allocations(|| rt.block_on(pool_path()))),
+        (
+            "vectored",
+            count_allocations(|| rt.block_on(vectored_path())),
+        ),
+    ] {
+        println!(
+            "packet_path/{}: {:.3} allocations per packet",
+            name,
+            allocations as f64 / PACKETS as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("packet_path");
+    group.throughput(Throughput::Bytes((PACKET_SIZE * PACKETS) as u64));
+    group.bench_function("copy", |b| b.iter(|| rt.block_on(copy_path())));
+    group.bench_function("pool", |b| b.iter(|| rt.block_on(pool_path())));
+    group.bench_function("vectored", |b| b.iter(|| rt.block_on(vectored_path())));
+    group.finish();
+}
+
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+criterion_group!(benches, packet_path);
+criterion_main!(benches);
@@ -1,4 +1,12 @@
 fn main() {
+    if std::env::var_os("CARGO_FEATURE_MOCK").is_some() {
+        // The mock backend of rats-tls does not use librats_tls.so
+        return;
+    }
+    if std::env::var_os("CARGO_FEATURE_DLOPEN").is_some() {
+        // librats_tls.so is loaded at runtime, see `--rats-tls-library`
+        return;
+    }
     // Currently Enta will only link to host mode librats_tls.so
     println!("cargo:rustc-link-search=/usr/local/lib/rats-tls");
     println!("cargo:rustc-link-lib=rats_tls");
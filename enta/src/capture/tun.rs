@@ -1,45 +1,273 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::IoSlice;
 use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 
-use anyhow::{ensure, Context, Result};
-use bytes::Bytes;
-use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
+use anyhow::{bail, ensure, Context, Result};
+use futures::future;
+use futures::ready;
+use log::{debug, error, info, warn};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::process::Command;
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tun::{AsyncDevice, Layer, TunPacket};
+use tokio::task::JoinHandle;
 
-use crate::packet::ENPacket;
+use crate::offload::{self, EMPTY_VNET_HDR};
+use crate::packet::{flow_hash, ENPacket, PacketReader, VNET_HDR_LEN};
 
+/// From linux/if_tun.h, `_IOW('T', 202, int)`
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// The part of `struct ifreq` used by TUNSETIFF
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// From linux/if_tun.h, `_IOW('T', 208, unsigned int)`
+const TUNSETOFFLOAD: libc::c_ulong = 0x400454d0;
+/// From linux/if_tun.h, `_IOW('T', 220, int)`
+const TUNSETVNETLE: libc::c_ulong = 0x400454dc;
+
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+const TUN_F_TSO6: libc::c_ulong = 0x04;
+const TUN_F_TSO_ECN: libc::c_ulong = 0x08;
+const TUN_F_USO4: libc::c_ulong = 0x20;
+const TUN_F_USO6: libc::c_ulong = 0x40;
+
+/// One queue of a TUN device. The device is opened without the packet
+/// information header, so each read returns and each write takes a bare IP
+/// packet, which is forwarded as it is. With offloads, the packet follows a
+/// virtio-net header, see `offload`.
+///
+/// Queues are opened directly rather than through rust-tun, which cannot open
+/// more than one queue of an async device. A clone reads and writes the same
+/// queue, so that one task can read it while another writes it.
+#[derive(Clone)]
+pub struct Queue(Arc<AsyncFd<File>>);
+
+impl Queue {
+    /// Opens a queue of the TUN device `name`, creating the device if it does
+    /// not exist, and returns it with the name of the device. An empty name
+    /// lets the kernel pick one. Every queue of a device with more than one
+    /// queue, including the first, has to be opened with `multi_queue`, and
+    /// all of them with the same `vnet_hdr`.
+    fn open(name: &str, multi_queue: bool, vnet_hdr: bool) -> io::Result<(Queue, String)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        if multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+        if vnet_hdr {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: flags as libc::c_short,
+            _pad: [0; 22],
+        };
+        // The name is NUL terminated
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let len = req
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(libc::IFNAMSIZ);
+        let name = String::from_utf8_lossy(&req.name[..len]).into_owned();
+        Ok((Queue(Arc::new(AsyncFd::new(file)?)), name))
+    }
+
+    /// Makes the device hand over TCP, and where the kernel supports it UDP,
+    /// super-packets of up to 64 KiB with their checksums left to be filled
+    /// in, as described by the virtio-net header.
+    fn enable_offload(&self) -> io::Result<()> {
+        let fd = self.0.as_raw_fd();
+        // Fields of the header are in little endian whatever the host is, so
+        // they mean the same to the peer
+        let little_endian: libc::c_int = 1;
+        if unsafe { libc::ioctl(fd, TUNSETVNETLE, &little_endian) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let tso = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_TSO_ECN;
+        // USO is supported since Linux 6.2
+        if unsafe { libc::ioctl(fd, TUNSETOFFLOAD, tso | TUN_F_USO4 | TUN_F_USO6) } < 0
+            && unsafe { libc::ioctl(fd, TUNSETOFFLOAD, tso) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for Queue {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            // The buffer is not initialized first, as a read from the device
+            // only writes to it
+            let unfilled = unsafe { buf.unfilled_mut() };
+            match guard.try_io(|inner| {
+                let n = unsafe {
+                    libc::read(
+                        inner.as_raw_fd(),
+                        unfilled.as_mut_ptr() as *mut libc::c_void,
+                        unfilled.len(),
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            }) {
+                Ok(Ok(n)) => {
+                    unsafe { buf.assume_init(n) };
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Queue {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|inner| io::Write::write(&mut inner.get_ref(), buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|inner| io::Write::write_vectored(&mut inner.get_ref(), bufs)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Creates a TUN device with the given number of queues, which is removed
+/// when all of them are closed.
 pub async fn setup_tun(
     tun_addr: IpAddr,
     tun_mask: IpAddr,
+    mtu: u16,
+    queues: usize,
+    offload: bool,
     capture: Option<u16>,
     replay: Option<u16>,
-) -> Result<AsyncDevice> {
+) -> Result<Vec<Queue>> {
     info!("Setting up TUN device");
 
-    let mut config = tun::Configuration::default();
-    config
-        .layer(Layer::L3)
-        .address(tun_addr)
-        .netmask(tun_mask)
-        .up();
-
-    config.platform(|config| {
-        config.packet_information(true);
-    });
+    let prefix = prefix_len(tun_addr, tun_mask)?;
+    let (first, name) =
+        Queue::open("", queues > 1, offload).context("Failed to create tun device")?;
+    if offload {
+        first
+            .enable_offload()
+            .context("Failed to enable offloads of tun device")?;
+    }
+    let mut devs = vec![first];
+    for _ in 1..queues {
+        let (queue, _) = Queue::open(&name, true, offload).context("Failed to open tun queue")?;
+        devs.push(queue);
+    }
 
-    let dev = tun::create_as_async(&config).context("Failed to create tun device")?;
+    let mut cmd = Command::new("/bin/sh");
+    let scripts = format!(
+        "ip addr add {}/{} dev {} ; \
+        ip link set dev {} mtu {} up",
+        tun_addr, prefix, name, name, mtu
+    );
+    cmd.args(["-e", "-c", &scripts]);
+    let output = cmd.output().await?;
+    ensure!(
+        output.status.success(),
+        "cmd failed: '{:?}' \nstatus: {:?}\nstderr: {}",
+        cmd,
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr)
+    );
     info!(
-        "TUN device is ready, address: {} mask: {}",
-        tun_addr, tun_mask
+        "TUN device {} is ready with {} queue(s), address: {} mask: {} mtu: {}",
+        name, queues, tun_addr, tun_mask, mtu
     );
 
     // Setup iptables rules
     setup_netfilter(capture, replay)
         .await
         .context("Failed to setup netfilter")?;
-    Ok(dev)
+    Ok(devs)
+}
+
+fn prefix_len(addr: IpAddr, mask: IpAddr) -> Result<u32> {
+    let (ones, leading_ones) = match (addr, mask) {
+        (IpAddr::V4(_), IpAddr::V4(mask)) => {
+            let mask = u32::from(mask);
+            (mask.count_ones(), mask.leading_ones())
+        }
+        (IpAddr::V6(_), IpAddr::V6(mask)) => {
+            let mask = u128::from(mask);
+            (mask.count_ones(), mask.leading_ones())
+        }
+        _ => bail!(
+            "Address {} and mask {} are of different families",
+            addr,
+            mask
+        ),
+    };
+    ensure!(ones == leading_ones, "Invalid network mask {}", mask);
+    Ok(ones)
 }
 
 async fn setup_netfilter(capture: Option<u16>, replay: Option<u16>) -> Result<()> {
@@ -119,56 +347,170 @@ pub async fn clean_up(capture: Option<u16>, replay: Option<u16>) -> Result<()> {
     Ok(())
 }
 
+/// Aborts the tasks forwarding packets of the queues when dropped
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Packets read are sent to `outbound_tx`, except that super-packets longer
+/// than `max_frame`, the largest frame ENTG accepts, are segmented first.
 pub async fn exchange_with_tun(
-    dev: AsyncDevice,
+    devs: Vec<Queue>,
+    offload: bool,
+    max_frame: usize,
     outbound_tx: Sender<ENPacket>,
     mut inbound_rx: Receiver<ENPacket>,
 ) -> Result<()> {
-    let (mut split_sink, mut split_stream) = dev.into_framed().split();
+    // Each queue is read and written by tasks of its own, which run in
+    // parallel on a multi-threaded runtime
+    let mut tasks = Tasks(Vec::new());
+    let mut queue_txs = Vec::new();
+    for (index, dev) in devs.into_iter().enumerate() {
+        let (queue_tx, queue_rx) = mpsc::channel(128);
+        queue_txs.push(queue_tx);
+        tasks.0.push(tokio::spawn(from_tun(
+            index,
+            dev.clone(),
+            offload,
+            max_frame,
+            outbound_tx.clone(),
+        )));
+        tasks
+            .0
+            .push(tokio::spawn(to_tun(index, dev, offload, queue_rx)));
+    }
+    drop(outbound_tx);
 
-    let to_tun = async move {
-        loop {
-            match inbound_rx.recv().await {
-                Some(packet) => {
-                    debug!("=> tun: {} bytes packet", packet.len());
-                    if let Err(e) = split_sink.send(TunPacket::new(packet.into())).await {
-                        error!("Failed to send IP packet to TUN device: {}", e);
-                        break;
-                    }
-                }
-                None => {
-                    debug!("Inbound channel closed, close TUN device now");
-                    break;
-                }
+    // Packets of one flow are always written to the same queue, so that they
+    // stay in order
+    let dispatch = async move {
+        while let Some(packet) = inbound_rx.recv().await {
+            let index = flow_hash(&packet) as usize % queue_txs.len();
+            if queue_txs[index].send(packet).await.is_err() {
+                return;
             }
         }
+        debug!("Inbound channel closed, close TUN device now");
     };
 
-    let from_tun = async move {
-        while let Some(frame) = split_stream.next().await {
-            match frame {
-                Ok(packet) => {
-                    debug!("<= tun: {} bytes packet", packet.get_bytes().len());
-                    // TODO: fix double copy here
-                    if let Err(e) = outbound_tx
-                        .send(Bytes::from(packet.get_bytes().to_owned()))
-                        .await
-                    {
-                        debug!("Outbound Channel closed, close TUN device now: {}", e);
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to receive data from TUN device: {}", e);
-                }
+    // Stop the others when one of them finished
+    tokio::select! {
+        _ = dispatch => {},
+        _ = future::select_all(tasks.0.iter_mut()) => {}
+    };
+    Ok(())
+}
+
+async fn to_tun(index: usize, mut dev: Queue, offload: bool, mut queue_rx: Receiver<ENPacket>) {
+    while let Some(packet) = queue_rx.recv().await {
+        debug!("=> tun queue {}: {} bytes packet", index, packet.len());
+        if let Err(e) = write_packet(&mut dev, offload, &packet).await {
+            error!("Failed to send IP packet to TUN device: {}", e);
+            break;
+        }
+    }
+}
+
+/// Writes an `ENPacket` to the TUN device, segmenting it if the device cannot
+async fn write_packet(dev: &mut Queue, offload: bool, packet: &ENPacket) -> io::Result<()> {
+    if !offload::has_vnet_hdr(packet) {
+        return write_bare_packet(dev, offload, packet).await;
+    }
+    if offload {
+        match dev.write(packet).await {
+            // The kernel does not support the offload the packet uses, e.g. USO
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            result => return result.map(drop),
+        }
+    }
+    match offload::segment(packet) {
+        Ok(packets) => {
+            for packet in packets {
+                write_bare_packet(dev, offload, &packet).await?;
             }
         }
-    };
+        Err(e) => warn!("Dropped a packet from ENTG: {}", e),
+    }
+    Ok(())
+}
 
-    // Stop another when one of then finished
-    tokio::select! {
-        _ = to_tun => {},
-        _ = from_tun  => {}
+/// Each write to the TUN device is one packet, which it takes as a whole
+async fn write_bare_packet(dev: &mut Queue, offload: bool, packet: &[u8]) -> io::Result<()> {
+    let (written, len) = if offload {
+        let bufs = [IoSlice::new(&EMPTY_VNET_HDR), IoSlice::new(packet)];
+        (
+            dev.write_vectored(&bufs).await?,
+            VNET_HDR_LEN + packet.len(),
+        )
+    } else {
+        (dev.write(packet).await?, packet.len())
     };
+    if written != len {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            format!("wrote {} bytes of a {} bytes packet", written, len),
+        ));
+    }
     Ok(())
 }
+
+async fn from_tun(
+    index: usize,
+    dev: Queue,
+    offload: bool,
+    max_frame: usize,
+    outbound_tx: Sender<ENPacket>,
+) {
+    let mut reader = PacketReader::new(dev);
+    'read: loop {
+        match reader.read().await {
+            Ok(Some(packet)) => {
+                let packet = if offload {
+                    match offload::from_vnet(packet) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            warn!("Dropped a packet from TUN device: {}", e);
+                            continue;
+                        }
+                    }
+                } else {
+                    packet
+                };
+                debug!("<= tun queue {}: {} bytes packet", index, packet.len());
+                if packet.len() > max_frame {
+                    let segments = match offload::segment(&packet) {
+                        Ok(segments) => segments,
+                        Err(e) => {
+                            warn!("Dropped a packet from TUN device: {}", e);
+                            continue;
+                        }
+                    };
+                    for segment in segments {
+                        if let Err(e) = outbound_tx.send(segment).await {
+                            debug!("Outbound Channel closed, close TUN device now: {}", e);
+                            break 'read;
+                        }
+                    }
+                    continue;
+                }
+                if let Err(e) = outbound_tx.send(packet).await {
+                    debug!("Outbound Channel closed, close TUN device now: {}", e);
+                    break;
+                }
+            }
+            Ok(None) => {
+                debug!("TUN device closed");
+                break;
+            }
+            Err(e) => {
+                error!("Failed to receive data from TUN device: {}", e);
+            }
+        }
+    }
+}
@@ -1,30 +1,38 @@
 mod capture;
+mod offload;
 mod packet;
 
+use std::fmt;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use futures::{SinkExt, StreamExt};
-use log::{debug, error, info, warn};
-use rats_tls::RatsTls;
-use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use log::{debug, info, warn};
+use rats_tls::{AppraisalPolicy, Config, QuoteVerification, RatsTls, RatsTlsConnector, Role, Spid};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tokio::runtime::{self, Runtime};
 use tokio::sync::mpsc;
-use tokio::{
-    net::TcpStream,
-    sync::mpsc::{Receiver, Sender},
-};
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Instant;
+use transport::{Address, Stream};
 
-use packet::ENPacket;
+use packet::{Batch, ENPacket, FrameWriter, VNET_HDR_LEN};
+use protocol::codec::frame_codec;
+use protocol::heartbeat::{self, Heartbeat};
+use protocol::hello::{self, Agreed, Features, Hello, Node, NodeId};
+use protocol::padding::{self, Padding, Shaper};
+use protocol::relink::{LinkReader, Relink};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Host address and port of ENTG
+    /// Address of ENTG, "host:port", "unix:<path>" or "vsock:<cid>:<port>"
     #[clap(long, value_parser, default_value = "127.0.0.1:6980")]
-    entg_connect: String,
+    entg_connect: Address,
 
     /// Set address for tun device
     #[clap(long, value_parser, default_value = "192.168.0.1")]
@@ -34,10 +42,137 @@ struct Args {
     #[clap(long, value_parser, default_value = "255.255.255.0")]
     tun_mask: IpAddr,
 
+    /// Number of queues of the tun device, each read and written by tasks of its own
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..=256), default_value_t = 1)]
+    tun_queues: u16,
+
+    /// MTU of the tun device
+    #[clap(long, value_parser = clap::value_parser!(u16).range(68..), default_value_t = 1500)]
+    tun_mtu: u16,
+
+    /// Read and write TCP and UDP super-packets of up to 64 KiB with virtio-net headers, instead of one packet per MTU. Super-packets are segmented by the receiving ENTA if its tun device does not use offloads
+    #[clap(long, value_parser, default_value_t = false)]
+    tun_offload: bool,
+
+    /// Largest frame accepted from ENTG, in bytes. A longer one closes the connection. Another ENTA with --tun-offload may send frames of up to 65545 bytes, which this one segments if it does not use offloads
+    #[clap(long, value_parser = clap::value_parser!(u32).range(68..=protocol::codec::MAX_FRAME_SIZE as i64), default_value_t = protocol::codec::MAX_FRAME_SIZE as u32)]
+    max_frame_size: u32,
+
+    /// Fail the handshake with ENTG, rats-tls and the exchange of hellos, if it stops responding for this many seconds
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 30)]
+    handshake_timeout: u64,
+
     /// Establish rats-tls connection with entg
     #[clap(long, value_parser, default_value_t = false)]
     entg_rats_tls: bool,
 
+    /// Appraisal policy file (JSON) that the evidence of ENTG must satisfy
+    #[clap(long, value_parser)]
+    entg_policy: Option<PathBuf>,
+
+    /// Role in the rats-tls handshake with ENTG, "client" or "server"
+    #[clap(long, value_parser, default_value = "client")]
+    entg_role: Role,
+
+    /// TLS wrapper type of the rats-tls connection with ENTG
+    #[clap(long, value_parser, default_value = "openssl")]
+    entg_tls_type: String,
+
+    /// Crypto wrapper type of the rats-tls connection with ENTG
+    #[clap(long, value_parser, default_value = "openssl")]
+    entg_crypto: String,
+
+    /// Attester type of the rats-tls connection with ENTG [default: "sgx_ecdsa" for server or mutual, otherwise "nullattester"]
+    #[clap(long, value_parser)]
+    entg_attester: Option<String>,
+
+    /// Verifier type of the rats-tls connection with ENTG [default: "sgx_ecdsa" for client or mutual, otherwise "nullverifier"]
+    #[clap(long, value_parser)]
+    entg_verifier: Option<String>,
+
+    /// Attest both sides of the rats-tls connection with ENTG
+    #[clap(long, value_parser, default_value_t = false)]
+    entg_mutual: bool,
+
+    /// Where sgx_ecdsa quotes of ENTG are verified, "qvl" (Quote Verification Library) or "qel" (Quote Verification Enclave) [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    entg_quote_verification: Option<QuoteVerification>,
+
+    /// Type of the certification data in sgx_ecdsa quotes presented to ENTG, 1 to 7, e.g. 5 for the PCK certificate chain [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    entg_quote_cert_type: Option<u8>,
+
+    /// SPID (32 hex digits) for sgx_epid quotes presented to ENTG, required by the sgx_epid attester
+    #[clap(long, value_parser)]
+    entg_epid_spid: Option<Spid>,
+
+    /// Request linkable sgx_epid quotes for ENTG
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        requires = "entg-epid-spid"
+    )]
+    entg_epid_linkable: bool,
+
+    /// Attest ENTG again every given number of seconds: the link moves to a new connection, on which ENTG presents fresh evidence appraised against the --entg-policy file reloaded from disk, and is closed if ENTG fails the appraisal
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), requires = "entg-rats-tls")]
+    entg_reattest_interval: Option<u64>,
+
+    /// Size in bytes of the buffers of rats-tls connections, which bounds the size of the TLS records sent, up to 16384
+    #[clap(long, value_parser, default_value_t = rats_tls::DEFAULT_BUFFER_SIZE)]
+    rats_tls_buffer_size: usize,
+
+    /// Write the frames of the packets queued for ENTG together, up to this many bytes, instead of one write per packet
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: Option<u32>,
+
+    /// Wait up to this many microseconds for more packets before writing a batch which is not full
+    #[clap(long, value_parser, default_value_t = 0, requires = "batch-size")]
+    batch_latency: u64,
+
+    /// Pad the frames of packets sent to ENTG to the smallest of these sizes in bytes (comma-separated) they fit in, so that their lengths tell less about the packets
+    #[clap(long, value_parser, value_delimiter = ',')]
+    entg_pad_to: Vec<usize>,
+
+    /// Send frames to ENTG at this constant rate per second, all padded to the largest --entg-pad-to size, with cover frames when there are no packets to send
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "entg-pad-to",
+        conflicts_with = "batch-size"
+    )]
+    entg_shape_rate: Option<u32>,
+
+    /// Ping ENTG every given number of seconds, and close the link if it stops answering. ENTG needs --enta-heartbeat-interval to answer
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    entg_heartbeat_interval: Option<u64>,
+
+    /// Number of pings in a row ENTG may leave unanswered before the link is closed
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 3)]
+    heartbeat_misses: u32,
+
+    /// Reconnect to ENTG this many seconds after the link fails or is closed, instead of exiting, and keep trying at this interval
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_interval: Option<u64>,
+
+    /// Id of this ENTA in its hello, which names it in the logs of ENTG [default: the host name]
+    #[clap(long, value_parser)]
+    node_id: Option<NodeId>,
+
+    /// Log the statistics of the link with ENTG every given number of seconds, besides when it is closed
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: Option<u64>,
+
+    /// Run on a multi-threaded runtime with this many worker threads, instead of on a single thread
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    worker_threads: Option<u16>,
+
+    /// Path of librats_tls.so to load at runtime
+    #[cfg(feature = "dlopen")]
+    #[clap(long, value_parser, default_value = rats_tls::DEFAULT_LIBRARY_PATH)]
+    rats_tls_library: PathBuf,
+
     /// The dport of the packet that needs to be captured. This option is set on the client side.
     #[clap(long, value_parser)]
     capture: Option<u16>, // TODO: capture more than one dport
@@ -50,10 +185,12 @@ struct Args {
 trait AsyncStream: AsyncRead + AsyncWrite {}
 
 impl AsyncStream for DuplexStream {}
-impl AsyncStream for TcpStream {}
+impl AsyncStream for Stream {}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+/// A connection with ENTG, over rats-tls or not
+type EntgStream = Pin<Box<dyn AsyncStream>>;
+
+fn main() -> Result<()> {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
@@ -62,130 +199,616 @@ async fn main() -> Result<()> {
     let capture = args.capture;
     let replay = args.replay;
 
-    let result = run(args).await;
+    runtime(args.worker_threads)?.block_on(async {
+        let result = run(args).await;
 
-    // Clean up before program exit
-    if let Err(err) = capture::tun::clean_up(capture, replay).await {
-        warn!("Failed to clean up: {}", err);
-    }
-    result
+        // Clean up before program exit
+        if let Err(err) = capture::tun::clean_up(capture, replay).await {
+            warn!("Failed to clean up: {}", err);
+        }
+        result
+    })
+}
+
+/// Builds a runtime on the current thread, or a multi-threaded one with the
+/// given number of worker threads.
+fn runtime(worker_threads: Option<u16>) -> std::io::Result<Runtime> {
+    let mut builder = match worker_threads {
+        Some(worker_threads) => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(worker_threads.into());
+            builder
+        }
+        None => runtime::Builder::new_current_thread(),
+    };
+    builder.enable_all().build()
 }
 
 async fn run(args: Args) -> Result<()> {
-    let stream = connect_to_entg(&args.entg_connect, args.entg_rats_tls).await?;
-    let dev =
-        capture::tun::setup_tun(args.tun_addr, args.tun_mask, args.capture, args.replay).await?;
-
-    // Create two channels as a bridge between tun device and entg. Data received 
-    // from entg will first be written to a channel named (inbound_tx,inbound_rx) 
-    // and then passed to the TUN device. In contrast, data from TUN device will 
-    // be put into a channel named (outbound_tx,outbound_rx) and then read out and 
+    #[cfg(feature = "dlopen")]
+    if args.entg_rats_tls {
+        rats_tls::load_library(&args.rats_tls_library).context("Failed to load librats_tls")?;
+    }
+
+    let entg_tls = if args.entg_rats_tls {
+        let mut config = Config::with_plugins(
+            args.entg_role,
+            args.entg_mutual,
+            args.entg_tls_type,
+            args.entg_crypto,
+            args.entg_attester,
+            args.entg_verifier,
+        );
+        config.set_quote_options(
+            args.entg_quote_verification,
+            args.entg_quote_cert_type,
+            args.entg_epid_spid,
+            args.entg_epid_linkable,
+        );
+        config.buffer_size = args.rats_tls_buffer_size;
+        config.handshake_timeout = Some(Duration::from_secs(args.handshake_timeout));
+        config.policy = args
+            .entg_policy
+            .as_ref()
+            .map(AppraisalPolicy::from_file)
+            .transpose()
+            .context("Failed to load appraisal policy for ENTG")?
+            .map(Arc::new);
+        Some(RatsTlsConnector::new(config).context("Failed to init rats-tls")?)
+    } else {
+        None
+    };
+    let max_frame_size = args.max_frame_size as usize;
+    // ENTA follows the link to a new connection whenever ENTG asks it to
+    let mut features = Features::RELINK;
+    features.set(Features::BATCHING, args.batch_size.is_some());
+    features.set(Features::VNET_HDR, args.tun_offload);
+    features.set(Features::PADDING, !args.entg_pad_to.is_empty());
+    features.set(Features::HEARTBEAT, args.entg_heartbeat_interval.is_some());
+    let node_id = args.node_id.unwrap_or_else(NodeId::hostname);
+    let mut greeting = Greeting {
+        hello: Hello::new(Node::Enta, node_id, features, max_frame_size),
+        largest_frame: match args.tun_offload {
+            true => usize::from(args.tun_mtu) + VNET_HDR_LEN,
+            false => args.tun_mtu.into(),
+        },
+        timeout: Duration::from_secs(args.handshake_timeout),
+    };
+    let (stream, session, node_id, agreed) =
+        connect_to_entg(&args.entg_connect, entg_tls.as_ref(), &greeting).await?;
+    // Super-packets longer than the link carries are segmented, and ENTG has
+    // to accept the longest of the others after a reconnect as well
+    if args.tun_offload {
+        greeting.largest_frame = agreed.max_frame_size as usize;
+    }
+    let devs = capture::tun::setup_tun(
+        args.tun_addr,
+        args.tun_mask,
+        args.tun_mtu,
+        args.tun_queues.into(),
+        args.tun_offload,
+        args.capture,
+        args.replay,
+    )
+    .await?;
+
+    // Create two channels as a bridge between tun device and entg. Data received
+    // from entg will first be written to a channel named (inbound_tx,inbound_rx)
+    // and then passed to the TUN device. In contrast, data from TUN device will
+    // be put into a channel named (outbound_tx,outbound_rx) and then read out and
     // sent to entg.
-    let (outbound_tx, outbound_rx) = mpsc::channel(128);
+    let (outbound_tx, mut outbound_rx) = mpsc::channel(128);
     let (inbound_tx, inbound_rx) = mpsc::channel(128);
-    let task1 = exchange_with_entg(stream, inbound_tx, outbound_rx);
-    let task2 = capture::tun::exchange_with_tun(dev, outbound_tx, inbound_rx);
+    let batch = args.batch_size.map(|size| Batch {
+        size: size as usize,
+        latency: Duration::from_micros(args.batch_latency),
+    });
+    let padding = if args.entg_pad_to.is_empty() {
+        None
+    } else {
+        Some(Padding::new(args.entg_pad_to).map_err(anyhow::Error::msg)?)
+    };
+    let heartbeat_stats = Arc::new(heartbeat::Stats::default());
+    let mut stats: Vec<(&str, Arc<dyn fmt::Display + Send + Sync>)> = Vec::new();
+    if let Some(padding) = &padding {
+        stats.push(("Padding to ENTG", padding.stats()));
+    }
+    if args.entg_heartbeat_interval.is_some() {
+        stats.push(("Heartbeat with ENTG", heartbeat_stats.clone()));
+    }
+    let shape_rate = args.entg_shape_rate;
+    // Shaped links send every frame at the largest size, so packets have to
+    // fit in it, and super-packets are segmented to fit as well
+    let mut tun_max_frame = greeting.largest_frame;
+    if let (Some(_), Some(padding)) = (shape_rate, &padding) {
+        if padding.largest() < usize::from(args.tun_mtu) {
+            bail!(
+                "--entg-shape-rate sends frames of {} bytes, less than --tun-mtu {}",
+                padding.largest(),
+                args.tun_mtu
+            );
+        }
+        tun_max_frame = tun_max_frame.min(padding.largest());
+    }
+    let heartbeat = args
+        .entg_heartbeat_interval
+        .map(|interval| (Duration::from_secs(interval), args.heartbeat_misses));
+    let reconnect_interval = args.reconnect_interval.map(Duration::from_secs);
+    let entg_connect = args.entg_connect;
+    let entg_policy = args.entg_policy;
+    let entg_reattest_interval = args.entg_reattest_interval;
+    let task1 = async move {
+        let (mut stream, mut session, mut node_id, mut agreed, mut entg_tls) =
+            (stream, session, node_id, agreed, entg_tls);
+        loop {
+            // Only what ENTG uses as well goes on the link
+            let on = |feature| agreed.features.contains(feature);
+            let padding = padding.clone().filter(|_| on(Features::PADDING));
+            let link = Link {
+                max_frame_size: agreed.max_frame_size as usize,
+                batch: batch.filter(|_| on(Features::BATCHING)),
+                padding: padding.clone(),
+                shaper: shape_rate
+                    .zip(padding)
+                    .map(|(rate, padding)| Shaper::new(padding, rate)),
+                heartbeat: heartbeat.filter(|_| on(Features::HEARTBEAT)).map(
+                    |(interval, misses)| Heartbeat::new(interval, misses, heartbeat_stats.clone()),
+                ),
+                timeout: greeting.timeout,
+            };
+            let (readers_tx, readers) = mpsc::channel(1);
+            let (writers_tx, writers) = mpsc::channel(1);
+            let (asked_tx, asked_rx) = mpsc::channel(1);
+            let moves = Moves {
+                readers,
+                writers,
+                asked_tx,
+            };
+            let relinks = Relinks {
+                entg_connect: &entg_connect,
+                greeting: &greeting,
+                node_id,
+                agreed,
+                policy: entg_policy.as_deref(),
+                interval: entg_reattest_interval.map(Duration::from_secs),
+                readers_tx,
+                writers_tx,
+                asked_rx,
+            };
+            let result = tokio::select! {
+                r = exchange_with_entg(stream, link, moves, &inbound_tx, &mut outbound_rx) => r,
+                Err(e) = relink(relinks, session, &mut entg_tls) => Err(e),
+            };
+            let mut interval = match (result, reconnect_interval) {
+                (Ok(Closed::ByEnta), _) | (Ok(Closed::ByEntg), None) => return Ok(()),
+                (Err(e), None) => return Err(e),
+                (Ok(Closed::ByEntg), Some(interval)) => interval,
+                (Err(e), Some(interval)) => {
+                    warn!("{:#}", e);
+                    interval
+                }
+            };
+            let reconnected = loop {
+                info!("Reconnecting to ENTG in {} seconds", interval.as_secs());
+                tokio::time::sleep(interval).await;
+                match (
+                    connect_to_entg(&entg_connect, entg_tls.as_ref(), &greeting).await,
+                    reconnect_interval,
+                ) {
+                    (Ok(reconnected), _) => break reconnected,
+                    (Err(e), None) => return Err(e),
+                    (Err(e), Some(reconnect_interval)) => {
+                        warn!("{:#}", e);
+                        interval = reconnect_interval;
+                    }
+                }
+            };
+            stream = reconnected.0;
+            session = reconnected.1;
+            node_id = reconnected.2;
+            agreed = reconnected.3;
+        }
+    };
+    let task2 = capture::tun::exchange_with_tun(
+        devs,
+        args.tun_offload,
+        tun_max_frame,
+        outbound_tx,
+        inbound_rx,
+    );
 
     let handle = async { tokio::join!(task1, task2) };
-    tokio::select! {
+    let result = tokio::select! {
         (first, second) = handle => { first.and(second) }
+        _ = report_stats(&stats, args.stats_interval) => { Ok(()) }
         _ = tokio::signal::ctrl_c() => { Ok(()) }
+    };
+
+    for (name, stats) in &stats {
+        info!("{}: {}", name, stats);
+    }
+    result
+}
+
+/// Logs `stats` every `interval` seconds. Never returns.
+async fn report_stats(
+    stats: &[(&str, Arc<dyn fmt::Display + Send + Sync>)],
+    interval: Option<u64>,
+) {
+    let interval = match interval {
+        Some(interval) if !stats.is_empty() => Duration::from_secs(interval),
+        _ => return futures::future::pending().await,
+    };
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        for (name, stats) in stats {
+            info!("{}: {}", name, stats);
+        }
     }
 }
 
+/// The hello ENTA sends to ENTG, and what the hello of ENTG has to agree to
+struct Greeting {
+    hello: Hello,
+    /// Largest frame ENTA sends, which ENTG has to accept
+    largest_frame: usize,
+    timeout: Duration,
+}
+
 async fn connect_to_entg(
-    entg_connect: &str,
-    entg_rats_tls: bool,
-) -> Result<Pin<Box<dyn AsyncStream>>> {
+    entg_connect: &Address,
+    entg_tls: Option<&RatsTlsConnector>,
+    greeting: &Greeting,
+) -> Result<(EntgStream, Option<Arc<RatsTls>>, NodeId, Agreed)> {
     info!("Connecting to ENTG");
-    let stream = TcpStream::connect(entg_connect)
+    let stream = transport::connect(entg_connect)
         .await
         .with_context(|| format!("Falied to connect to ENTG: {}", entg_connect))?;
     info!(
         "Connection with ENTG is established, peer address: {}",
         stream.peer_addr()?
     );
-    if entg_rats_tls {
-        let stream = upgrade_to_rats_tls(stream).await?;
+    let (mut stream, session): (EntgStream, _) = if let Some(connector) = entg_tls {
+        let (session, stream) = connector
+            .connect_shared(stream)
+            .await
+            .context("Failed in rats-tls negotiation")?;
         info!("Rats-tls channel with ENTG is established");
-        Ok(Box::pin(stream))
+        (Box::pin(stream), Some(session))
     } else {
-        Ok(Box::pin(stream))
+        (Box::pin(stream), None)
+    };
+
+    let ours = &greeting.hello;
+    let theirs = tokio::time::timeout(greeting.timeout, hello::exchange(&mut stream, ours))
+        .await
+        .context("Timed out waiting for the hello of ENTG")?
+        .context("Failed to exchange hellos with ENTG")?;
+    let agreed = hello::agree(ours, &theirs, Node::Entg).context("Failed to agree with ENTG")?;
+    if (theirs.max_frame_size as usize) < greeting.largest_frame {
+        bail!(
+            "ENTG {} accepts frames of up to {} bytes, but ENTA sends up to {}, raise --max-frame-size of ENTG",
+            theirs.node_id,
+            theirs.max_frame_size,
+            greeting.largest_frame
+        );
+    }
+    if ours.features.contains(Features::VNET_HDR) && !agreed.features.contains(Features::VNET_HDR) {
+        bail!(
+            "ENTG {} does not accept super-packets, run ENTA without --tun-offload",
+            theirs.node_id
+        );
+    }
+    info!(
+        "ENTG {} speaks version {} of the protocol, features on the link: {}",
+        theirs.node_id, agreed.version, agreed.features
+    );
+    let off = ours.features.without(agreed.features);
+    if !off.is_empty() {
+        warn!("ENTG does not use {}, which stays off on the link", off);
     }
+    Ok((stream, session, theirs.node_id, agreed))
 }
 
-async fn upgrade_to_rats_tls(stream: TcpStream) -> Result<DuplexStream> {
-    let tls = RatsTls::new(
-        false,
-        0,
-        Some("openssl"),
-        Some("openssl"),
-        Some("nullattester"),
-        Some("sgx_ecdsa"),
-        false,
-    )
-    .map_err(|err| anyhow!("Failed to init rats-tls: error {:#x}", err))?;
+/// What `relink()` needs to move the link with ENTG to a new connection
+struct Relinks<'a> {
+    entg_connect: &'a Address,
+    greeting: &'a Greeting,
+    /// The ENTG on the link, which has to be the one on the new connection
+    node_id: NodeId,
+    agreed: Agreed,
+    policy: Option<&'a Path>,
+    /// How often ENTG is attested again, if at all
+    interval: Option<Duration>,
+    readers_tx: Sender<ReadHalf<EntgStream>>,
+    writers_tx: Sender<Relink<WriteHalf<EntgStream>>>,
+    /// Requests of ENTG to connect again
+    asked_rx: Receiver<()>,
+}
 
-    tls.negotiate_async(stream)
-        .await
-        .context("Failed in rats-tls negotiation")
+/// Moves the link with ENTG to a new connection every `interval`, to attest
+/// ENTG again, and whenever ENTG asks for it. The new connection is set up next
+/// to the old one, which carries packets until the new one is ready, and the
+/// link only moves once ENTG has been attested on it. Returns an error once the
+/// link has to be closed: when ENTG fails the appraisal of its evidence, or
+/// cannot be attested again within the handshake timeout. Never returns
+/// otherwise.
+async fn relink(
+    relinks: Relinks<'_>,
+    mut session: Option<Arc<RatsTls>>,
+    entg_tls: &mut Option<RatsTlsConnector>,
+) -> Result<()> {
+    let Relinks {
+        entg_connect,
+        greeting,
+        node_id,
+        agreed,
+        policy,
+        interval,
+        readers_tx,
+        writers_tx,
+        mut asked_rx,
+    } = relinks;
+    if interval.is_some() && !agreed.features.contains(Features::RELINK) {
+        bail!(
+            "ENTG {} cannot move the link to a new connection, which attesting it again needs",
+            node_id
+        );
+    }
+    let mut due = interval.map(|interval| Instant::now() + interval);
+    loop {
+        let next_attestation = async {
+            match due {
+                Some(due) => tokio::time::sleep_until(due).await,
+                None => futures::future::pending().await,
+            }
+        };
+        let reattesting = tokio::select! {
+            _ = next_attestation => true,
+            Some(()) = asked_rx.recv() => false,
+        };
+        if reattesting {
+            due = interval.map(|interval| Instant::now() + interval);
+            if let Some(session) = &session {
+                if let Some(policy) = reappraise(session, policy)? {
+                    *entg_tls = entg_tls.take().map(|tls| tls.with_policy(Arc::new(policy)));
+                }
+            }
+            info!("Attesting ENTG again on a new connection");
+        } else {
+            info!("ENTG asks to move the link to a new connection");
+        }
+
+        let deadline = Instant::now() + greeting.timeout;
+        let connected = loop {
+            let connect = connect_to_entg(entg_connect, entg_tls.as_ref(), greeting);
+            match tokio::time::timeout_at(deadline, connect).await {
+                Ok(Ok(connected)) => break Some(connected),
+                Ok(Err(e)) if rejected(&e) => {
+                    return Err(e.context("Re-attestation of ENTG failed, closing the link"))
+                }
+                Ok(Err(e)) => warn!("{:#}", e),
+                Err(_) => break None,
+            }
+            if Instant::now() + RELINK_RETRY_INTERVAL >= deadline {
+                break None;
+            }
+            tokio::time::sleep(RELINK_RETRY_INTERVAL).await;
+        };
+        let (stream, new_session, new_node_id, new_agreed) = match connected {
+            Some(connected) => connected,
+            None if reattesting => bail!(
+                "ENTG could not be attested again within {} seconds, closing the link",
+                greeting.timeout.as_secs()
+            ),
+            None => {
+                warn!("Failed to move the link to a new connection, staying on the old one");
+                continue;
+            }
+        };
+        if new_node_id != node_id || new_agreed != agreed {
+            bail!(
+                "ENTG {} on the new connection is not the one on the link, closing the link",
+                new_node_id
+            );
+        }
+        // The reader turns to the new connection once ENTG moved as well
+        let (reader, writer) = tokio::io::split(stream);
+        let _ = readers_tx.send(reader).await;
+        let _ = writers_tx.send(Relink::MoveTo(writer)).await;
+        session = new_session;
+        info!("Moved the link with ENTG to the new connection");
+    }
+}
+
+/// How long to wait before connecting again when a new connection for the
+/// link fails
+const RELINK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reloads the policy from `policy`, and appraises the evidence of the current
+/// session against it, which fails right away if the policy file alone revokes
+/// ENTG. Returns the reloaded policy for the fresh evidence to be appraised
+/// against.
+fn reappraise(session: &RatsTls, policy: Option<&Path>) -> Result<Option<AppraisalPolicy>> {
+    let policy = policy
+        .map(AppraisalPolicy::from_file)
+        .transpose()
+        .context("Failed to reload appraisal policy for ENTG")?;
+    session
+        .reappraise_handshake_evidence(policy.as_ref().unwrap_or(&AppraisalPolicy::default()))
+        .context("Re-attestation of ENTG failed, closing the link")?;
+    Ok(policy)
+}
+
+/// Whether `e` is the appraisal policy rejecting the evidence of the peer
+fn rejected(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        let e = e
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<rats_tls::Error>());
+        matches!(e, Some(rats_tls::Error::Rejected(_)))
+    })
+}
+
+/// How frames are sent and received on the link with ENTG
+struct Link {
+    /// Largest frame accepted from ENTG
+    max_frame_size: usize,
+    batch: Option<Batch>,
+    padding: Option<Padding>,
+    shaper: Option<Shaper>,
+    heartbeat: Option<Heartbeat>,
+    /// How long the reader waits for the new connection once ENTG moved the
+    /// link
+    timeout: Duration,
+}
+
+/// The new connections the link with ENTG moves to, for the tasks reading and
+/// writing it
+struct Moves<T> {
+    readers: Receiver<ReadHalf<T>>,
+    writers: Receiver<Relink<WriteHalf<T>>>,
+    /// Where requests of ENTG to connect again go
+    asked_tx: Sender<()>,
+}
+
+/// Which side closed a link with ENTG that ended without an error
+enum Closed {
+    /// The tun device is gone, there is nothing more to forward
+    ByEnta,
+    ByEntg,
 }
 
 async fn exchange_with_entg<T>(
     stream: T,
-    inbound_tx: Sender<ENPacket>,
-    mut outbound_rx: Receiver<ENPacket>,
-) -> Result<()>
+    link: Link,
+    moves: Moves<T>,
+    inbound_tx: &Sender<ENPacket>,
+    outbound_rx: &mut Receiver<ENPacket>,
+) -> Result<Closed>
 where
     T: AsyncRead + AsyncWrite + 'static,
 {
-    let (mut split_sink, mut split_stream) =
-        Framed::new(stream, LengthDelimitedCodec::new()).split();
+    let (reader, writer) = tokio::io::split(stream);
+    let Moves {
+        readers,
+        mut writers,
+        asked_tx,
+    } = moves;
+    let mut split_stream = LinkReader::new(reader, frame_codec(link.max_frame_size)).follow(
+        readers,
+        asked_tx,
+        link.timeout,
+    );
+    let Link {
+        batch,
+        padding,
+        shaper,
+        heartbeat,
+        ..
+    } = link;
+    let heartbeat = heartbeat.as_ref();
+    // Pings and pongs, which are sent ahead of the packets
+    let (control_tx, mut control_rx) = mpsc::channel(16);
+    let pong_tx = control_tx.clone();
     let to_entg = async move {
+        if let Some(mut shaper) = shaper {
+            // Frames are padded by the shaper
+            let mut writer = FrameWriter::new(writer, None);
+            loop {
+                tokio::select! {
+                    frames = shaper.next_frames(outbound_rx) => match frames {
+                        Some(frames) => writer
+                            .send_frames(frames)
+                            .await
+                            .context("Failed to send data to ENTG")?,
+                        None => break,
+                    },
+                    Some(frame) = control_rx.recv() => shaper.push(frame),
+                    Some(relink) = writers.recv() => writer
+                        .relink(relink)
+                        .await
+                        .context("Failed to move the link with ENTG")?,
+                }
+            }
+            info!("No more packets to send to ENTG, shutdown connection to ENTG");
+            return Ok(Closed::ByEnta);
+        }
+        let mut writer = FrameWriter::new(writer, padding);
         loop {
-            match outbound_rx.recv().await {
-                Some(packet) => {
-                    debug!("=> entg: {} bytes packet", packet.len());
-                    if let Err(e) = split_sink.send(packet.into()).await {
-                        error!("Failed to send data to ENTG: {}", e);
-                        break;
-                    }
+            tokio::select! {
+                biased;
+                Some(frame) = control_rx.recv() => {
+                    writer.send(frame).await.context("Failed to send data to ENTG")?;
                 }
-                None => {
-                    info!("No more packets to send to ENTG, shutdown connection to ENTG");
-                    break;
+                Some(relink) = writers.recv() => {
+                    writer.relink(relink).await.context("Failed to move the link with ENTG")?;
                 }
+                packet = outbound_rx.recv() => match packet {
+                    Some(packet) => {
+                        debug!("=> entg: {} bytes packet", packet.len());
+                        let sent = match batch {
+                            Some(batch) => writer
+                                .send_batch(packet, outbound_rx, batch)
+                                .await
+                                .map(|count| debug!("=> entg: batch of {} packets", count)),
+                            None => writer.send(packet).await,
+                        };
+                        sent.context("Failed to send data to ENTG")?;
+                    }
+                    None => {
+                        info!("No more packets to send to ENTG, shutdown connection to ENTG");
+                        return Ok(Closed::ByEnta);
+                    }
+                },
             }
         }
     };
 
     let from_entg = async move {
-        loop {
-            match split_stream.next().await {
-                Some(Ok(packet)) => {
-                    debug!("<= entg: {} bytes packet", packet.len());
-                    if let Err(e) = inbound_tx.send(packet.freeze()).await {
-                        info!(
-                            "All capturers are closed. We will drop the subsequent packets from ENTG: {}",
-                            e
-                        );
-                        break;
-                    }
-                }
-                Some(Err(e)) => {
-                    error!("Failed to receive data from ENTG: {}", e);
-                }
-                None => {
-                    info!("Connection with ENTG was closed");
-                    break;
+        while let Some(frame) = split_stream.next().await {
+            let frame = frame
+                .context("Failed to receive data from ENTG, closing the connection")?
+                .freeze();
+            if heartbeat::is_heartbeat(&frame) {
+                if let Some(pong) = heartbeat::receive(&frame, heartbeat) {
+                    // A pong which does not fit is as good as lost on the way
+                    let _ = pong_tx.try_send(pong);
                 }
+                continue;
+            }
+            // Cover frames are dropped and padding is cut off
+            let packet = match padding::unpad(frame) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            debug!("<= entg: {} bytes packet", packet.len());
+            if let Err(e) = inbound_tx.send(packet).await {
+                info!(
+                    "All capturers are closed. We will drop the subsequent packets from ENTG: {}",
+                    e
+                );
+                return Ok(Closed::ByEnta);
             }
         }
+        info!("Connection with ENTG was closed");
+        Ok(Closed::ByEntg)
+    };
+    let ping = async {
+        match heartbeat {
+            Some(heartbeat) => heartbeat.ping(&control_tx).await,
+            None => futures::future::pending().await,
+        }
     };
     // Stop another when one of then finished
     tokio::select! {
-        _ = to_entg => {},
-        _ = from_entg  => {}
+        r = to_entg => r,
+        r = from_entg => r,
+        r = ping => {
+            r.context("ENTG stopped answering pings, closing the connection")?;
+            Ok(Closed::ByEntg)
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,358 @@
+use anyhow::{bail, ensure, Result};
+use bytes::{Buf, BytesMut};
+
+use crate::packet::{ENPacket, VNET_HDR_LEN};
+
+/// Header of a packet that needs neither segmentation nor a checksum
+pub const EMPTY_VNET_HDR: [u8; VNET_HDR_LEN] = [0; VNET_HDR_LEN];
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_UDP_L4: u8 = 5;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// `struct virtio_net_hdr`, with the fields in little endian as the TUN
+/// device is set up with TUNSETVNETLE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VnetHdr {
+    flags: u8,
+    gso_type: u8,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHdr {
+    fn parse(buf: &[u8]) -> VnetHdr {
+        let field = |offset: usize| u16::from_le_bytes([buf[offset], buf[offset + 1]]);
+        VnetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        }
+    }
+}
+
+/// An `ENPacket` is either a bare IP packet, or, when it needs segmentation or
+/// a checksum, a virtio-net header followed by the IP packet. The first byte of
+/// the header holds its flags, which are below 16, while that of an IP packet
+/// holds its version, 4 or 6.
+pub fn has_vnet_hdr(packet: &[u8]) -> bool {
+    matches!(packet.first(), Some(b) if b >> 4 == 0)
+}
+
+/// Turns a packet read from a TUN device with IFF_VNET_HDR into an `ENPacket`,
+/// dropping the header if it carries nothing.
+pub fn from_vnet(mut packet: ENPacket) -> Result<ENPacket> {
+    ensure!(
+        packet.len() > VNET_HDR_LEN,
+        "{} bytes packet is too short for a virtio-net header",
+        packet.len()
+    );
+    let hdr = VnetHdr::parse(&packet);
+    if hdr.gso_type == VIRTIO_NET_HDR_GSO_NONE && hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        packet.advance(VNET_HDR_LEN);
+    }
+    Ok(packet)
+}
+
+/// Turns an `ENPacket` with a virtio-net header into the bare IP packets a
+/// TUN device without IFF_VNET_HDR takes: a super-packet is split into
+/// segments of `gso_size` bytes of payload, and checksums left to the device
+/// are filled in.
+pub fn segment(packet: &[u8]) -> Result<Vec<ENPacket>> {
+    ensure!(
+        packet.len() > VNET_HDR_LEN,
+        "{} bytes packet is too short for a virtio-net header",
+        packet.len()
+    );
+    let hdr = VnetHdr::parse(packet);
+    let ip = &packet[VNET_HDR_LEN..];
+    let transport = hdr.csum_start as usize;
+
+    let protocol = match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let mut ip = BytesMut::from(ip);
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                let field = transport + hdr.csum_offset as usize;
+                ensure!(field + 2 <= ip.len(), "checksum offset out of the packet");
+                // The field holds the checksum of the pseudo header, to which
+                // that of the rest of the packet is added
+                let checksum = !fold(sum(&ip[transport..], 0));
+                ip[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+            }
+            return Ok(vec![ip.freeze()]);
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => IPPROTO_TCP,
+        VIRTIO_NET_HDR_GSO_UDP_L4 => IPPROTO_UDP,
+        gso_type => bail!("unsupported GSO type {}", gso_type),
+    };
+
+    let ipv4 = match ip.first().map(|b| b >> 4) {
+        Some(4) => true,
+        Some(6) => false,
+        _ => bail!("not an IP packet"),
+    };
+    let header_len = match protocol {
+        IPPROTO_TCP => {
+            ensure!(transport + 20 <= ip.len(), "truncated TCP header");
+            transport + (ip[transport + 12] >> 4) as usize * 4
+        }
+        _ => transport + 8,
+    };
+    ensure!(
+        header_len <= ip.len() && (!ipv4 || transport >= 20) && (ipv4 || transport >= 40),
+        "truncated headers"
+    );
+    ensure!(hdr.gso_size > 0, "GSO size is zero");
+
+    let payload = &ip[header_len..];
+    let segments = payload.chunks(hdr.gso_size as usize);
+    let count = segments.len();
+    let mut packets = Vec::with_capacity(count);
+    for (i, chunk) in segments.enumerate() {
+        let mut seg = BytesMut::with_capacity(header_len + chunk.len());
+        seg.extend_from_slice(&ip[..header_len]);
+        seg.extend_from_slice(chunk);
+        let len = seg.len();
+
+        if ipv4 {
+            seg[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            let id = u16::from_be_bytes([seg[4], seg[5]]).wrapping_add(i as u16);
+            seg[4..6].copy_from_slice(&id.to_be_bytes());
+            seg[10..12].copy_from_slice(&[0, 0]);
+            let checksum = !fold(sum(&seg[..transport], 0));
+            seg[10..12].copy_from_slice(&checksum.to_be_bytes());
+        } else {
+            seg[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+        }
+
+        let field = if protocol == IPPROTO_TCP {
+            let offset = (i * hdr.gso_size as usize) as u32;
+            let seq = u32::from_be_bytes([
+                seg[transport + 4],
+                seg[transport + 5],
+                seg[transport + 6],
+                seg[transport + 7],
+            ]);
+            seg[transport + 4..transport + 8]
+                .copy_from_slice(&seq.wrapping_add(offset).to_be_bytes());
+            // FIN and PSH only on the last segment, CWR only on the first
+            if i + 1 < count {
+                seg[transport + 13] &= !0x09;
+            }
+            if i > 0 {
+                seg[transport + 13] &= !0x80;
+            }
+            transport + 16
+        } else {
+            seg[transport + 4..transport + 6]
+                .copy_from_slice(&((len - transport) as u16).to_be_bytes());
+            transport + 6
+        };
+        seg[field..field + 2].copy_from_slice(&[0, 0]);
+        let pseudo = if ipv4 {
+            sum(&seg[12..20], 0)
+        } else {
+            sum(&seg[8..40], 0)
+        };
+        let pseudo = pseudo + protocol as u64 + (len - transport) as u64;
+        let mut checksum = !fold(sum(&seg[transport..], pseudo));
+        if protocol == IPPROTO_UDP && checksum == 0 {
+            checksum = 0xffff;
+        }
+        seg[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        packets.push(seg.freeze());
+    }
+    Ok(packets)
+}
+
+/// Adds the data as big endian 16-bit words to a one's complement sum
+fn sum(data: &[u8], mut acc: u64) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        acc += u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [last] = words.remainder() {
+        acc += (*last as u64) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u64) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn with_vnet_hdr(hdr: VnetHdr, packet: &[u8]) -> Bytes {
+        let mut buf = vec![hdr.flags, hdr.gso_type, 0, 0];
+        buf.extend_from_slice(&hdr.gso_size.to_le_bytes());
+        buf.extend_from_slice(&hdr.csum_start.to_le_bytes());
+        buf.extend_from_slice(&hdr.csum_offset.to_le_bytes());
+        buf.extend_from_slice(packet);
+        Bytes::from(buf)
+    }
+
+    fn ipv4_tcp(payload_len: usize, flags: u8) -> Vec<u8> {
+        let len = 40 + payload_len;
+        let mut packet = vec![
+            0x45,
+            0,
+            (len >> 8) as u8,
+            len as u8,
+            0x12,
+            0x34,
+            0x40,
+            0,
+            64,
+            6,
+        ];
+        packet.extend_from_slice(&[0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        // Ports, sequence number 0xfffffc00, data offset 5 and flags
+        packet.extend_from_slice(&[0x1f, 0x90, 0x04, 0x00, 0xff, 0xff, 0xfc, 0x00]);
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend((0..payload_len).map(|i| i as u8));
+        let checksum = !fold(sum(&packet[..20], 0));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    fn ipv6_udp(payload_len: usize) -> Vec<u8> {
+        let len = 8 + payload_len;
+        let mut packet = vec![0x60, 0, 0, 0, (len >> 8) as u8, len as u8, 17, 64];
+        packet.extend_from_slice(&[0xfd; 16]);
+        packet.extend_from_slice(&[0xfe; 16]);
+        packet.extend_from_slice(&[0x13, 0x88, 0x00, 0x35, (len >> 8) as u8, len as u8, 0, 0]);
+        packet.extend((0..payload_len).map(|i| i as u8));
+        packet
+    }
+
+    /// Checks the checksum of the transport header, and for IPv4 of the IP
+    /// header, of a bare IP packet
+    fn assert_checksums(packet: &[u8], protocol: u8) {
+        let (transport, pseudo) = if packet[0] >> 4 == 4 {
+            assert_eq!(fold(sum(&packet[..20], 0)), 0xffff);
+            (20, sum(&packet[12..20], 0))
+        } else {
+            (40, sum(&packet[8..40], 0))
+        };
+        let len = (packet.len() - transport) as u64;
+        assert_eq!(
+            fold(sum(&packet[transport..], pseudo + protocol as u64 + len)),
+            0xffff
+        );
+    }
+
+    #[test]
+    fn vnet_hdr() {
+        let packet = ipv4_tcp(10, 0x18);
+        assert!(!has_vnet_hdr(&packet));
+        let empty = with_vnet_hdr(VnetHdr::parse(&EMPTY_VNET_HDR), &packet);
+        assert!(has_vnet_hdr(&empty));
+        assert_eq!(&from_vnet(empty).unwrap()[..], &packet[..]);
+
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_NONE,
+            gso_size: 0,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        let partial = with_vnet_hdr(hdr, &packet);
+        assert_eq!(from_vnet(partial.clone()).unwrap(), partial);
+        assert!(from_vnet(Bytes::from_static(&EMPTY_VNET_HDR)).is_err());
+    }
+
+    #[test]
+    fn complete_checksum() {
+        let mut packet = ipv4_tcp(101, 0x18);
+        // The checksum of the pseudo header, as left by the kernel
+        let pseudo = fold(sum(&packet[12..20], 0) + 6 + 121);
+        packet[36..38].copy_from_slice(&pseudo.to_be_bytes());
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_NONE,
+            gso_size: 0,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        let packets = segment(&with_vnet_hdr(hdr, &packet)).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_checksums(&packets[0], IPPROTO_TCP);
+    }
+
+    #[test]
+    fn segment_tcp() {
+        // FIN, PSH and CWR set
+        let packet = ipv4_tcp(2500, 0x89);
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_ECN,
+            gso_size: 1000,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        let packets = segment(&with_vnet_hdr(hdr, &packet)).unwrap();
+        assert_eq!(
+            packets.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            [1040, 1040, 540]
+        );
+        for (i, p) in packets.iter().enumerate() {
+            assert_eq!(u16::from_be_bytes([p[2], p[3]]) as usize, p.len());
+            assert_eq!(u16::from_be_bytes([p[4], p[5]]), 0x1234 + i as u16);
+            let seq = u32::from_be_bytes([p[24], p[25], p[26], p[27]]);
+            assert_eq!(seq, 0xfffffc00u32.wrapping_add(i as u32 * 1000));
+            assert_eq!(p[40], (i * 1000) as u8);
+            assert_checksums(p, IPPROTO_TCP);
+        }
+        assert_eq!(packets[0][33], 0x80);
+        assert_eq!(packets[1][33], 0x00);
+        assert_eq!(packets[2][33], 0x09);
+    }
+
+    #[test]
+    fn segment_udp() {
+        let packet = ipv6_udp(3000);
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_UDP_L4,
+            gso_size: 1200,
+            csum_start: 40,
+            csum_offset: 6,
+        };
+        let packets = segment(&with_vnet_hdr(hdr, &packet)).unwrap();
+        assert_eq!(
+            packets.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            [1248, 1248, 648]
+        );
+        for p in &packets {
+            assert_eq!(u16::from_be_bytes([p[4], p[5]]) as usize, p.len() - 40);
+            assert_eq!(u16::from_be_bytes([p[44], p[45]]) as usize, p.len() - 40);
+            assert_checksums(p, IPPROTO_UDP);
+        }
+
+        let ufo = VnetHdr { gso_type: 3, ..hdr };
+        assert!(segment(&with_vnet_hdr(ufo, &packet)).is_err());
+        let truncated = VnetHdr {
+            csum_start: 60000,
+            ..hdr
+        };
+        assert!(segment(&with_vnet_hdr(truncated, &packet)).is_err());
+    }
+}
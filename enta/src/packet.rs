@@ -1,2 +1,519 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, IoSlice};
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use protocol::padding::Padding;
+use protocol::relink::{self, Relink};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
 
 pub type ENPacket = bytes::Bytes;
+
+/// Largest IP packet
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+/// Length of `struct virtio_net_hdr`, which precedes every packet read from or
+/// written to a TUN device with offloads
+pub const VNET_HDR_LEN: usize = 10;
+
+/// Size of the chunks of memory packets are read into
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reads packets from a device that returns one packet per read, e.g. a TUN
+/// device, straight into a chunk of memory shared by all the packets read into
+/// it, so each packet is neither allocated nor copied on its own. Once every
+/// packet of a full chunk has been dropped the chunk is reused, otherwise a
+/// new one is allocated and the old one is freed with its last packet.
+pub struct PacketReader<R> {
+    reader: R,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> PacketReader<R> {
+    pub fn new(reader: R) -> PacketReader<R> {
+        PacketReader {
+            reader,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Returns `None` when the device is closed.
+    pub async fn read(&mut self) -> io::Result<Option<ENPacket>> {
+        // Room for the largest packet one read may return
+        if self.buf.capacity() < VNET_HDR_LEN + MAX_PACKET_SIZE {
+            self.buf.reserve(CHUNK_SIZE);
+        }
+        if self.reader.read_buf(&mut self.buf).await? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.split().freeze()))
+    }
+}
+
+/// Batching of the frames written to ENTG: after a packet, the packets already
+/// queued, and those arriving within `latency`, are written with it in one go
+/// until the frames add up to `size` bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct Batch {
+    pub size: usize,
+    pub latency: Duration,
+}
+
+/// Most slices one vectored write takes, the lengths and frames of 32 frames
+const MAX_SLICES: usize = 64;
+
+/// Writes packets as frames of `LengthDelimitedCodec`, i.e. each after its
+/// length as a 4 bytes big-endian integer. The frames queued are written with
+/// one vectored write of the lengths and the packets as they are, so that a
+/// batch of frames takes one write and no packet is copied. Streams which do
+/// not write vectors efficiently, such as TLS streams, get the frames copied
+/// into a buffer instead, which is written as a whole and kept for the next
+/// frames.
+pub struct FrameWriter<W> {
+    writer: W,
+    /// Length of each frame queued
+    lengths: Vec<[u8; 4]>,
+    frames: Vec<ENPacket>,
+    /// Bytes of the frames queued, with their lengths
+    queued: usize,
+    buf: BytesMut,
+    padding: Option<Padding>,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Frames are padded with `padding` if given
+    pub fn new(writer: W, padding: Option<Padding>) -> FrameWriter<W> {
+        FrameWriter {
+            writer,
+            lengths: Vec::new(),
+            frames: Vec::new(),
+            queued: 0,
+            buf: BytesMut::new(),
+            padding,
+        }
+    }
+
+    pub async fn send(&mut self, packet: ENPacket) -> io::Result<()> {
+        self.encode(packet)?;
+        self.flush().await
+    }
+
+    /// Sends frames which are ready to go, without padding them
+    pub async fn send_frames(&mut self, frames: Vec<ENPacket>) -> io::Result<()> {
+        for frame in frames {
+            self.queue(frame)?;
+        }
+        self.flush().await
+    }
+
+    /// Sends `packet` together with the packets following it in `rx`, as
+    /// described by `batch`, and returns the number of packets sent.
+    pub async fn send_batch(
+        &mut self,
+        packet: ENPacket,
+        rx: &mut Receiver<ENPacket>,
+        batch: Batch,
+    ) -> io::Result<usize> {
+        // Timers of tokio are too coarse for a budget of microseconds, so the
+        // task yields to others until it runs out
+        let deadline = Instant::now() + batch.latency;
+        let mut count = 1;
+        self.encode(packet)?;
+        while self.queued < batch.size {
+            match rx.try_recv() {
+                Ok(packet) => {
+                    self.encode(packet)?;
+                    count += 1;
+                }
+                Err(TryRecvError::Empty) if Instant::now() < deadline => {
+                    tokio::task::yield_now().await
+                }
+                Err(_) => break,
+            }
+        }
+        self.flush().await?;
+        Ok(count)
+    }
+
+    /// Writes `RELINK`, or writes `MOVED` after the frames sent so far and
+    /// carries on on the writer of the new connection the link moves to
+    pub async fn relink(&mut self, relink: Relink<W>) -> io::Result<()> {
+        let writer = match relink {
+            Relink::Ask => {
+                self.queue(relink::relink())?;
+                None
+            }
+            Relink::MoveTo(writer) => {
+                self.queue(relink::moved())?;
+                Some(writer)
+            }
+        };
+        self.flush().await?;
+        if let Some(writer) = writer {
+            self.writer = writer;
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, packet: ENPacket) -> io::Result<()> {
+        let frame = match &self.padding {
+            Some(padding) => padding.pad(packet),
+            None => packet,
+        };
+        self.queue(frame)
+    }
+
+    fn queue(&mut self, frame: ENPacket) -> io::Result<()> {
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large to send"))?;
+        self.lengths.push(len.to_be_bytes());
+        self.queued += 4 + frame.len();
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.writer.is_write_vectored() {
+            self.write_vectored().await?;
+        } else {
+            self.buf.reserve(self.queued);
+            for (len, frame) in self.lengths.iter().zip(&self.frames) {
+                self.buf.put_slice(len);
+                self.buf.put_slice(frame);
+            }
+            self.writer.write_all(&self.buf).await?;
+            self.buf.clear();
+        }
+        self.lengths.clear();
+        self.frames.clear();
+        self.queued = 0;
+        Ok(())
+    }
+
+    /// Writes the frames queued, the length of each followed by the frame,
+    /// with vectored writes of up to `MAX_SLICES` of them
+    async fn write_vectored(&mut self) -> io::Result<()> {
+        let (lengths, frames) = (&self.lengths, &self.frames);
+        let part = |i: usize| -> &[u8] {
+            match i % 2 {
+                0 => &lengths[i / 2],
+                _ => &frames[i / 2],
+            }
+        };
+        let parts = 2 * frames.len();
+        // Next part to write and how much of it is written already
+        let (mut start, mut taken) = (0, 0);
+        let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+        while start < parts {
+            let end = parts.min(start + MAX_SLICES);
+            slices[0] = IoSlice::new(&part(start)[taken..]);
+            for i in start + 1..end {
+                slices[i - start] = IoSlice::new(part(i));
+            }
+            let written = self.writer.write_vectored(&slices[..end - start]).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            taken += written;
+            while start < parts && taken >= part(start).len() {
+                taken -= part(start).len();
+                start += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash of the flow an IP packet belongs to, from its addresses, protocol and,
+/// for TCP, UDP and SCTP, ports. Fragmented packets are hashed without ports,
+/// which only the first fragment carries, to keep the fragments together.
+/// The virtio-net header in front of a super-packet is skipped, so that it is
+/// hashed like the bare packets of its flow.
+pub fn flow_hash(packet: &[u8]) -> u64 {
+    let packet = match packet.first().map(|b| b >> 4) {
+        Some(0) => packet.get(VNET_HDR_LEN..).unwrap_or_default(),
+        _ => packet,
+    };
+    let mut hasher = DefaultHasher::new();
+    let (protocol, transport) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            hasher.write(&packet[12..20]);
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+            let transport = if fragmented {
+                None
+            } else {
+                packet.get(header_len..)
+            };
+            (packet[9], transport)
+        }
+        // Extension headers are not followed, packets with any are hashed
+        // without ports
+        Some(6) if packet.len() >= 40 => {
+            hasher.write(&packet[8..40]);
+            (packet[6], packet.get(40..))
+        }
+        _ => return 0,
+    };
+    hasher.write_u8(protocol);
+    if let (6 | 17 | 132, Some(ports)) = (protocol, transport.and_then(|t| t.get(..4))) {
+        hasher.write(ports);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    /// Returns one packet per read like a TUN device
+    struct Device(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Device {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(packet) = self.0.pop_front() {
+                buf.put_slice(&packet);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn flow_hash_of_packets() {
+        fn ipv4(ports: [u8; 4], fragment: [u8; 2], payload: u8) -> Vec<u8> {
+            let mut packet = vec![0x45, 0, 0, 28, 0, 0, fragment[0], fragment[1], 64, 6];
+            packet.extend_from_slice(&[0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+            packet.extend_from_slice(&ports);
+            packet.extend_from_slice(&[payload; 4]);
+            packet
+        }
+        let flow = flow_hash(&ipv4([0, 80, 4, 0], [0, 0], 1));
+        assert_eq!(flow, flow_hash(&ipv4([0, 80, 4, 0], [0x40, 0], 2)));
+        assert_ne!(flow, flow_hash(&ipv4([0, 80, 4, 1], [0, 0], 1)));
+        // Fragments are hashed without ports
+        assert_eq!(
+            flow_hash(&ipv4([0, 80, 4, 0], [0x20, 0], 1)),
+            flow_hash(&ipv4([1, 2, 3, 4], [0, 0x10], 1))
+        );
+
+        let mut ipv6 = vec![0x60, 0, 0, 0, 0, 8, 17, 64];
+        ipv6.extend_from_slice(&[0xfe; 32]);
+        ipv6.extend_from_slice(&[0, 53, 4, 0]);
+        let flow = flow_hash(&ipv6);
+        ipv6[42] = 5;
+        assert_ne!(flow, flow_hash(&ipv6));
+
+        assert_eq!(flow_hash(&[]), flow_hash(&[0x45, 0]));
+
+        // A super-packet goes with the bare packets of its flow
+        let bare = ipv4([0, 80, 4, 0], [0, 0], 1);
+        let mut headered = vec![1, 1, 0, 0, 0x5c, 0x05, 20, 0, 16, 0];
+        headered.extend_from_slice(&bare);
+        assert_eq!(flow_hash(&headered), flow_hash(&bare));
+        assert_ne!(flow_hash(&headered), flow_hash(&[1; VNET_HDR_LEN]));
+    }
+
+    #[tokio::test]
+    async fn read_packets_and_write_frames() {
+        use futures::StreamExt;
+        use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+        // Enough packets to fill more than one chunk
+        let packets = (0..CHUNK_SIZE / 1000)
+            .map(|i| vec![i as u8; [1, 1400, MAX_PACKET_SIZE][i % 3]])
+            .collect::<Vec<_>>();
+        let mut device = PacketReader::new(Device(packets.iter().cloned().collect()));
+        let (writer, reader) = tokio::io::duplex(2 * MAX_PACKET_SIZE);
+        let mut writer = FrameWriter::new(writer, None);
+        let mut frames = FramedRead::new(reader, LengthDelimitedCodec::new());
+
+        let mut received = Vec::new();
+        for expected in &packets {
+            let packet = device.read().await.unwrap().unwrap();
+            assert_eq!(&packet[..], &expected[..]);
+            writer.send(packet.clone()).await.unwrap();
+            assert_eq!(&frames.next().await.unwrap().unwrap()[..], &expected[..]);
+            // Packets stay valid while later ones are read into the same chunk
+            received.push(packet);
+        }
+        assert!(received.iter().zip(&packets).all(|(r, p)| r[..] == p[..]));
+        assert!(device.read().await.unwrap().is_none());
+    }
+
+    /// Takes vectored writes of at most `limit` bytes
+    struct VectoredWriter {
+        written: Vec<u8>,
+        limit: usize,
+        writes: usize,
+    }
+
+    impl AsyncWrite for VectoredWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(self.limit - written);
+                self.written.extend_from_slice(&buf[..len]);
+                written += len;
+            }
+            Poll::Ready(Ok(written))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn write_frames_vectored() {
+        let frames = (1..=5u8)
+            .map(|i| ENPacket::from(vec![i; 100 * usize::from(i)]))
+            .collect::<Vec<_>>();
+        let mut expected = Vec::new();
+        for frame in &frames {
+            expected.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            expected.extend_from_slice(frame);
+        }
+
+        // One write takes all the frames
+        let writer = VectoredWriter {
+            written: Vec::new(),
+            limit: usize::MAX,
+            writes: 0,
+        };
+        let mut writer = FrameWriter::new(writer, None);
+        writer.send_frames(frames.clone()).await.unwrap();
+        assert_eq!(writer.writer.writes, 1);
+        assert_eq!(writer.writer.written, expected);
+        assert!(writer.buf.is_empty());
+
+        // Writes taking part of a frame go on from there
+        let writer = VectoredWriter {
+            written: Vec::new(),
+            limit: 333,
+            writes: 0,
+        };
+        let mut writer = FrameWriter::new(writer, None);
+        writer.send_frames(frames).await.unwrap();
+        assert_eq!(writer.writer.written, expected);
+        // 1520 bytes in all
+        assert_eq!(writer.writer.writes, 5);
+
+        // A write takes up to 32 frames
+        let writer = VectoredWriter {
+            written: Vec::new(),
+            limit: usize::MAX,
+            writes: 0,
+        };
+        let mut writer = FrameWriter::new(writer, None);
+        writer
+            .send_frames(vec![ENPacket::from_static(&[1; 10]); 40])
+            .await
+            .unwrap();
+        assert_eq!(writer.writer.writes, 2);
+        assert_eq!(writer.writer.written.len(), 40 * 14);
+    }
+
+    #[tokio::test]
+    async fn move_to_new_writer() {
+        use futures::StreamExt;
+        use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+        let (old, old_reader) = tokio::io::duplex(64 * 1024);
+        let (new, new_reader) = tokio::io::duplex(64 * 1024);
+        let mut writer = FrameWriter::new(old, None);
+        for i in 0..3 {
+            writer.send(ENPacket::from(vec![i; 100])).await.unwrap();
+        }
+        writer.relink(Relink::MoveTo(new)).await.unwrap();
+        for i in 3..6 {
+            writer.send(ENPacket::from(vec![i; 100])).await.unwrap();
+        }
+        drop(writer);
+
+        // The old connection ends with `MOVED`, and the frames carry on on the
+        // new one
+        let mut frames = FramedRead::new(old_reader, LengthDelimitedCodec::new());
+        for i in 0..3 {
+            assert_eq!(&frames.next().await.unwrap().unwrap()[..], &[i; 100][..]);
+        }
+        assert_eq!(
+            &frames.next().await.unwrap().unwrap()[..],
+            &relink::moved()[..]
+        );
+        assert!(frames.next().await.is_none());
+        let mut frames = FramedRead::new(new_reader, LengthDelimitedCodec::new());
+        for i in 3..6 {
+            assert_eq!(&frames.next().await.unwrap().unwrap()[..], &[i; 100][..]);
+        }
+        assert!(frames.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn send_batches() {
+        use futures::StreamExt;
+        use tokio::sync::mpsc;
+        use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let mut writer = FrameWriter::new(writer, None);
+        let (tx, mut rx) = mpsc::channel(16);
+        for i in 1..=10 {
+            tx.send(ENPacket::from(vec![i; 1000])).await.unwrap();
+        }
+        let batch = Batch {
+            size: 4000,
+            latency: Duration::from_micros(50),
+        };
+
+        // Frames of 1004 bytes are gathered until the batch reaches 4000 bytes
+        let packet = rx.recv().await.unwrap();
+        assert_eq!(writer.send_batch(packet, &mut rx, batch).await.unwrap(), 4);
+        let mut buf = vec![0; 64 * 1024];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 4 * 1004);
+        let packet = rx.recv().await.unwrap();
+        assert_eq!(writer.send_batch(packet, &mut rx, batch).await.unwrap(), 4);
+        // The rest is sent once the latency budget runs out
+        let packet = rx.recv().await.unwrap();
+        assert_eq!(writer.send_batch(packet, &mut rx, batch).await.unwrap(), 2);
+        drop(writer);
+
+        let mut frames = FramedRead::new(reader, LengthDelimitedCodec::new());
+        for i in 5..=10 {
+            assert_eq!(&frames.next().await.unwrap().unwrap()[..], &[i; 1000][..]);
+        }
+        assert!(frames.next().await.is_none());
+    }
+}
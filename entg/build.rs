@@ -1,6 +1,10 @@
 fn main(){
     cfg_if::cfg_if! {
-        if #[cfg(feature = "occlum")] {
+        if #[cfg(feature = "mock")] {
+            // The mock backend of rats-tls does not use librats_tls.so
+        } else if #[cfg(feature = "dlopen")] {
+            // librats_tls.so is loaded at runtime, see `--rats-tls-library`
+        } else if #[cfg(feature = "occlum")] {
             println!("cargo:rustc-link-search=deps/rats-tls/build-occlum/src");
             println!("cargo:rustc-link-lib=rats_tls");
             // We specify `-rpath` here because it only works in binary crate and not in library crate.
@@ -11,7 +15,7 @@ fn main(){
             println!("cargo:rustc-link-lib=rats_tls");
             println!("cargo:rustc-link-arg=-Wl,-rpath,/usr/local/lib/rats-tls");
         }else {
-            panic!("One of these features must be specified: {:?}", ["host", "occlum"]);
+            panic!("One of these features must be specified: {:?}", ["host", "occlum", "dlopen", "mock"]);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,441 @@
+//! Compression of the frames on the link between two ENTGs. Each frame from
+//! ENTA is compressed on its own, so that frames stay independent of each other
+//! and small or incompressible ones can be sent as they are. On the link every
+//! frame starts with a byte telling how its payload is encoded:
+//!
+//! - `0`: the frame as received from ENTA
+//! - `1` or `2`: the length of the frame as a little-endian u32, followed by
+//!   the frame compressed with LZ4 or zstd
+//!
+//! Both ENTGs give the algorithms they accept as a bitmask in their hello. Each
+//! side then compresses with the first of its own algorithms the peer accepts.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hasher;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use protocol::codec::VNET_HDR_LEN;
+
+const RAW: u8 = 0;
+
+/// Bytes in front of the data of a compressed frame: the algorithm and the
+/// length of the frame once decompressed. A raw frame has only the first one.
+pub const HEADER_LEN: usize = 5;
+
+/// zstd level used for the frames, the fastest one as links are expected to
+/// carry traffic at line rate
+const ZSTD_LEVEL: i32 = 1;
+
+/// A compressed frame is only sent if it saves at least an eighth of the frame
+const MIN_SAVING: usize = 8;
+
+/// Number of packets of a flow sent as they are after one of them turned out
+/// to be incompressible, before compression is tried on the flow again
+const SKIP_PACKETS: u32 = 256;
+
+/// Number of flows remembered as incompressible, beyond which all of them are
+/// forgotten
+const MAX_FLOWS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Algorithm {
+    fn bit(self) -> u8 {
+        1 << (self as u8 - 1)
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Algorithm::Lz4 => Ok(lz4_flex::compress(data)),
+            Algorithm::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+        }
+    }
+
+    fn decompress(self, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Algorithm::Lz4 => lz4_flex::decompress(data, len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Algorithm::Zstd => zstd::bulk::decompress(data, len),
+        }
+    }
+}
+
+impl TryFrom<u8> for Algorithm {
+    type Error = io::Error;
+
+    fn try_from(kind: u8) -> io::Result<Algorithm> {
+        match kind {
+            1 => Ok(Algorithm::Lz4),
+            2 => Ok(Algorithm::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown frame encoding {}", kind),
+            )),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lz4" => Ok(Algorithm::Lz4),
+            "zstd" => Ok(Algorithm::Zstd),
+            _ => Err(format!(
+                "invalid compression '{}', expect 'lz4' or 'zstd'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Lz4 => write!(f, "lz4"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Bitmask of `algorithms`, which the hello of ENTG gives as the ones it
+/// accepts
+pub fn accepted(algorithms: &[Algorithm]) -> u8 {
+    algorithms.iter().fold(0, |bits, a| bits | a.bit())
+}
+
+/// The algorithm to compress the frames sent to the peer ENTG with: the first
+/// of `algorithms` in the bitmask of those the peer `accepts`, if any
+pub fn select(algorithms: &[Algorithm], accepts: u8) -> Option<Algorithm> {
+    algorithms.iter().copied().find(|a| accepts & a.bit() != 0)
+}
+
+/// Counters of the frames sent on a link with compression. They are shared
+/// with the task reporting them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub frames: AtomicU64,
+    pub compressed: AtomicU64,
+    /// Frames sent as they are because they were below the minimum size
+    pub small: AtomicU64,
+    /// Frames sent as they are because their flow was found incompressible
+    pub skipped: AtomicU64,
+    /// Bytes of the frames before compression
+    pub bytes_in: AtomicU64,
+    /// Bytes of the frames sent on the link
+    pub bytes_out: AtomicU64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.bytes_out.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} frames, {} compressed, {} below minimum size, {} of incompressible flows, {} bytes in, {} bytes out, ratio {:.3}",
+            self.frames.load(Ordering::Relaxed),
+            self.compressed.load(Ordering::Relaxed),
+            self.small.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+            bytes_in,
+            bytes_out,
+            if bytes_in == 0 {
+                1.0
+            } else {
+                bytes_out as f64 / bytes_in as f64
+            }
+        )
+    }
+}
+
+/// Encodes the frames sent to the peer ENTG.
+pub struct Compressor {
+    algorithm: Option<Algorithm>,
+    min_size: usize,
+    /// Flows found incompressible, with the number of their packets still to
+    /// be sent as they are, if incompressible flows are skipped
+    skipped_flows: Option<HashMap<u64, u32>>,
+    stats: Arc<Stats>,
+}
+
+impl Compressor {
+    pub fn new(
+        algorithm: Option<Algorithm>,
+        min_size: usize,
+        skip_incompressible: bool,
+        stats: Arc<Stats>,
+    ) -> Compressor {
+        Compressor {
+            algorithm,
+            min_size,
+            skipped_flows: skip_incompressible.then(HashMap::new),
+            stats,
+        }
+    }
+
+    pub fn compress(&mut self, frame: &[u8]) -> io::Result<Bytes> {
+        self.stats.frames.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_in
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        let algorithm = self.algorithm;
+        let encoded = match algorithm {
+            None => raw(frame),
+            Some(_) if frame.len() < self.min_size => {
+                self.stats.small.fetch_add(1, Ordering::Relaxed);
+                raw(frame)
+            }
+            Some(_) if self.skip(frame) => {
+                self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+                raw(frame)
+            }
+            Some(algorithm) => {
+                let compressed = algorithm.compress(frame)?;
+                if compressed.len() + frame.len() / MIN_SAVING <= frame.len() {
+                    self.stats.compressed.fetch_add(1, Ordering::Relaxed);
+                    let mut buf = BytesMut::with_capacity(5 + compressed.len());
+                    buf.put_u8(algorithm as u8);
+                    buf.put_u32_le(frame.len() as u32);
+                    buf.put_slice(&compressed);
+                    buf
+                } else {
+                    self.remember_incompressible(frame);
+                    raw(frame)
+                }
+            }
+        };
+        self.stats
+            .bytes_out
+            .fetch_add(encoded.len() as u64, Ordering::Relaxed);
+        Ok(encoded.freeze())
+    }
+
+    fn remember_incompressible(&mut self, frame: &[u8]) {
+        if let (Some(flows), Some(flow)) = (&mut self.skipped_flows, flow_key(frame)) {
+            if flows.len() >= MAX_FLOWS {
+                flows.clear();
+            }
+            flows.insert(flow, SKIP_PACKETS);
+        }
+    }
+
+    /// Whether `frame` belongs to a flow whose packets are sent as they are
+    fn skip(&mut self, frame: &[u8]) -> bool {
+        let flows = match &mut self.skipped_flows {
+            Some(flows) => flows,
+            None => return false,
+        };
+        let flow = match flow_key(frame) {
+            Some(flow) => flow,
+            None => return false,
+        };
+        match flows.get_mut(&flow) {
+            Some(left) if *left > 1 => {
+                *left -= 1;
+                true
+            }
+            Some(_) => {
+                flows.remove(&flow);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn raw(frame: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(1 + frame.len());
+    buf.put_u8(RAW);
+    buf.put_slice(frame);
+    buf
+}
+
+/// Decodes a frame received from the peer ENTG, which may not expand to more
+/// than `max_len` bytes.
+pub fn decompress(mut frame: BytesMut, max_len: usize) -> io::Result<Bytes> {
+    if frame.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty frame"));
+    }
+    let kind = frame[0];
+    if kind == RAW {
+        return Ok(frame.split_off(1).freeze());
+    }
+    let algorithm = Algorithm::try_from(kind)?;
+    if frame.len() < HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated compressed frame",
+        ));
+    }
+    let len = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("compressed frame expands to {} bytes", len),
+        ));
+    }
+    let data = algorithm.decompress(&frame[HEADER_LEN..], len)?;
+    if data.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "compressed frame expands to {} bytes, expect {}",
+                data.len(),
+                len
+            ),
+        ));
+    }
+    Ok(Bytes::from(data))
+}
+
+/// Key of the flow of the packet in `frame`, from its addresses, protocol and
+/// ports. The virtio-net header ENTA puts in front of super-packets is skipped.
+fn flow_key(frame: &[u8]) -> Option<u64> {
+    let packet = match frame.first()? >> 4 {
+        0 => frame.get(VNET_HDR_LEN..)?,
+        _ => frame,
+    };
+    let (protocol, addrs, l4) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = usize::from(packet[0] & 0x0f) * 4;
+            // Only the first fragment carries the ports
+            let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x1fff;
+            let l4 = if fragment == 0 {
+                packet.get(ihl..)
+            } else {
+                None
+            };
+            (*packet.get(9)?, packet.get(12..20)?, l4)
+        }
+        6 => (*packet.get(6)?, packet.get(8..40)?, packet.get(40..)),
+        _ => return None,
+    };
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u8(protocol);
+    hasher.write(addrs);
+    if let (6 | 17, Some(ports)) = (protocol, l4.and_then(|l4| l4.get(..4))) {
+        hasher.write(ports);
+    }
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::codec::MAX_FRAME_SIZE;
+
+    use super::*;
+
+    fn udp_packet(port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Bytes which no algorithm can compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compress_and_decompress() {
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n".repeat(20);
+        for algorithm in [Algorithm::Lz4, Algorithm::Zstd] {
+            let stats = Arc::new(Stats::default());
+            let mut compressor = Compressor::new(Some(algorithm), 128, false, stats.clone());
+            for frame in [udp_packet(80, &text), udp_packet(80, b"short")] {
+                let encoded = compressor.compress(&frame).unwrap();
+                assert_eq!(
+                    &decompress(BytesMut::from(&encoded[..]), MAX_FRAME_SIZE).unwrap()[..],
+                    &frame[..]
+                );
+            }
+            assert_eq!(stats.frames.load(Ordering::Relaxed), 2);
+            assert_eq!(stats.compressed.load(Ordering::Relaxed), 1);
+            assert_eq!(stats.small.load(Ordering::Relaxed), 1);
+            assert!(
+                stats.bytes_out.load(Ordering::Relaxed) * 4
+                    < stats.bytes_in.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    #[test]
+    fn skip_incompressible_flows() {
+        let stats = Arc::new(Stats::default());
+        let mut compressor = Compressor::new(Some(Algorithm::Lz4), 128, true, stats.clone());
+        let text = b"abcdefgh".repeat(100);
+        let noise = udp_packet(443, &noise(800));
+
+        // The first packet of a flow is tried, the following ones are not
+        let encoded = compressor.compress(&noise).unwrap();
+        assert_eq!(encoded[0], RAW);
+        assert_eq!(&encoded[1..], &noise[..]);
+        for _ in 0..SKIP_PACKETS {
+            assert_eq!(compressor.compress(&noise).unwrap()[0], RAW);
+        }
+        assert_eq!(
+            stats.skipped.load(Ordering::Relaxed),
+            u64::from(SKIP_PACKETS)
+        );
+        // Other flows are still compressed
+        assert_eq!(compressor.compress(&udp_packet(80, &text)).unwrap()[0], 1);
+        // and so is the flow again after a while
+        assert_eq!(compressor.compress(&udp_packet(443, &text)).unwrap()[0], 1);
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        assert!(decompress(BytesMut::new(), MAX_FRAME_SIZE).is_err());
+        assert!(decompress(BytesMut::from(&[3, 0, 0][..]), MAX_FRAME_SIZE).is_err());
+        assert!(decompress(BytesMut::from(&[1, 0, 0][..]), MAX_FRAME_SIZE).is_err());
+        let mut frame = BytesMut::from(&[2, 0xff, 0xff, 0xff, 0xff][..]);
+        frame.extend_from_slice(&zstd::bulk::compress(&[0; 100], 1).unwrap());
+        assert!(decompress(frame, MAX_FRAME_SIZE).is_err());
+        // A frame expanding to another length than the one it claims
+        let mut frame = BytesMut::from(&[1, 10, 0, 0, 0][..]);
+        frame.extend_from_slice(&lz4_flex::compress(&[0; 100]));
+        assert!(decompress(frame, MAX_FRAME_SIZE).is_err());
+        // A frame expanding to more than allowed
+        let mut frame = BytesMut::from(&[1, 100, 0, 0, 0][..]);
+        frame.extend_from_slice(&lz4_flex::compress(&[0; 100]));
+        assert!(decompress(frame.clone(), 100).is_ok());
+        assert!(decompress(frame, 99).is_err());
+    }
+
+    #[test]
+    fn select_algorithms() {
+        let accepts = accepted(&[Algorithm::Lz4]);
+        assert_eq!(
+            select(&[Algorithm::Zstd, Algorithm::Lz4], accepts),
+            Some(Algorithm::Lz4)
+        );
+        assert_eq!(select(&[Algorithm::Zstd], accepts), None);
+        let accepts = accepted(&[Algorithm::Lz4, Algorithm::Zstd]);
+        assert_eq!(
+            select(&[Algorithm::Zstd, Algorithm::Lz4], accepts),
+            Some(Algorithm::Zstd)
+        );
+    }
+}
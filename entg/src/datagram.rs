@@ -0,0 +1,553 @@
+//! Datagrams on the link between two ENTGs. Once both ENTGs offer them in
+//! their hellos, the frames are carried in UDP datagrams instead of over the
+//! TCP connection, so that a lost packet holds up only itself rather than the
+//! whole link, and the TCP connections tunneled do not run over another one.
+//! The TCP connection, with rats-tls, stays up: it sets the datagrams up,
+//! carries the frames too large for a datagram, and its closing closes the
+//! link.
+//!
+//! The setup runs on the TCP connection after the hellos:
+//!
+//! 1. Each ENTG sends a secret of 32 random bytes, protected by rats-tls, and
+//!    the keys of the two directions are derived from both with HKDF-SHA256.
+//! 2. The ENTG which connected sends probes to the UDP port of the other one,
+//!    the same as its TCP port, which answers each with an ack.
+//! 3. It then sends a byte on the TCP connection, 1 if an ack came back within
+//!    the probe timeout, and 0 otherwise, in which case both stay on TCP.
+//!
+//! A datagram is its kind, a big-endian u64 sequence number, and the payload
+//! sealed with ChaCha20-Poly1305 under the key of its direction, with the kind
+//! and sequence number as associated data and the sequence number as nonce.
+//! Each direction numbers its datagrams from 1 up, and the receiver drops those
+//! which do not open, as well as replays: sequence numbers it has already seen
+//! or which are behind the last 64 it has.
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+const SECRET_LEN: usize = 32;
+
+/// Bytes in front of the sealed payload: the kind and the sequence number
+const HEADER_LEN: usize = 9;
+
+const TAG_LEN: usize = 16;
+
+/// Largest payload of a UDP datagram
+const MAX_DATAGRAM: usize = 65507;
+
+/// Bytes of the UDP header, and of the IPv4 and IPv6 headers without options,
+/// which the path MTU has room for besides the datagram
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Interval of the probes, which are sent until one is acked
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of sequence numbers behind the highest one received which are still
+/// accepted, if not received yet
+const WINDOW: u64 = 64;
+
+const PROBE: u8 = 0;
+const ACK: u8 = 1;
+const FRAME: u8 = 2;
+
+/// The UDP side of the setup
+pub enum Udp {
+    /// The ENTG which connected, probing the other ENTG at this address
+    Connect(SocketAddr),
+    /// The ENTG which was connected to, answering probes on this socket. `None`
+    /// if the socket could not be bound, so that the other ENTG falls back to
+    /// TCP.
+    Listen(Option<UdpSocket>),
+}
+
+/// Counters of the datagrams of a link. They are shared with the task
+/// reporting them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+    /// Datagrams which did not open, e.g. forged or corrupted ones
+    pub invalid: AtomicU64,
+    pub replayed: AtomicU64,
+    /// Frames sent over TCP because they do not fit in a datagram
+    pub over_tcp: AtomicU64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} datagrams sent, {} received, {} dropped as invalid, {} as replayed, {} frames sent over TCP",
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+            self.invalid.load(Ordering::Relaxed),
+            self.replayed.load(Ordering::Relaxed),
+            self.over_tcp.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Sequence numbers received recently, to tell replays apart
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// Highest sequence number received
+    top: u64,
+    /// Bit `n` is set if `top - n` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Whether `seq` was not received yet and is within the window
+    fn check(&self, seq: u64) -> bool {
+        match seq {
+            0 => false,
+            _ if seq > self.top => true,
+            _ if self.top - seq >= WINDOW => false,
+            _ => self.seen & (1 << (self.top - seq)) == 0,
+        }
+    }
+
+    fn update(&mut self, seq: u64) {
+        if seq > self.top {
+            let shift = seq - self.top;
+            self.seen = if shift >= WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.top = seq;
+        } else {
+            self.seen |= 1 << (self.top - seq);
+        }
+    }
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Seals the datagrams of one direction
+struct Sealer {
+    key: LessSafeKey,
+    seq: u64,
+}
+
+impl Sealer {
+    fn new(key: LessSafeKey) -> Sealer {
+        Sealer { key, seq: 0 }
+    }
+
+    fn seal(&mut self, kind: u8, payload: &[u8]) -> Vec<u8> {
+        self.seq += 1;
+        let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len() + TAG_LEN);
+        datagram.push(kind);
+        datagram.extend_from_slice(&self.seq.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        let (header, payload) = datagram.split_at_mut(HEADER_LEN);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce(self.seq), Aad::from(&*header), payload)
+            .expect("payload of a datagram is too long to seal");
+        datagram.extend_from_slice(tag.as_ref());
+        datagram
+    }
+}
+
+/// Opens the datagrams of one direction
+struct Opener {
+    key: LessSafeKey,
+    window: ReplayWindow,
+    stats: Arc<Stats>,
+}
+
+impl Opener {
+    fn new(key: LessSafeKey, stats: Arc<Stats>) -> Opener {
+        Opener {
+            key,
+            window: ReplayWindow::default(),
+            stats,
+        }
+    }
+
+    /// Opens `datagram` in place, and returns its kind and the range of its
+    /// payload. Returns `None` for an invalid or replayed datagram.
+    fn open(&mut self, datagram: &mut [u8]) -> Option<(u8, std::ops::Range<usize>)> {
+        if datagram.len() < HEADER_LEN + TAG_LEN {
+            self.stats.invalid.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let (header, sealed) = datagram.split_at_mut(HEADER_LEN);
+        let seq = u64::from_be_bytes(header[1..].try_into().unwrap());
+        // The window is only moved once the datagram opened, so that forged
+        // sequence numbers cannot push the real ones out of it
+        if !self.window.check(seq) {
+            self.stats.replayed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        match self
+            .key
+            .open_in_place(nonce(seq), Aad::from(&*header), sealed)
+        {
+            Ok(payload) => {
+                let len = payload.len();
+                self.window.update(seq);
+                Some((header[0], HEADER_LEN..HEADER_LEN + len))
+            }
+            Err(_) => {
+                self.stats.invalid.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+/// Derives the keys of the datagrams from the ENTG which connected to the
+/// other one, and from the other one to it
+fn keys(client_secret: &[u8], server_secret: &[u8]) -> (LessSafeKey, LessSafeKey) {
+    let prk =
+        Salt::new(HKDF_SHA256, b"ENTG datagrams").extract(&[client_secret, server_secret].concat());
+    let key = |info: &[u8]| {
+        let info = [info];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .expect("key length is valid for HKDF");
+        LessSafeKey::new(UnboundKey::from(okm))
+    };
+    (key(b"client"), key(b"server"))
+}
+
+/// Sends frames to the other ENTG in datagrams
+pub struct Sender {
+    socket: Arc<UdpSocket>,
+    sealer: Sealer,
+    stats: Arc<Stats>,
+    /// Largest frame sent in a datagram, which keeps the datagrams within the
+    /// path MTU so that IP does not fragment them
+    max_frame: usize,
+}
+
+impl Sender {
+    /// Sends `frame` in a datagram. Returns `false` if it does not fit in one,
+    /// to be sent over TCP instead.
+    pub async fn send(&mut self, frame: &[u8]) -> io::Result<bool> {
+        if frame.len() > self.max_frame {
+            self.stats.over_tcp.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        let datagram = self.sealer.seal(FRAME, frame);
+        match self.socket.send(&datagram).await {
+            // An ICMP error for an earlier datagram, which is as good as lost
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            r => {
+                r?;
+            }
+        }
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+}
+
+/// Receives frames from the other ENTG in datagrams
+pub struct Receiver {
+    socket: Arc<UdpSocket>,
+    opener: Opener,
+    stats: Arc<Stats>,
+    /// Datagrams are received and opened in place here, and only the frame
+    /// they carry is copied out
+    buf: Box<[u8]>,
+}
+
+impl Receiver {
+    /// Receives the next frame, skipping invalid and replayed datagrams, as
+    /// well as the probes and acks left over from the setup
+    pub async fn recv(&mut self) -> io::Result<BytesMut> {
+        loop {
+            let len = match self.socket.recv(&mut self.buf).await {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                r => r?,
+            };
+            if let Some((FRAME, payload)) = self.opener.open(&mut self.buf[..len]) {
+                self.stats.received.fetch_add(1, Ordering::Relaxed);
+                return Ok(BytesMut::from(&self.buf[payload]));
+            }
+        }
+    }
+}
+
+/// Largest frame which fits in a datagram to `peer` over a path of `path_mtu`
+/// bytes
+fn max_frame(peer: SocketAddr, path_mtu: usize) -> usize {
+    let ip_header_len = match peer {
+        SocketAddr::V4(_) => IPV4_HEADER_LEN,
+        SocketAddr::V6(_) => IPV6_HEADER_LEN,
+    };
+    path_mtu
+        .min(ip_header_len + UDP_HEADER_LEN + MAX_DATAGRAM)
+        .saturating_sub(ip_header_len + UDP_HEADER_LEN + HEADER_LEN + TAG_LEN)
+}
+
+/// Sets the datagrams up with the other ENTG over `stream`, the TCP connection
+/// with it. Returns `None` if the datagrams do not get through, e.g. because
+/// a firewall drops UDP, and the link stays on TCP. Frames too large for a
+/// datagram within `path_mtu` are left to TCP.
+pub async fn setup<S>(
+    stream: &mut S,
+    udp: Udp,
+    probe_timeout: Duration,
+    path_mtu: usize,
+    stats: Arc<Stats>,
+) -> Result<Option<(Sender, Receiver)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ours = [0; SECRET_LEN];
+    SystemRandom::new()
+        .fill(&mut ours)
+        .map_err(|_| anyhow::anyhow!("Failed to generate the secret of the datagrams"))?;
+    stream.write_all(&ours).await?;
+    stream.flush().await?;
+    let mut theirs = [0; SECRET_LEN];
+    stream
+        .read_exact(&mut theirs)
+        .await
+        .context("Failed to receive the secret of the datagrams")?;
+
+    let (socket, sealer, opener) = match udp {
+        Udp::Connect(peer) => {
+            let (to_peer, from_peer) = keys(&ours, &theirs);
+            let local: SocketAddr = match peer {
+                SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                SocketAddr::V6(_) => ([0; 16], 0).into(),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(peer).await?;
+            let mut sealer = Sealer::new(to_peer);
+            let mut opener = Opener::new(from_peer, stats.clone());
+            let probe = probe(&socket, &mut sealer, &mut opener);
+            let acked = tokio::time::timeout(probe_timeout, probe).await.is_ok();
+            stream.write_u8(acked as u8).await?;
+            stream.flush().await?;
+            if !acked {
+                return Ok(None);
+            }
+            (socket, sealer, opener)
+        }
+        Udp::Listen(socket) => {
+            let (from_peer, to_peer) = keys(&theirs, &ours);
+            let mut sealer = Sealer::new(to_peer);
+            let mut opener = Opener::new(from_peer, stats.clone());
+            let mut peer = None;
+            let answer = async {
+                match &socket {
+                    Some(socket) => answer(socket, &mut sealer, &mut opener, &mut peer).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            let decision = tokio::select! {
+                decision = stream.read_u8() => decision.context("Failed to receive the result of the probes")?,
+                r = answer => return Err(r.into()),
+            };
+            match (decision, socket, peer) {
+                (1, Some(socket), Some(peer)) => {
+                    socket.connect(peer).await?;
+                    (socket, sealer, opener)
+                }
+                _ => return Ok(None),
+            }
+        }
+    };
+    let max_frame = max_frame(socket.peer_addr()?, path_mtu);
+    let socket = Arc::new(socket);
+    let sender = Sender {
+        socket: socket.clone(),
+        sealer,
+        stats: stats.clone(),
+        max_frame,
+    };
+    let receiver = Receiver {
+        socket,
+        opener,
+        stats,
+        buf: vec![0; MAX_DATAGRAM].into_boxed_slice(),
+    };
+    Ok(Some((sender, receiver)))
+}
+
+/// Sends probes on `socket` until one of them is acked
+async fn probe(socket: &UdpSocket, sealer: &mut Sealer, opener: &mut Opener) {
+    let mut buf = [0; 64];
+    loop {
+        // Until the other ENTG binds its socket, probes may be refused
+        let _ = socket.send(&sealer.seal(PROBE, &[])).await;
+        let recv = tokio::time::timeout(PROBE_INTERVAL, async {
+            loop {
+                if let Ok(len) = socket.recv(&mut buf).await {
+                    if let Some((ACK, _)) = opener.open(&mut buf[..len]) {
+                        return;
+                    }
+                }
+            }
+        });
+        if recv.await.is_ok() {
+            return;
+        }
+    }
+}
+
+/// Answers the probes received on `socket`, and keeps the address they came
+/// from in `peer`. Only returns on failure.
+async fn answer(
+    socket: &UdpSocket,
+    sealer: &mut Sealer,
+    opener: &mut Opener,
+    peer: &mut Option<SocketAddr>,
+) -> io::Error {
+    let mut buf = [0; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => return e,
+        };
+        if let Some((PROBE, _)) = opener.open(&mut buf[..len]) {
+            *peer = Some(from);
+            if let Err(e) = socket.send_to(&sealer.seal(ACK, &[]), from).await {
+                return e;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Sealer, Opener, Arc<Stats>) {
+        let (key, _) = keys(&[1; SECRET_LEN], &[2; SECRET_LEN]);
+        let (same, _) = keys(&[1; SECRET_LEN], &[2; SECRET_LEN]);
+        let stats = Arc::new(Stats::default());
+        (Sealer::new(key), Opener::new(same, stats.clone()), stats)
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut sealer, mut opener, stats) = pair();
+        let mut datagram = sealer.seal(FRAME, b"frame");
+        let (kind, payload) = opener.open(&mut datagram).unwrap();
+        assert_eq!((kind, &datagram[payload]), (FRAME, &b"frame"[..]));
+
+        // Tampered with, or sealed with another key
+        let mut datagram = sealer.seal(FRAME, b"frame");
+        datagram[HEADER_LEN] ^= 1;
+        assert!(opener.open(&mut datagram).is_none());
+        let (_, other) = keys(&[1; SECRET_LEN], &[2; SECRET_LEN]);
+        let mut other = Sealer::new(other);
+        other.seq = 2;
+        let mut datagram = other.seal(FRAME, b"frame");
+        assert!(opener.open(&mut datagram).is_none());
+        assert!(opener.open(&mut [FRAME, 0, 0]).is_none());
+        assert_eq!(stats.invalid.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn drop_replays() {
+        let (mut sealer, mut opener, stats) = pair();
+        let datagrams: Vec<_> = (0..100).map(|_| sealer.seal(FRAME, b"frame")).collect();
+        let mut open = |i: usize| opener.open(&mut datagrams[i].clone()).is_some();
+        assert!(open(10));
+        assert!(!open(10));
+        // Out of order within the window
+        assert!(open(5));
+        assert!(open(80));
+        assert!(open(20));
+        assert!(!open(20));
+        // Behind the window
+        assert!(!open(15));
+        assert!(open(99));
+        assert_eq!(stats.replayed.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn set_up_datagrams() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let timeout = Duration::from_secs(5);
+        let stats = Arc::new(Stats::default());
+        let (client, server) = tokio::join!(
+            setup(&mut a, Udp::Connect(addr), timeout, 1500, stats.clone()),
+            setup(
+                &mut b,
+                Udp::Listen(Some(socket)),
+                timeout,
+                1500,
+                stats.clone()
+            ),
+        );
+        let (mut client_tx, mut client_rx) = client.unwrap().unwrap();
+        let (mut server_tx, mut server_rx) = server.unwrap().unwrap();
+
+        assert!(client_tx.send(b"to server").await.unwrap());
+        assert_eq!(&server_rx.recv().await.unwrap()[..], b"to server");
+        assert!(server_tx.send(b"to client").await.unwrap());
+        assert_eq!(&client_rx.recv().await.unwrap()[..], b"to client");
+        // Too large for a datagram within the path MTU, less the IPv4 and UDP
+        // headers and those of the datagram
+        let largest = 1500 - 20 - 8 - HEADER_LEN - TAG_LEN;
+        assert!(client_tx.send(&vec![0; largest]).await.unwrap());
+        assert_eq!(server_rx.recv().await.unwrap().len(), largest);
+        assert!(!client_tx.send(&vec![0; largest + 1]).await.unwrap());
+        assert_eq!(stats.over_tcp.load(Ordering::Relaxed), 1);
+        assert_eq!(max_frame("[::1]:1".parse().unwrap(), 1500), largest - 20);
+        assert_eq!(
+            max_frame(addr, 1 << 20),
+            MAX_DATAGRAM - HEADER_LEN - TAG_LEN
+        );
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_tcp() {
+        // Probes to a port nobody answers on
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = blackhole.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let timeout = Duration::from_millis(300);
+        let (client, server) = tokio::join!(
+            setup(&mut a, Udp::Connect(addr), timeout, 1500, Arc::default()),
+            setup(
+                &mut b,
+                Udp::Listen(Some(socket)),
+                timeout,
+                1500,
+                Arc::default()
+            ),
+        );
+        assert!(client.unwrap().is_none());
+        assert!(server.unwrap().is_none());
+
+        // Without a socket on the other side
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let (client, server) = tokio::join!(
+            setup(&mut a, Udp::Connect(addr), timeout, 1500, Arc::default()),
+            setup(&mut b, Udp::Listen(None), timeout, 1500, Arc::default()),
+        );
+        assert!(client.unwrap().is_none());
+        assert!(server.unwrap().is_none());
+    }
+}
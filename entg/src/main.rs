@@ -1,13 +1,41 @@
+mod compress;
+mod datagram;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
 use clap::{ArgGroup, Parser};
-use log::info;
-use rats_tls::RatsTls;
-use tokio::{
-    io::{AsyncRead, AsyncWrite, DuplexStream},
-    net::{TcpListener, TcpStream},
+use futures::stream::{self, FuturesUnordered};
+use futures::{FutureExt, SinkExt, StreamExt};
+use log::{info, warn};
+use rats_tls::{
+    AppraisalPolicy, Config, QuoteVerification, RatsTls, RatsTlsAcceptor, RatsTlsConnector, Role,
+    Spid,
+};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
 };
+use tokio::runtime::{self, Runtime};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+use transport::{Address, Listener, Stream};
+
+use compress::{Algorithm, Compressor, Stats};
+use protocol::codec::{frame_codec, MAX_FRAME_SIZE};
+use protocol::heartbeat::{self, Heartbeat};
+use protocol::hello::{self, Agreed, Features, Hello, Node, NodeId};
+use protocol::padding::{Padding, Shaper};
+use protocol::relink::{self, LinkReader, Relink};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -17,17 +45,17 @@ use tokio::{
         .args(&["entg-connect", "entg-listen"]),
 ))]
 struct Args {
-    /// Host address and port of another ENTG, e.g. "172.17.0.1:6979"
+    /// Address of another ENTG, e.g. "172.17.0.1:6979", "unix:/run/entg.sock" or "vsock:3:6979"
     #[clap(long, value_parser)]
-    entg_connect: Option<String>,
+    entg_connect: Option<Address>,
 
-    /// Listen port for another ENTG Server
-    #[clap(long, value_parser, default_value_t = 6979)]
-    entg_listen: u16,
+    /// Listen address for another ENTG Server, a port, "host:port", "unix:<path>" or "vsock:<cid>:<port>"
+    #[clap(long, value_parser, default_value = "6979")]
+    entg_listen: Address,
 
-    /// Listen port for ENTA Agent
-    #[clap(long, value_parser, default_value_t = 6980)]
-    enta_listen: u16,
+    /// Listen address for ENTA Agent, a port, "host:port", "unix:<path>" or "vsock:<cid>:<port>"
+    #[clap(long, value_parser, default_value = "6980")]
+    enta_listen: Address,
 
     /// Establish rats-tls connection with entg
     #[clap(long, value_parser, default_value_t = false)]
@@ -36,146 +64,1462 @@ struct Args {
     /// Establish rats-tls connection with enta
     #[clap(long, value_parser, default_value_t = false)]
     enta_rats_tls: bool,
+
+    /// Appraisal policy file (JSON) that the evidence of another ENTG must satisfy
+    #[clap(long, value_parser)]
+    entg_policy: Option<PathBuf>,
+
+    /// Role in the rats-tls handshake with another ENTG, "client" or "server" [default: client with --entg-connect, otherwise server]
+    #[clap(long, value_parser)]
+    entg_role: Option<Role>,
+
+    /// TLS wrapper type of the rats-tls connection with another ENTG
+    #[clap(long, value_parser, default_value = "openssl")]
+    entg_tls_type: String,
+
+    /// Crypto wrapper type of the rats-tls connection with another ENTG
+    #[clap(long, value_parser, default_value = "openssl")]
+    entg_crypto: String,
+
+    /// Attester type of the rats-tls connection with another ENTG [default: "sgx_ecdsa" for server or mutual, otherwise "nullattester"]
+    #[clap(long, value_parser)]
+    entg_attester: Option<String>,
+
+    /// Verifier type of the rats-tls connection with another ENTG [default: "sgx_ecdsa" for client or mutual, otherwise "nullverifier"]
+    #[clap(long, value_parser)]
+    entg_verifier: Option<String>,
+
+    /// Attest both sides of the rats-tls connection with another ENTG
+    #[clap(long, value_parser, default_value_t = false)]
+    entg_mutual: bool,
+
+    /// Where sgx_ecdsa quotes of another ENTG are verified, "qvl" (Quote Verification Library) or "qel" (Quote Verification Enclave) [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    entg_quote_verification: Option<QuoteVerification>,
+
+    /// Type of the certification data in sgx_ecdsa quotes presented to another ENTG, 1 to 7, e.g. 5 for the PCK certificate chain [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    entg_quote_cert_type: Option<u8>,
+
+    /// SPID (32 hex digits) for sgx_epid quotes presented to another ENTG, required by the sgx_epid attester
+    #[clap(long, value_parser)]
+    entg_epid_spid: Option<Spid>,
+
+    /// Request linkable sgx_epid quotes for another ENTG
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        requires = "entg-epid-spid"
+    )]
+    entg_epid_linkable: bool,
+
+    /// Attest another ENTG again every given number of seconds: the link moves to a new connection, on which the other ENTG presents fresh evidence appraised against the --entg-policy file reloaded from disk, and is closed if it fails the appraisal
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), requires = "entg-rats-tls")]
+    entg_reattest_interval: Option<u64>,
+
+    /// Role in the rats-tls handshake with ENTA, "client" or "server"
+    #[clap(long, value_parser, default_value = "server")]
+    enta_role: Role,
+
+    /// TLS wrapper type of the rats-tls connection with ENTA
+    #[clap(long, value_parser, default_value = "openssl")]
+    enta_tls_type: String,
+
+    /// Crypto wrapper type of the rats-tls connection with ENTA
+    #[clap(long, value_parser, default_value = "openssl")]
+    enta_crypto: String,
+
+    /// Attester type of the rats-tls connection with ENTA [default: "sgx_ecdsa" for server or mutual, otherwise "nullattester"]
+    #[clap(long, value_parser)]
+    enta_attester: Option<String>,
+
+    /// Verifier type of the rats-tls connection with ENTA [default: "sgx_ecdsa" for client or mutual, otherwise "nullverifier"]
+    #[clap(long, value_parser)]
+    enta_verifier: Option<String>,
+
+    /// Attest both sides of the rats-tls connection with ENTA
+    #[clap(long, value_parser, default_value_t = false)]
+    enta_mutual: bool,
+
+    /// Where sgx_ecdsa quotes of ENTA are verified, "qvl" (Quote Verification Library) or "qel" (Quote Verification Enclave) [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    enta_quote_verification: Option<QuoteVerification>,
+
+    /// Type of the certification data in sgx_ecdsa quotes presented to ENTA, 1 to 7, e.g. 5 for the PCK certificate chain [default: left to librats_tls]
+    #[clap(long, value_parser)]
+    enta_quote_cert_type: Option<u8>,
+
+    /// SPID (32 hex digits) for sgx_epid quotes presented to ENTA, required by the sgx_epid attester
+    #[clap(long, value_parser)]
+    enta_epid_spid: Option<Spid>,
+
+    /// Request linkable sgx_epid quotes for ENTA
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        requires = "enta-epid-spid"
+    )]
+    enta_epid_linkable: bool,
+
+    /// Appraisal policy file (JSON) that the evidence of ENTA must satisfy
+    #[clap(long, value_parser)]
+    enta_policy: Option<PathBuf>,
+
+    /// Attest ENTA again every given number of seconds: ENTA is asked to move its link to a new connection, on which it presents fresh evidence appraised against the --enta-policy file reloaded from disk, and the link is closed if ENTA fails the appraisal
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), requires = "enta-rats-tls")]
+    enta_reattest_interval: Option<u64>,
+
+    /// Size in bytes of the buffers of rats-tls connections, which bounds the size of the TLS records sent, up to 16384
+    #[clap(long, value_parser, default_value_t = rats_tls::DEFAULT_BUFFER_SIZE)]
+    rats_tls_buffer_size: usize,
+
+    /// Gather the data forwarded in each direction up to this many bytes before writing it, instead of writing each read as it is
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: Option<u32>,
+
+    /// Wait up to this many microseconds for more data before writing a batch which is not full
+    #[clap(long, value_parser, default_value_t = 0, requires = "batch-size")]
+    batch_latency: u64,
+
+    /// Compress the frames sent to another ENTG, with the first of these algorithms ("lz4", "zstd", comma-separated) that it accepts. Both ENTGs of the link need the option
+    #[clap(long, value_parser, value_delimiter = ',')]
+    entg_compression: Vec<Algorithm>,
+
+    /// Send frames smaller than this many bytes to another ENTG uncompressed
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 128,
+        requires = "entg-compression"
+    )]
+    entg_compression_min_size: usize,
+
+    /// Stop compressing the packets of a flow for a while once one of them does not compress, e.g. because the flow is already compressed or encrypted
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        requires = "entg-compression"
+    )]
+    entg_compression_skip_incompressible: bool,
+
+    /// Pad the frames sent to another ENTG to the smallest of these sizes in bytes (comma-separated) they fit in, so that their lengths tell less about the packets
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        conflicts_with = "entg-compression"
+    )]
+    entg_pad_to: Vec<usize>,
+
+    /// Send frames to another ENTG at this constant rate per second, all padded to the largest --entg-pad-to size, with cover frames when there are no packets to send
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "entg-pad-to",
+        conflicts_with = "batch-size"
+    )]
+    entg_shape_rate: Option<u32>,
+
+    /// Pad the frames sent to ENTA to the smallest of these sizes in bytes (comma-separated) they fit in
+    #[clap(long, value_parser, value_delimiter = ',')]
+    enta_pad_to: Vec<usize>,
+
+    /// Send frames to ENTA at this constant rate per second, all padded to the largest --enta-pad-to size, with cover frames when there are no packets to send
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "enta-pad-to",
+        conflicts_with = "batch-size"
+    )]
+    enta_shape_rate: Option<u32>,
+
+    /// Carry the frames to and from another ENTG in UDP datagrams on the port of its TCP address, sealed with keys agreed over rats-tls, instead of over the TCP connection. The link stays on TCP if the other ENTG does not use the option, or if the datagrams do not get through
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        requires = "entg-rats-tls"
+    )]
+    entg_datagram: bool,
+
+    /// Stay on TCP if no datagram gets through to the other ENTG within this many seconds
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 3,
+        requires = "entg-datagram"
+    )]
+    entg_datagram_probe_timeout: u64,
+
+    /// MTU of the path to the other ENTG, in bytes. Frames too large for a datagram within it go over TCP rather than being fragmented by IP
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u16).range(576..),
+        default_value_t = 1500,
+        requires = "entg-datagram"
+    )]
+    entg_datagram_path_mtu: u16,
+
+    /// Largest frame accepted from ENTA or another ENTG, in bytes. A longer one closes the connection. Frames are looked at unless a peer cannot move its link to a new connection and neither compression, padding, heartbeats nor datagrams are used, in which case ENTG forwards the data as it is
+    #[clap(long, value_parser = clap::value_parser!(u32).range(68..=MAX_FRAME_SIZE as i64), default_value_t = MAX_FRAME_SIZE as u32)]
+    max_frame_size: u32,
+
+    /// Fail the handshake with a peer if it stops responding for this many seconds
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 30)]
+    handshake_timeout: u64,
+
+    /// Most connections from one address whose handshake is in progress, further ones are closed right away
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 4)]
+    max_handshakes_per_addr: u32,
+
+    /// Ping ENTA every given number of seconds, and close the links if it stops answering. ENTA needs --entg-heartbeat-interval to notice a dead ENTG in turn
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    enta_heartbeat_interval: Option<u64>,
+
+    /// Ping the other ENTG every given number of seconds, and close the links if it stops answering. The other ENTG needs the option as well
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    entg_heartbeat_interval: Option<u64>,
+
+    /// Number of pings in a row a peer may leave unanswered before the links are closed
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 3)]
+    heartbeat_misses: u32,
+
+    /// Wait for ENTA and the other ENTG again this many seconds after a link fails or is closed, instead of exiting
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_interval: Option<u64>,
+
+    /// Id of this ENTG in the hellos of its links, which names it in the logs of its peers [default: the host name]
+    #[clap(long, value_parser)]
+    node_id: Option<NodeId>,
+
+    /// Log the statistics of the links every given number of seconds, besides when they are closed
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: Option<u64>,
+
+    /// Run on a multi-threaded runtime with this many worker threads, instead of on a single thread
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    worker_threads: Option<u16>,
+
+    /// Path of librats_tls.so to load at runtime
+    #[cfg(feature = "dlopen")]
+    #[clap(long, value_parser, default_value = rats_tls::DEFAULT_LIBRARY_PATH)]
+    rats_tls_library: PathBuf,
 }
 
 trait AsyncStream: AsyncRead + AsyncWrite {}
 
 impl AsyncStream for DuplexStream {}
-impl AsyncStream for TcpStream {}
+impl AsyncStream for Stream {}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+/// A connection with a peer, over rats-tls or not
+type LinkStream = Pin<Box<dyn AsyncStream>>;
+
+/// A connected stream, with the rats-tls session if it runs over rats-tls.
+type Link = (LinkStream, Option<Arc<RatsTls>>);
+
+fn main() -> Result<()> {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
 
     let args = Args::parse();
+    runtime(args.worker_threads)?.block_on(run(args))
+}
+
+/// Builds a runtime on the current thread, or a multi-threaded one with the
+/// given number of worker threads.
+fn runtime(worker_threads: Option<u16>) -> std::io::Result<Runtime> {
+    let mut builder = match worker_threads {
+        Some(worker_threads) => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(worker_threads.into());
+            builder
+        }
+        None => runtime::Builder::new_current_thread(),
+    };
+    builder.enable_all().build()
+}
 
-    let task1 = get_enta_stream(args.enta_listen, args.enta_rats_tls);
-    tokio::pin!(task1);
+async fn run(args: Args) -> Result<()> {
+    #[cfg(feature = "dlopen")]
+    if args.enta_rats_tls || args.entg_rats_tls {
+        rats_tls::load_library(&args.rats_tls_library).context("Failed to load librats_tls")?;
+    }
+
+    let handshake_timeout = Duration::from_secs(args.handshake_timeout);
+    let enta_tls = if args.enta_rats_tls {
+        let mut config = Config::with_plugins(
+            args.enta_role,
+            args.enta_mutual,
+            args.enta_tls_type.clone(),
+            args.enta_crypto.clone(),
+            args.enta_attester.clone(),
+            args.enta_verifier.clone(),
+        );
+        config.set_quote_options(
+            args.enta_quote_verification,
+            args.enta_quote_cert_type,
+            args.enta_epid_spid,
+            args.enta_epid_linkable,
+        );
+        config.buffer_size = args.rats_tls_buffer_size;
+        config.handshake_timeout = Some(handshake_timeout);
+        config.policy = args
+            .enta_policy
+            .as_ref()
+            .map(AppraisalPolicy::from_file)
+            .transpose()
+            .context("Failed to load appraisal policy for ENTA")?
+            .map(Arc::new);
+        let acceptor = RatsTlsAcceptor::new(config).context("Failed to init rats-tls for ENTA")?;
+        Some(LinkTls::Acceptor(acceptor))
+    } else {
+        None
+    };
 
-    let task2 = get_entg_stream(args.entg_connect, args.entg_listen, args.entg_rats_tls);
-    tokio::pin!(task2);
+    let entg_tls = if args.entg_rats_tls {
+        let role = args.entg_role.unwrap_or(match args.entg_connect {
+            Some(_) => Role::Client,
+            None => Role::Server,
+        });
+        let mut config = Config::with_plugins(
+            role,
+            args.entg_mutual,
+            args.entg_tls_type.clone(),
+            args.entg_crypto.clone(),
+            args.entg_attester.clone(),
+            args.entg_verifier.clone(),
+        );
+        config.set_quote_options(
+            args.entg_quote_verification,
+            args.entg_quote_cert_type,
+            args.entg_epid_spid,
+            args.entg_epid_linkable,
+        );
+        config.buffer_size = args.rats_tls_buffer_size;
+        config.handshake_timeout = Some(handshake_timeout);
+        config.policy = args
+            .entg_policy
+            .as_ref()
+            .map(AppraisalPolicy::from_file)
+            .transpose()
+            .context("Failed to load appraisal policy for ENTG")?
+            .map(Arc::new);
+        // Built once and shared by the sessions, so that the evidence is too
+        // where the backend allows it
+        let tls = match args.entg_connect {
+            Some(_) => RatsTlsConnector::new(config).map(LinkTls::Connector),
+            None => RatsTlsAcceptor::new(config).map(LinkTls::Acceptor),
+        };
+        Some(tls.context("Failed to init rats-tls for ENTG")?)
+    } else {
+        None
+    };
 
-    let mut enta_stream = None;
-    let mut entg_stream = None;
+    let node_id = args.node_id.clone().unwrap_or_else(NodeId::hostname);
+    // ENTG carries the super-packets of any ENTA as they are, and follows a
+    // link to a new connection whenever the peer moves it
+    let mut features = Features::VNET_HDR | Features::RELINK;
+    features.set(Features::BATCHING, args.batch_size.is_some());
+    let mut enta_features = features;
+    enta_features.set(Features::PADDING, !args.enta_pad_to.is_empty());
+    enta_features.set(Features::HEARTBEAT, args.enta_heartbeat_interval.is_some());
+    let mut entg_features = features;
+    entg_features.set(Features::COMPRESSION, !args.entg_compression.is_empty());
+    entg_features.set(Features::PADDING, !args.entg_pad_to.is_empty());
+    entg_features.set(Features::HEARTBEAT, args.entg_heartbeat_interval.is_some());
+    let entg_address = args.entg_connect.as_ref().unwrap_or(&args.entg_listen);
+    let datagrams = matches!(entg_address, Address::Tcp(_));
+    if args.entg_datagram && !datagrams {
+        warn!(
+            "Datagrams need a TCP address of ENTG, staying on {}",
+            entg_address
+        );
+    }
+    entg_features.set(Features::DATAGRAM, args.entg_datagram && datagrams);
+    let max_frame_size = args.max_frame_size as usize;
+    let mut entg_hello = Hello::new(Node::Entg, node_id.clone(), entg_features, max_frame_size);
+    entg_hello.compression = compress::accepted(&args.entg_compression);
 
-    while enta_stream.is_none() || entg_stream.is_none() {
-        tokio::select! {
-            v1 = (&mut task1), if enta_stream.is_none() => {
-                enta_stream = Some(v1?);
-            },
-            v2 = (&mut task2), if entg_stream.is_none() => {
-                entg_stream = Some(v2?);
-            },
+    let entg_padding = padding(args.entg_pad_to.clone())?;
+    // Shaped links send every frame at the largest size, so ENTA segments its
+    // super-packets to fit in it, and larger frames are dropped
+    let enta_max_frame_size = match (&entg_padding, args.entg_shape_rate) {
+        (Some(padding), Some(_)) => max_frame_size.min(padding.largest()),
+        _ => max_frame_size,
+    };
+
+    let shared = Shared {
+        enta_hello: Hello::new(Node::Entg, node_id, enta_features, enta_max_frame_size),
+        entg_hello,
+        entg_padding,
+        enta_padding: padding(args.enta_pad_to.clone())?,
+        compression: Arc::new(Stats::default()),
+        enta_heartbeat: Arc::new(heartbeat::Stats::default()),
+        entg_heartbeat: Arc::new(heartbeat::Stats::default()),
+        datagram: Arc::new(datagram::Stats::default()),
+    };
+    let mut stats: Vec<(&str, Arc<dyn fmt::Display + Send + Sync>)> = Vec::new();
+    if !args.entg_compression.is_empty() {
+        stats.push(("Compression to ENTG", shared.compression.clone()));
+    }
+    if let Some(padding) = &shared.entg_padding {
+        stats.push(("Padding to ENTG", padding.stats()));
+    }
+    if let Some(padding) = &shared.enta_padding {
+        stats.push(("Padding to ENTA", padding.stats()));
+    }
+    if args.entg_heartbeat_interval.is_some() {
+        stats.push(("Heartbeat with ENTG", shared.entg_heartbeat.clone()));
+    }
+    if args.enta_heartbeat_interval.is_some() {
+        stats.push(("Heartbeat with ENTA", shared.enta_heartbeat.clone()));
+    }
+    if shared.entg_hello.features.contains(Features::DATAGRAM) {
+        stats.push(("Datagrams with ENTG", shared.datagram.clone()));
+    }
+
+    // The listeners stay bound for the connections links move to
+    let enta_listener = bind(&args.enta_listen).await?;
+    let entg_listener;
+    let ways = Ways {
+        enta: Way::Accept(&args.enta_listen, &enta_listener),
+        entg: match &args.entg_connect {
+            Some(entg_connect) => Way::Connect(entg_connect),
+            None => {
+                entg_listener = bind(&args.entg_listen).await?;
+                Way::Accept(&args.entg_listen, &entg_listener)
+            }
+        },
+    };
+    let sessions = async {
+        let (mut enta_tls, mut entg_tls) = (enta_tls, entg_tls);
+        loop {
+            let result = session(&args, ways, &mut enta_tls, &mut entg_tls, &shared).await;
+            let interval = match (result, args.reconnect_interval) {
+                (result, None) => return result,
+                (Ok(()), Some(interval)) => interval,
+                (Err(e), Some(interval)) => {
+                    warn!("{:#}", e);
+                    interval
+                }
+            };
+            info!("Waiting for ENTA and ENTG again in {} seconds", interval);
+            tokio::time::sleep(Duration::from_secs(interval)).await;
         }
+    };
+    tokio::select!(
+        r = sessions => r?,
+        _ = report_stats(&stats, args.stats_interval) => {},
+    );
+
+    for (name, stats) in &stats {
+        info!("{}: {}", name, stats);
     }
 
-    let enta_stream = enta_stream.unwrap();
-    let entg_stream = entg_stream.unwrap();
+    info!("Shutdown ENTG Server");
+    Ok(())
+}
+
+/// What the sessions of ENTG share: the hellos and padding of the links, and
+/// the statistics kept over all of them
+struct Shared {
+    enta_hello: Hello,
+    entg_hello: Hello,
+    entg_padding: Option<Padding>,
+    enta_padding: Option<Padding>,
+    compression: Arc<Stats>,
+    enta_heartbeat: Arc<heartbeat::Stats>,
+    entg_heartbeat: Arc<heartbeat::Stats>,
+    datagram: Arc<datagram::Stats>,
+}
+
+/// How ENTG sets up its links with ENTA and the other ENTG
+#[derive(Clone, Copy)]
+struct Ways<'a> {
+    enta: Way<'a>,
+    entg: Way<'a>,
+}
+
+/// Waits for ENTA and the other ENTG, and forwards between them until either
+/// link is closed. The links move to new connections meanwhile, whenever a
+/// peer is attested again.
+async fn session(
+    args: &Args,
+    ways: Ways<'_>,
+    enta_tls: &mut Option<LinkTls>,
+    entg_tls: &mut Option<LinkTls>,
+    shared: &Shared,
+) -> Result<()> {
+    let handshake_timeout = Duration::from_secs(args.handshake_timeout);
+    let limits = Limits {
+        handshake_timeout,
+        handshakes_per_addr: args.max_handshakes_per_addr as usize,
+    };
+    let (enta_link, entg_link) = tokio::try_join!(
+        open_link(
+            ways.enta,
+            enta_tls.as_ref(),
+            &shared.enta_hello,
+            Node::Enta,
+            limits
+        ),
+        open_link(
+            ways.entg,
+            entg_tls.as_ref(),
+            &shared.entg_hello,
+            Node::Entg,
+            limits
+        ),
+    )?;
+
+    let ((enta_stream, enta_session), enta_hello, enta_agreed) = enta_link;
+    let ((mut entg_stream, entg_session), entg_hello, entg_agreed) = entg_link;
+    // Only what the peer uses as well goes on each link
+    let on_enta = |feature| enta_agreed.features.contains(feature);
+    let on_entg = |feature| entg_agreed.features.contains(feature);
+
+    let datagrams = match (
+        shared.entg_hello.features.contains(Features::DATAGRAM),
+        on_entg(Features::DATAGRAM),
+    ) {
+        (true, true) => {
+            let probe_timeout = Duration::from_secs(args.entg_datagram_probe_timeout);
+            let udp = datagram_udp(args.entg_connect.as_ref(), &args.entg_listen).await?;
+            let setup = datagram::setup(
+                &mut entg_stream,
+                udp,
+                probe_timeout,
+                args.entg_datagram_path_mtu.into(),
+                shared.datagram.clone(),
+            );
+            let datagrams = tokio::time::timeout(handshake_timeout + probe_timeout, setup)
+                .await
+                .context("Timed out setting up datagrams with ENTG")?
+                .context("Failed to set up datagrams with ENTG")?;
+            match datagrams {
+                Some(_) => info!("Carrying frames to and from ENTG in UDP datagrams"),
+                None => warn!("Datagrams do not get through to ENTG, carrying frames over TCP"),
+            }
+            datagrams
+        }
+        (true, false) => {
+            info!("ENTG does not use datagrams, carrying frames over TCP");
+            None
+        }
+        _ => None,
+    };
+    let (datagram_tx, datagram_rx) = match datagrams {
+        Some((tx, rx)) => (Some(tx), Some(rx)),
+        None => (None, None),
+    };
+
+    let compressor = if !on_entg(Features::COMPRESSION) {
+        None
+    } else {
+        let algorithm = compress::select(&args.entg_compression, entg_hello.compression);
+        match algorithm {
+            Some(algorithm) => info!("Compressing frames to ENTG with {}", algorithm),
+            None => info!(
+                "ENTG accepts none of the compression algorithms, sending frames uncompressed"
+            ),
+        }
+        Some(Compressor::new(
+            algorithm,
+            args.entg_compression_min_size,
+            args.entg_compression_skip_incompressible,
+            shared.compression.clone(),
+        ))
+    };
 
     let (mut enta_r, mut enta_w) = tokio::io::split(enta_stream);
     let (mut entg_r, mut entg_w) = tokio::io::split(entg_stream);
 
     info!("Start forwarding");
 
-    tokio::select!(
-        r = tokio::io::copy(&mut enta_r, &mut entg_w) => {info!("Connection from ENTA is closed"); r?},
-        r = tokio::io::copy(&mut entg_r, &mut enta_w) => {info!("Connection from ENTG is closed"); r?},
+    // The new connections each link moves to: the reader goes to the direction
+    // reading the link, and the writer to the one writing it
+    let (enta_readers_tx, enta_readers) = mpsc::channel(1);
+    let (enta_writers_tx, enta_writers) = mpsc::channel(1);
+    let (enta_asked_tx, enta_asked_rx) = mpsc::channel(1);
+    let (entg_readers_tx, entg_readers) = mpsc::channel(1);
+    let (entg_writers_tx, entg_writers) = mpsc::channel(1);
+    let (entg_asked_tx, entg_asked_rx) = mpsc::channel(1);
+    let relinks = on_enta(Features::RELINK) || on_entg(Features::RELINK);
+    // Moving a link, and attesting the peer again with it, runs beside the
+    // forwarding
+    let enta_relink = relink(
+        Relinks {
+            peer: Node::Enta,
+            way: ways.enta,
+            hello: &shared.enta_hello,
+            limits,
+            node_id: enta_hello.node_id,
+            agreed: enta_agreed,
+            policy: args.enta_policy.as_deref(),
+            interval: args.enta_reattest_interval.map(Duration::from_secs),
+            readers_tx: enta_readers_tx,
+            writers_tx: enta_writers_tx,
+            asked_rx: enta_asked_rx,
+        },
+        enta_session,
+        enta_tls,
+    );
+    let entg_relink = relink(
+        Relinks {
+            peer: Node::Entg,
+            way: ways.entg,
+            hello: &shared.entg_hello,
+            limits,
+            node_id: entg_hello.node_id.clone(),
+            agreed: entg_agreed,
+            policy: args.entg_policy.as_deref(),
+            interval: args.entg_reattest_interval.map(Duration::from_secs),
+            readers_tx: entg_readers_tx,
+            writers_tx: entg_writers_tx,
+            asked_rx: entg_asked_rx,
+        },
+        entg_session,
+        entg_tls,
     );
 
-    info!("Shutdown ENTG Server");
+    let heartbeat = |interval: Option<u64>, stats: &Arc<heartbeat::Stats>| {
+        interval.map(|interval| {
+            let interval = Duration::from_secs(interval);
+            Heartbeat::new(interval, args.heartbeat_misses, stats.clone())
+        })
+    };
+    let enta_heartbeat = heartbeat(
+        args.enta_heartbeat_interval
+            .filter(|_| on_enta(Features::HEARTBEAT)),
+        &shared.enta_heartbeat,
+    );
+    let entg_heartbeat = heartbeat(
+        args.entg_heartbeat_interval
+            .filter(|_| on_entg(Features::HEARTBEAT)),
+        &shared.entg_heartbeat,
+    );
+    // Pings and pongs to write on each link
+    let (enta_control_tx, enta_control_rx) = mpsc::channel(16);
+    let (entg_control_tx, entg_control_rx) = mpsc::channel(16);
+    let heartbeats = enta_heartbeat.is_some() || entg_heartbeat.is_some();
+
+    let batch = args
+        .batch_size
+        .map(|size| (size as usize, Duration::from_micros(args.batch_latency)));
+    let entg_padding = shared
+        .entg_padding
+        .clone()
+        .filter(|_| on_entg(Features::PADDING));
+    let enta_padding = shared
+        .enta_padding
+        .clone()
+        .filter(|_| on_enta(Features::PADDING));
+    let compressed = compressor.is_some();
+    let datagrams = datagram_tx.is_some();
+    let framed = compressed || heartbeats || datagrams || relinks;
+    let to_entg = async {
+        if framed || entg_padding.is_some() {
+            let frames = Frames {
+                max_frame_size: enta_agreed.max_frame_size as usize,
+                decompress: false,
+                compressor,
+                padding: entg_padding,
+                shape_rate: args.entg_shape_rate,
+                heartbeat: enta_heartbeat.as_ref().zip(Some(enta_control_tx.clone())),
+                control_rx: entg_heartbeat.as_ref().map(|_| entg_control_rx),
+                datagram_tx,
+                datagram_rx: None,
+                readers: enta_readers,
+                asked_tx: enta_asked_tx,
+                writers: entg_writers,
+                relink_timeout: handshake_timeout,
+            };
+            forward_frames(enta_r, entg_w, frames).await
+        } else {
+            let batch = batch.filter(|_| on_entg(Features::BATCHING));
+            forward(&mut enta_r, &mut entg_w, batch).await.map(drop)
+        }
+    };
+    let from_entg = async {
+        if framed || enta_padding.is_some() {
+            let frames = Frames {
+                max_frame_size: entg_agreed.max_frame_size as usize,
+                decompress: compressed,
+                compressor: None,
+                padding: enta_padding,
+                shape_rate: args.enta_shape_rate,
+                heartbeat: entg_heartbeat.as_ref().zip(Some(entg_control_tx.clone())),
+                control_rx: enta_heartbeat.as_ref().map(|_| enta_control_rx),
+                datagram_tx: None,
+                datagram_rx,
+                readers: entg_readers,
+                asked_tx: entg_asked_tx,
+                writers: enta_writers,
+                relink_timeout: handshake_timeout,
+            };
+            forward_frames(entg_r, enta_w, frames).await
+        } else {
+            let batch = batch.filter(|_| on_enta(Features::BATCHING));
+            forward(&mut entg_r, &mut enta_w, batch).await.map(drop)
+        }
+    };
+    tokio::select!(
+        r = to_entg => {
+            info!("Connection from ENTA is closed");
+            r.context("Failed to forward from ENTA to ENTG")?;
+        },
+        r = from_entg => {
+            info!("Connection from ENTG is closed");
+            r.context("Failed to forward from ENTG to ENTA")?;
+        },
+        Err(e) = enta_relink => return Err(e),
+        Err(e) = entg_relink => return Err(e),
+        r = ping(enta_heartbeat.as_ref(), &enta_control_tx, "ENTA") => r?,
+        r = ping(entg_heartbeat.as_ref(), &entg_control_tx, "ENTG") => r?,
+    );
     Ok(())
 }
 
-async fn get_enta_stream(
-    enta_listen_port: u16,
-    enta_rats_tls: bool,
-) -> Result<Pin<Box<dyn AsyncStream>>> {
-    info!("Waiting for ENTA on port {}", enta_listen_port);
-    let tcp_stream = listen_on_port(enta_listen_port).await?;
-    info!("Connection received from ENTA: {}", tcp_stream.peer_addr()?);
-    let stream: Pin<Box<dyn AsyncStream>> = if enta_rats_tls {
-        let stream = Box::pin(upgrade_to_rats_tls(tcp_stream, true).await?);
-        info!("Rats-tls channel with ENTA is established");
-        stream
-    } else {
-        Box::pin(tcp_stream)
-    };
-    Ok(stream)
-}
-
-async fn upgrade_to_rats_tls(stream: TcpStream, server: bool) -> Result<DuplexStream> {
-    let tls = if server {
-        RatsTls::new(
-            server,
-            0,
-            Some("openssl"),
-            Some("openssl"),
-            Some("sgx_ecdsa"),
-            Some("nullverifier"),
-            false,
-        )
-    } else {
-        RatsTls::new(
-            server,
-            0,
-            Some("openssl"),
-            Some("openssl"),
-            Some("nullattester"),
-            Some("sgx_ecdsa"),
-            false,
-        )
-    }
-    .map_err(|err| anyhow!("Failed to init rats-tls: error {:#x}", err))?;
-
-    tls.negotiate_async(stream)
-        .await
-        .context("Failed in rats-tls negotiation")
+fn padding(sizes: Vec<usize>) -> Result<Option<Padding>> {
+    if sizes.is_empty() {
+        return Ok(None);
+    }
+    Padding::new(sizes).map(Some).map_err(anyhow::Error::msg)
 }
 
-async fn get_entg_stream(
-    entg_connect: Option<String>,
-    entg_listen_port: u16,
-    entg_rats_tls: bool,
-) -> Result<Pin<Box<dyn AsyncStream>>> {
-    // TODO: replace `is_server` with the options to select attester and verifier
-    let is_server = entg_connect.is_none();
+/// How `forward_frames()` handles the frames of one direction
+struct Frames<'a, R, W> {
+    /// Largest frame read, once decompressed
+    max_frame_size: usize,
+    /// The frames read are compressed by the other ENTG
+    decompress: bool,
+    /// Compresses the frames written
+    compressor: Option<Compressor>,
+    padding: Option<Padding>,
+    shape_rate: Option<u32>,
+    /// Heartbeat of the link read from, and where the pongs answering the
+    /// pings on it go. Without one, pings and pongs are forwarded.
+    heartbeat: Option<(&'a Heartbeat, mpsc::Sender<Bytes>)>,
+    /// Pings and pongs to write on the link written to
+    control_rx: Option<mpsc::Receiver<Bytes>>,
+    /// Sends the frames written in datagrams, when they fit in one
+    datagram_tx: Option<datagram::Sender>,
+    /// Receives frames in datagrams, besides those read
+    datagram_rx: Option<datagram::Receiver>,
+    /// The readers of the new connections the link read from moves to, and
+    /// how long to wait for one once the peer moved
+    readers: mpsc::Receiver<R>,
+    relink_timeout: Duration,
+    /// Where requests of the peer on the link read from to connect again go
+    asked_tx: mpsc::Sender<()>,
+    /// What the writer of the link written to is told when the link moves
+    writers: mpsc::Receiver<Relink<W>>,
+}
+
+/// Writes frames in datagrams if there are any, and otherwise on a stream
+struct FrameSink<W> {
+    sink: FramedWrite<W, LengthDelimitedCodec>,
+    datagrams: Option<datagram::Sender>,
+}
 
-    let tcp_stream = match entg_connect {
-        Some(entg_connect) => {
-            info!("Connect to the peer ENTG: {}", entg_connect);
-            let stream = connect_to(entg_connect).await?;
-            info!("Connection established with ENTG: {}", stream.peer_addr()?);
-            stream
+impl<W: AsyncWrite + Unpin> FrameSink<W> {
+    async fn feed(&mut self, frame: Bytes) -> io::Result<()> {
+        if let Some(datagrams) = &mut self.datagrams {
+            if datagrams.send(&frame).await? {
+                return Ok(());
+            }
         }
-        _ => {
-            info!("Waiting for ENTG on port {}", entg_listen_port);
-            let stream = listen_on_port(entg_listen_port).await?;
-            info!("Connection received from ENTG: {}", stream.peer_addr()?);
-            stream
+        self.sink.feed(frame).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        SinkExt::<Bytes>::flush(&mut self.sink).await
+    }
+
+    /// Writes `RELINK`, or writes `MOVED` after the frames fed so far and
+    /// carries on on the writer of the new connection the link moves to.
+    /// Datagrams carry on as they are.
+    async fn relink(&mut self, relink: Relink<W>) -> io::Result<()> {
+        match relink {
+            Relink::Ask => self.sink.send(relink::relink()).await,
+            Relink::MoveTo(writer) => {
+                self.sink.send(relink::moved()).await?;
+                self.sink = FramedWrite::new(writer, LengthDelimitedCodec::new());
+                Ok(())
+            }
         }
+    }
+}
+
+/// Forwards the frames from `reader` to `writer` as described by `frames`.
+/// Frames already received when one is sent are written together with it,
+/// unless the link is shaped to a rate of frames per second.
+async fn forward_frames<R, W>(reader: R, writer: W, frames: Frames<'_, R, W>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Frames {
+        max_frame_size,
+        decompress,
+        mut compressor,
+        padding,
+        shape_rate,
+        heartbeat,
+        mut control_rx,
+        datagram_tx,
+        datagram_rx,
+        readers,
+        relink_timeout,
+        asked_tx,
+        mut writers,
+    } = frames;
+    let codec = match decompress {
+        true => frame_codec(max_frame_size + compress::HEADER_LEN),
+        false => frame_codec(max_frame_size),
+    };
+    let frames = LinkReader::new(reader, codec)
+        .follow(readers, asked_tx, relink_timeout)
+        .into_stream();
+    // Frames in datagrams are merged with those read, which still end the
+    // stream when they end
+    let mut frames = match datagram_rx {
+        Some(datagram_rx) => {
+            let datagrams =
+                stream::unfold(datagram_rx, |mut rx| async { Some((rx.recv().await, rx)) });
+            let frames = frames.map(Some).chain(stream::once(async { None }));
+            stream::select(frames, datagrams.map(Some))
+                .take_while(|frame| futures::future::ready(frame.is_some()))
+                .map(Option::unwrap)
+                .boxed_local()
+        }
+        None => frames.boxed_local(),
+    };
+    let mut sink = FrameSink {
+        sink: FramedWrite::new(writer, LengthDelimitedCodec::new()),
+        datagrams: datagram_tx,
     };
 
-    let stream: Pin<Box<dyn AsyncStream>> = if entg_rats_tls {
-        let stream = Box::pin(upgrade_to_rats_tls(tcp_stream, is_server).await?);
-        info!("Rats-tls channel with ENTG is established");
-        stream
-    } else {
-        Box::pin(tcp_stream)
+    // Returns the packet in a frame read, or `None` for a ping or pong, which
+    // is handled here
+    let receive = |frame: BytesMut| -> io::Result<Option<Bytes>> {
+        let frame = match decompress {
+            true => compress::decompress(frame, max_frame_size)?,
+            false => frame.freeze(),
+        };
+        match &heartbeat {
+            Some((heartbeat, pong_tx)) if heartbeat::is_heartbeat(&frame) => {
+                if let Some(pong) = heartbeat::receive(&frame, Some(heartbeat)) {
+                    // A pong which does not fit is as good as lost on the way
+                    let _ = pong_tx.try_send(pong);
+                }
+                Ok(None)
+            }
+            _ => Ok(Some(frame)),
+        }
+    };
+    let mut encode = |frame: Bytes| match &mut compressor {
+        Some(compressor) => compressor.compress(&frame),
+        None => Ok(frame),
+    };
+
+    if let (Some(padding), Some(rate)) = (&padding, shape_rate) {
+        let mut shaper = Shaper::new(padding.clone(), rate);
+        let (tx, mut rx) = mpsc::channel(128);
+        let read = async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = frames.next() => match frame {
+                        Some(frame) => match receive(frame?)? {
+                            Some(frame) => frame,
+                            None => continue,
+                        },
+                        None => break,
+                    },
+                    Some(frame) = recv(&mut control_rx) => frame,
+                };
+                if tx.send(encode(frame)?).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let write = async {
+            loop {
+                tokio::select! {
+                    shaped = shaper.next_frames(&mut rx) => match shaped {
+                        Some(shaped) => {
+                            for frame in shaped {
+                                sink.feed(frame).await?;
+                            }
+                            sink.flush().await?;
+                        }
+                        None => return Ok(()),
+                    },
+                    Some(relink) = writers.recv() => sink.relink(relink).await?,
+                }
+            }
+        };
+        return tokio::try_join!(read, write).map(drop);
+    }
+
+    let mut encode = |frame: Bytes| {
+        let frame = encode(frame)?;
+        Ok::<_, io::Error>(match &padding {
+            Some(padding) => padding.pad(frame),
+            None => frame,
+        })
+    };
+    loop {
+        tokio::select! {
+            biased;
+            Some(frame) = recv(&mut control_rx) => {
+                sink.feed(encode(frame)?).await?;
+                sink.flush().await?;
+            }
+            Some(relink) = writers.recv() => sink.relink(relink).await?,
+            frame = frames.next() => {
+                match frame {
+                    Some(frame) => if let Some(frame) = receive(frame?)? {
+                        sink.feed(encode(frame)?).await?;
+                    },
+                    None => return Ok(()),
+                }
+                while let Some(Some(frame)) = frames.next().now_or_never() {
+                    if let Some(frame) = receive(frame?)? {
+                        sink.feed(encode(frame)?).await?;
+                    }
+                }
+                sink.flush().await?;
+            }
+        }
+    }
+}
+
+/// Receives from `rx`, or waits forever without one
+async fn recv(rx: &mut Option<mpsc::Receiver<Bytes>>) -> Option<Bytes> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Pings `peer` with `heartbeat` over the control frames sent on `control_tx`.
+/// Only returns once the peer stops answering, and never without a heartbeat.
+async fn ping(
+    heartbeat: Option<&Heartbeat>,
+    control_tx: &mpsc::Sender<Bytes>,
+    peer: &str,
+) -> Result<()> {
+    match heartbeat {
+        Some(heartbeat) => heartbeat
+            .ping(control_tx)
+            .await
+            .with_context(|| format!("{} stopped answering pings, closing the link", peer)),
+        None => futures::future::pending().await,
+    }
+}
+
+/// Logs `stats` every `interval` seconds. Never returns.
+async fn report_stats(
+    stats: &[(&str, Arc<dyn fmt::Display + Send + Sync>)],
+    interval: Option<u64>,
+) {
+    let interval = match interval {
+        Some(interval) if !stats.is_empty() => Duration::from_secs(interval),
+        _ => return futures::future::pending().await,
+    };
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        for (name, stats) in stats {
+            info!("{}: {}", name, stats);
+        }
+    }
+}
+
+/// Copies everything from `reader` to `writer`. With `batch`, data is gathered
+/// as `copy_batched()` does, otherwise each read is written as it is.
+async fn forward<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    batch: Option<(usize, Duration)>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match batch {
+        Some((size, latency)) => copy_batched(reader, writer, size, latency).await,
+        None => tokio::io::copy(reader, writer).await,
+    }
+}
+
+/// Like `tokio::io::copy()`, but after each read it keeps reading whatever is
+/// already available, or arrives within `latency`, until `size` bytes are
+/// gathered, and writes them at once. ENTG does not parse the frames, so a
+/// batch may end in the middle of one.
+async fn copy_batched<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    size: usize,
+    latency: Duration,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; size];
+    let mut copied = 0;
+    loop {
+        let mut len = reader.read(&mut buf).await?;
+        if len == 0 {
+            writer.flush().await?;
+            return Ok(copied);
+        }
+        // Timers of tokio are too coarse for a budget of microseconds, so the
+        // task yields to others until it runs out
+        let deadline = std::time::Instant::now() + latency;
+        while len < size {
+            match reader.read(&mut buf[len..]).now_or_never() {
+                Some(Ok(0)) => break,
+                Some(r) => len += r?,
+                None if std::time::Instant::now() < deadline => tokio::task::yield_now().await,
+                None => break,
+            }
+        }
+        writer.write_all(&buf[..len]).await?;
+        copied += len as u64;
+    }
+}
+
+/// Limits on the connections accepted while waiting for a peer
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    handshake_timeout: Duration,
+    handshakes_per_addr: usize,
+}
+
+/// How ENTG sets up a link with a peer, and sets it up again when it moves
+#[derive(Clone, Copy)]
+enum Way<'a> {
+    /// Accepting the connections of the peer on the listener of the address
+    Accept(&'a Address, &'a Listener),
+    Connect(&'a Address),
+}
+
+/// Sets up a link with `peer` the given `way`: the connection, rats-tls on it
+/// with `tls` if any, and the exchange of hellos
+async fn open_link(
+    way: Way<'_>,
+    tls: Option<&LinkTls>,
+    hello: &Hello,
+    peer: Node,
+    limits: Limits,
+) -> Result<(Link, Hello, Agreed)> {
+    match way {
+        Way::Connect(addr) => {
+            info!("Connect to the peer {}: {}", peer, addr);
+            let stream = connect_to(addr).await?;
+            info!(
+                "Connection established with {}: {}",
+                peer,
+                stream.peer_addr()?
+            );
+            tokio::time::timeout(
+                limits.handshake_timeout,
+                handshake(stream, tls, hello, peer),
+            )
+            .await
+            .with_context(|| format!("Timed out in the handshake with {}", peer))?
+        }
+        Way::Accept(addr, listener) => {
+            info!("Waiting for {} on {}", peer, addr);
+            accept_link(listener, peer, limits, |stream| {
+                handshake(stream, tls, hello, peer)
+            })
+            .await
+        }
+    }
+}
+
+/// Negotiates rats-tls with `tls` on `stream` if any, and exchanges hellos
+async fn handshake(
+    stream: Stream,
+    tls: Option<&LinkTls>,
+    hello: &Hello,
+    peer: Node,
+) -> Result<(Link, Hello, Agreed)> {
+    let link = match tls {
+        Some(tls) => established(tls.negotiate(stream).await, peer)?,
+        None => (Box::pin(stream) as _, None),
     };
-    Ok(stream)
+    greet(link, hello, peer).await
+}
+
+/// rats-tls with a peer, on the connection to it or on the one accepted from
+/// it. The role in the handshake comes from the config either way.
+enum LinkTls {
+    Connector(RatsTlsConnector),
+    Acceptor(RatsTlsAcceptor),
+}
+
+impl LinkTls {
+    fn with_policy(&self, policy: Arc<AppraisalPolicy>) -> LinkTls {
+        match self {
+            LinkTls::Connector(connector) => LinkTls::Connector(connector.with_policy(policy)),
+            LinkTls::Acceptor(acceptor) => LinkTls::Acceptor(acceptor.with_policy(policy)),
+        }
+    }
+
+    async fn negotiate(&self, stream: Stream) -> io::Result<(Arc<RatsTls>, DuplexStream)> {
+        match self {
+            LinkTls::Connector(connector) => connector.connect_shared(stream).await,
+            LinkTls::Acceptor(acceptor) => acceptor.accept_shared(stream).await,
+        }
+    }
+}
+
+fn established(
+    negotiated: std::io::Result<(Arc<RatsTls>, DuplexStream)>,
+    peer: Node,
+) -> Result<Link> {
+    let (session, stream) = negotiated.context("Failed in rats-tls negotiation")?;
+    info!("Rats-tls channel with {} is established", peer);
+    Ok((Box::pin(stream), Some(session)))
 }
 
-async fn listen_on_port(port: u16) -> Result<TcpStream> {
-    let listener = TcpListener::bind(("0.0.0.0", port))
+/// The UDP side of the datagrams with the other ENTG: the address it listens on,
+/// or the socket this ENTG listens on, matching the TCP ones
+async fn datagram_udp(
+    entg_connect: Option<&Address>,
+    entg_listen: &Address,
+) -> Result<datagram::Udp> {
+    match (entg_connect, entg_listen) {
+        (Some(Address::Tcp(addr)), _) => {
+            let addr = tokio::net::lookup_host(addr)
+                .await?
+                .next()
+                .with_context(|| format!("Failed to resolve {}", addr))?;
+            Ok(datagram::Udp::Connect(addr))
+        }
+        (None, Address::Tcp(addr)) => match tokio::net::UdpSocket::bind(addr).await {
+            Ok(socket) => Ok(datagram::Udp::Listen(Some(socket))),
+            Err(e) => {
+                warn!("Failed to bind UDP {} for datagrams with ENTG: {}", addr, e);
+                Ok(datagram::Udp::Listen(None))
+            }
+        },
+        _ => unreachable!("datagrams are only offered on TCP addresses"),
+    }
+}
+
+/// Exchanges hellos with `peer` on `link`, and fails unless they agree.
+/// Returns the hello of the peer and what the two agreed on.
+async fn greet(link: Link, ours: &Hello, peer: Node) -> Result<(Link, Hello, Agreed)> {
+    let (mut stream, session) = link;
+    let theirs = hello::exchange(&mut stream, ours)
         .await
-        .with_context(|| format!("Failed to bind port {}", port))?;
+        .with_context(|| format!("Failed to exchange hellos with {}", peer))?;
+    let agreed = hello::agree(ours, &theirs, peer)
+        .with_context(|| format!("Failed to agree with {}", peer))?;
+    info!(
+        "{} {} speaks version {} of the protocol, features on the link: {}",
+        peer, theirs.node_id, agreed.version, agreed.features
+    );
+    let off = ours.features.without(agreed.features | Features::VNET_HDR);
+    if !off.is_empty() {
+        warn!("{} does not use {}, which stays off on the link", peer, off);
+    }
+    Ok(((stream, session), theirs, agreed))
+}
 
-    let (stream, _) = listener.accept().await?;
-    return Ok(stream);
+/// What `relink()` needs to move the link with a peer to a new connection
+struct Relinks<'a> {
+    peer: Node,
+    way: Way<'a>,
+    hello: &'a Hello,
+    limits: Limits,
+    /// The peer on the link, which has to be the one on the new connection
+    node_id: NodeId,
+    agreed: Agreed,
+    policy: Option<&'a Path>,
+    /// How often the peer is attested again, if at all
+    interval: Option<Duration>,
+    readers_tx: mpsc::Sender<ReadHalf<LinkStream>>,
+    writers_tx: mpsc::Sender<Relink<WriteHalf<LinkStream>>>,
+    /// Requests of the peer to connect again
+    asked_rx: mpsc::Receiver<()>,
 }
 
-async fn connect_to(addr: String) -> Result<TcpStream> {
-    Ok(TcpStream::connect(addr).await?)
+/// Moves the link with a peer to a new connection every `interval`, to attest
+/// the peer again, and whenever the peer moves it. ENTG connects again itself
+/// if it connected to the peer, and otherwise asks the peer to. The new
+/// connection is set up next to the old one, which carries packets until the
+/// new one is ready, and the link only moves once the peer has been attested
+/// on it. Returns an error once the link has to be closed: when the peer fails
+/// the appraisal of its evidence, or cannot be attested again within the
+/// handshake timeout. Never returns otherwise, nor for peers which cannot move
+/// their link.
+async fn relink(
+    relinks: Relinks<'_>,
+    mut session: Option<Arc<RatsTls>>,
+    tls: &mut Option<LinkTls>,
+) -> Result<()> {
+    let Relinks {
+        peer,
+        way,
+        hello,
+        limits,
+        node_id,
+        agreed,
+        policy,
+        interval,
+        readers_tx,
+        writers_tx,
+        mut asked_rx,
+    } = relinks;
+    if !agreed.features.contains(Features::RELINK) {
+        if interval.is_some() {
+            bail!(
+                "{} {} cannot move the link to a new connection, which attesting it again needs",
+                peer,
+                node_id
+            );
+        }
+        return futures::future::pending().await;
+    }
+    let mut due = interval.map(|interval| Instant::now() + interval);
+    // When the peer asked to connect again has to be attested by
+    let mut deadline = None;
+    loop {
+        let opened = match way {
+            Way::Accept(_, listener) => {
+                let accept = accept_link(listener, peer, limits, |stream| {
+                    handshake(stream, tls.as_ref(), hello, peer)
+                });
+                let accepted = tokio::select! {
+                    _ = until(due) => None,
+                    _ = until(deadline) => bail!(
+                        "{} could not be attested again within {} seconds, closing the link",
+                        peer,
+                        limits.handshake_timeout.as_secs()
+                    ),
+                    accepted = accept => Some(accepted?),
+                };
+                match accepted {
+                    Some(accepted) => accepted,
+                    None => {
+                        due = interval.map(|interval| Instant::now() + interval);
+                        reappraise(session.as_deref(), policy, peer, tls)?;
+                        info!(
+                            "Asking {} to connect again, to attest it on a new connection",
+                            peer
+                        );
+                        let _ = writers_tx.send(Relink::Ask).await;
+                        deadline = Some(Instant::now() + limits.handshake_timeout);
+                        continue;
+                    }
+                }
+            }
+            Way::Connect(_) => {
+                let reattesting = tokio::select! {
+                    _ = until(due) => true,
+                    Some(()) = asked_rx.recv() => false,
+                };
+                if reattesting {
+                    due = interval.map(|interval| Instant::now() + interval);
+                    reappraise(session.as_deref(), policy, peer, tls)?;
+                    info!("Attesting {} again on a new connection", peer);
+                } else {
+                    info!("{} asks to move the link to a new connection", peer);
+                }
+                let deadline = Instant::now() + limits.handshake_timeout;
+                let opened = loop {
+                    let open = open_link(way, tls.as_ref(), hello, peer, limits);
+                    match tokio::time::timeout_at(deadline, open).await {
+                        Ok(Ok(opened)) => break Some(opened),
+                        Ok(Err(e)) if rejected(&e) => {
+                            return Err(e.context(format!(
+                                "Re-attestation of {} failed, closing the link",
+                                peer
+                            )))
+                        }
+                        Ok(Err(e)) => warn!("{:#}", e),
+                        Err(_) => break None,
+                    }
+                    if Instant::now() + RELINK_RETRY_INTERVAL >= deadline {
+                        break None;
+                    }
+                    tokio::time::sleep(RELINK_RETRY_INTERVAL).await;
+                };
+                match opened {
+                    Some(opened) => opened,
+                    None if reattesting => bail!(
+                        "{} could not be attested again within {} seconds, closing the link",
+                        peer,
+                        limits.handshake_timeout.as_secs()
+                    ),
+                    None => {
+                        warn!(
+                            "Failed to move the link with {} to a new connection, staying on the old one",
+                            peer
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        let ((stream, new_session), theirs, new_agreed) = opened;
+        if theirs.node_id != node_id || new_agreed != agreed {
+            match way {
+                Way::Accept(..) => {
+                    warn!(
+                        "{} {} is not the one on the link, closing its connection",
+                        peer, theirs.node_id
+                    );
+                    continue;
+                }
+                Way::Connect(_) => bail!(
+                    "{} {} on the new connection is not the one on the link, closing the link",
+                    peer,
+                    theirs.node_id
+                ),
+            }
+        }
+        // The reader turns to the new connection once the peer moved as well
+        let (reader, writer) = tokio::io::split(stream);
+        let _ = readers_tx.send(reader).await;
+        let _ = writers_tx.send(Relink::MoveTo(writer)).await;
+        session = new_session;
+        deadline = None;
+        info!("Moved the link with {} to the new connection", peer);
+    }
+}
+
+/// How long to wait before connecting again when a new connection for a link
+/// fails
+const RELINK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reloads the policy from `policy`, and appraises the evidence of the current
+/// session against it, which fails right away if the policy file alone revokes
+/// the peer. The fresh evidence of the peer is appraised against the reloaded
+/// policy by `tls` from then on.
+fn reappraise(
+    session: Option<&RatsTls>,
+    policy: Option<&Path>,
+    peer: Node,
+    tls: &mut Option<LinkTls>,
+) -> Result<()> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(()),
+    };
+    let policy = policy
+        .map(AppraisalPolicy::from_file)
+        .transpose()
+        .with_context(|| format!("Failed to reload appraisal policy for {}", peer))?;
+    session
+        .reappraise_handshake_evidence(policy.as_ref().unwrap_or(&AppraisalPolicy::default()))
+        .with_context(|| format!("Re-attestation of {} failed, closing the link", peer))?;
+    if let Some(policy) = policy {
+        *tls = tls.take().map(|tls| tls.with_policy(Arc::new(policy)));
+    }
+    Ok(())
+}
+
+/// Whether `e` is the appraisal policy rejecting the evidence of the peer
+fn rejected(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        let e = e
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<rats_tls::Error>());
+        matches!(e, Some(rats_tls::Error::Rejected(_)))
+    })
+}
+
+/// Sleeps until `deadline`, or forever without one
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn bind(addr: &Address) -> Result<Listener> {
+    Listener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", addr))
+}
+
+/// Accepts connections on `listener` until the handshake of one of them succeeds.
+/// Handshakes run side by side and fail after the timeout of `limits`, so a
+/// peer which connects and stays silent holds no one else up. Connections from
+/// an address with too many handshakes in progress are closed right away.
+async fn accept_link<F, H, T>(
+    listener: &Listener,
+    peer: Node,
+    limits: Limits,
+    handshake: F,
+) -> Result<T>
+where
+    F: Fn(Stream) -> H,
+    H: Future<Output = Result<T>>,
+{
+    let mut pending = FuturesUnordered::new();
+    let mut in_progress: HashMap<String, usize> = HashMap::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer_addr) = accepted?;
+                let host = peer_host(&peer_addr);
+                let count = in_progress.entry(host.clone()).or_default();
+                if *count >= limits.handshakes_per_addr {
+                    warn!("Too many handshakes in progress from {}, closing the connection from {}", host, peer_addr);
+                    continue;
+                }
+                *count += 1;
+                info!("Connection received from {}: {}", peer, peer_addr);
+                let handshake = tokio::time::timeout(limits.handshake_timeout, handshake(stream));
+                pending.push(async move { (host, peer_addr, handshake.await) });
+            }
+            Some((host, peer_addr, result)) = pending.next() => {
+                if let Some(count) = in_progress.get_mut(&host) {
+                    *count -= 1;
+                    if *count == 0 {
+                        in_progress.remove(&host);
+                    }
+                }
+                match result {
+                    Ok(Ok(link)) => return Ok(link),
+                    Ok(Err(e)) => warn!("Handshake with {} {} failed: {:#}", peer, peer_addr, e),
+                    Err(_) => warn!("Handshake with {} {} timed out", peer, peer_addr),
+                }
+            }
+        }
+    }
+}
+
+/// The host part of `addr`, which connections are limited by. All local
+/// processes connecting over a unix socket count as one host.
+fn peer_host(addr: &Address) -> String {
+    match addr {
+        Address::Tcp(addr) => match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => addr.clone(),
+        },
+        Address::Unix(_) => "unix".to_owned(),
+        Address::Vsock { cid, .. } => format!("vsock:{}", cid),
+    }
+}
+
+async fn connect_to(addr: &Address) -> Result<Stream> {
+    transport::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}", addr))
 }
@@ -0,0 +1,33 @@
+//! Feeds arbitrary bytes received on a link to the frame decoder, in chunks as
+//! they would come off the socket, and the frames decoded to `unpad()`.
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use protocol::codec::{frame_codec, MAX_FRAME_SIZE};
+use protocol::padding::unpad;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk, data) = match data.split_first() {
+        Some((&chunk, data)) => (usize::from(chunk).max(1), data),
+        None => return,
+    };
+    let mut codec = frame_codec(MAX_FRAME_SIZE);
+    let mut buf = BytesMut::new();
+    for chunk in data.chunks(chunk) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(frame)) => {
+                    assert!(frame.len() <= MAX_FRAME_SIZE);
+                    if let Some(packet) = unpad(frame.freeze()) {
+                        assert!(!packet.is_empty());
+                    }
+                }
+                Ok(None) => break,
+                // The link is closed on the first error
+                Err(_) => return,
+            }
+        }
+    }
+});
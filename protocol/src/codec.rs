@@ -0,0 +1,41 @@
+//! Framing of the links: each frame is preceded by its length as a big-endian
+//! u32, as `LengthDelimitedCodec` does by default. The length is bounded, so
+//! that a peer cannot make the receiver buffer up to the 8 MiB the codec
+//! allows on its own, and a frame claiming more closes the link.
+use tokio_util::codec::LengthDelimitedCodec;
+
+/// Length of the virtio-net header in front of the super-packets of ENTAs
+/// using offloads
+pub const VNET_HDR_LEN: usize = 10;
+
+/// Largest frame a peer sends: an IP packet of the largest size, preceded by a
+/// virtio-net header
+pub const MAX_FRAME_SIZE: usize = VNET_HDR_LEN + u16::MAX as usize;
+
+/// Codec for the frames on a link, which fails on frames longer than
+/// `max_frame_size`
+pub fn frame_codec(max_frame_size: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_size)
+        .new_codec()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+
+    #[test]
+    fn bound_frame_length() {
+        let mut codec = frame_codec(1500);
+        let mut buf = BytesMut::new();
+        buf.put_u32(1500);
+        buf.put_slice(&[0x45; 1500]);
+        buf.put_u32(1501);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 1500);
+        // Rejected as soon as the length is read, without waiting for the data
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Heartbeat of a link: each side with a heartbeat sends a ping at an interval,
+//! which the other side answers with a pong, so that a peer which went away
+//! without closing the connection, e.g. behind a NAT which forgot the
+//! connection or on a host which crashed, is noticed and the link torn down.
+//!
+//! A ping is `PING` followed by the time it was sent, as a big-endian u64 of
+//! microseconds since the heartbeat started, which the pong echoes after
+//! `PONG` so that the side which sent the ping can tell the round-trip time.
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
+
+use crate::{PING, PONG};
+
+const FRAME_LEN: usize = 9;
+
+/// Counters of a heartbeat, kept over the connections of a link. They are
+/// shared with the task reporting them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub pings: AtomicU64,
+    pub pongs: AtomicU64,
+    /// Round-trip times of the pongs, in microseconds
+    pub last_rtt: AtomicU64,
+    pub min_rtt: AtomicU64,
+    pub max_rtt: AtomicU64,
+    pub total_rtt: AtomicU64,
+}
+
+impl Stats {
+    fn record(&self, rtt: u64) {
+        if self.pongs.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.min_rtt.store(rtt, Ordering::Relaxed);
+        } else {
+            self.min_rtt.fetch_min(rtt, Ordering::Relaxed);
+        }
+        self.max_rtt.fetch_max(rtt, Ordering::Relaxed);
+        self.total_rtt.fetch_add(rtt, Ordering::Relaxed);
+        self.last_rtt.store(rtt, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pongs = self.pongs.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} pings, {} pongs",
+            self.pings.load(Ordering::Relaxed),
+            pongs
+        )?;
+        if pongs == 0 {
+            return Ok(());
+        }
+        let ms = |us: u64| us as f64 / 1000.0;
+        write!(
+            f,
+            ", RTT last {:.3} ms, min {:.3} ms, avg {:.3} ms, max {:.3} ms",
+            ms(self.last_rtt.load(Ordering::Relaxed)),
+            ms(self.min_rtt.load(Ordering::Relaxed)),
+            ms(self.total_rtt.load(Ordering::Relaxed)) / pongs as f64,
+            ms(self.max_rtt.load(Ordering::Relaxed))
+        )
+    }
+}
+
+/// The heartbeat of one connection. It is shared by the task sending the
+/// pings and the one receiving the pongs.
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    misses: u32,
+    start: Instant,
+    /// When the next ping is due, in microseconds since `start`
+    next_ping: AtomicU64,
+    /// Pings sent since the last pong
+    unanswered: AtomicU32,
+    stats: Arc<Stats>,
+}
+
+impl Heartbeat {
+    /// Sends a ping every `interval`, and gives up on the peer once `misses`
+    /// pings in a row have gone unanswered for an interval each
+    pub fn new(interval: Duration, misses: u32, stats: Arc<Stats>) -> Heartbeat {
+        Heartbeat {
+            interval,
+            misses,
+            start: Instant::now(),
+            next_ping: AtomicU64::new(interval.as_micros() as u64),
+            unanswered: AtomicU32::new(0),
+            stats,
+        }
+    }
+
+    /// Sends pings on `tx`, which carries the control frames to the peer.
+    /// Returns an error once the peer stops answering them, and `Ok` once `tx`
+    /// is closed.
+    pub async fn ping(&self, tx: &Sender<Bytes>) -> io::Result<()> {
+        loop {
+            let ping = self.next_ping().await?;
+            if tx.send(ping).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next ping to be due and returns it
+    pub async fn next_ping(&self) -> io::Result<Bytes> {
+        let due = self.start + Duration::from_micros(self.next_ping.load(Ordering::Relaxed));
+        tokio::time::sleep_until(due).await;
+        if self.unanswered.load(Ordering::Relaxed) >= self.misses {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("peer answered none of the last {} pings", self.misses),
+            ));
+        }
+        // Pings that fell due while the task was held up are not made up for,
+        // they would only count as missed at once
+        let now = self.start.elapsed();
+        self.next_ping
+            .store((now + self.interval).as_micros() as u64, Ordering::Relaxed);
+        self.unanswered.fetch_add(1, Ordering::Relaxed);
+        self.stats.pings.fetch_add(1, Ordering::Relaxed);
+        Ok(frame(PING, now.as_micros() as u64))
+    }
+
+    /// Takes in a pong from the peer
+    pub fn receive_pong(&self, pong: &[u8]) {
+        let sent = match pong.get(1..FRAME_LEN) {
+            Some(sent) => u64::from_be_bytes(sent.try_into().unwrap()),
+            None => return,
+        };
+        let now = self.start.elapsed().as_micros() as u64;
+        self.unanswered.store(0, Ordering::Relaxed);
+        self.stats.record(now.saturating_sub(sent));
+    }
+}
+
+/// Whether `frame` is a ping or a pong
+pub fn is_heartbeat(frame: &[u8]) -> bool {
+    matches!(frame.first(), Some(&(PING | PONG)))
+}
+
+/// Handles a ping or pong from the peer: returns the pong answering a ping,
+/// and passes a pong on to `heartbeat`. Pongs are ignored without one.
+pub fn receive(frame: &[u8], heartbeat: Option<&Heartbeat>) -> Option<Bytes> {
+    match (frame.first(), heartbeat) {
+        (Some(&PING), _) => Some(pong(frame)),
+        (Some(&PONG), Some(heartbeat)) => {
+            heartbeat.receive_pong(frame);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// The pong answering `ping`
+pub fn pong(ping: &[u8]) -> Bytes {
+    let sent = match ping.get(1..FRAME_LEN) {
+        Some(sent) => u64::from_be_bytes(sent.try_into().unwrap()),
+        None => 0,
+    };
+    frame(PONG, sent)
+}
+
+fn frame(kind: u8, time: u64) -> Bytes {
+    let mut frame = BytesMut::with_capacity(FRAME_LEN);
+    frame.put_u8(kind);
+    frame.put_u64(time);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn answered_pings() {
+        let stats = Arc::new(Stats::default());
+        let heartbeat = Heartbeat::new(Duration::from_secs(1), 3, stats.clone());
+        let start = Instant::now();
+        for _ in 0..5 {
+            let ping = heartbeat.next_ping().await.unwrap();
+            assert!(is_heartbeat(&ping));
+            assert_eq!(ping[0], PING);
+            tokio::time::advance(Duration::from_millis(20)).await;
+            let pong = receive(&ping, None).unwrap();
+            assert!(is_heartbeat(&pong));
+            assert_eq!(receive(&pong, Some(&heartbeat)), None);
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(5020));
+        assert_eq!(stats.pings.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.pongs.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.min_rtt.load(Ordering::Relaxed), 20_000);
+        assert_eq!(stats.max_rtt.load(Ordering::Relaxed), 20_000);
+        assert!(stats.to_string().contains("avg 20.000 ms"));
+        assert!(!is_heartbeat(&[0x45, 0, 0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn give_up_on_silent_peer() {
+        let heartbeat = Heartbeat::new(Duration::from_secs(1), 3, Arc::default());
+        let (tx, mut rx) = mpsc::channel(16);
+        let start = Instant::now();
+        let err = heartbeat.ping(&tx).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Three pings, and an interval for the last one to be answered
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+        for _ in 0..3 {
+            assert_eq!(rx.try_recv().unwrap()[0], PING);
+        }
+        assert!(rx.try_recv().is_err());
+
+        // Any pong resets the count
+        let heartbeat = Heartbeat::new(Duration::from_secs(1), 2, Arc::default());
+        for _ in 0..2 {
+            heartbeat.next_ping().await.unwrap();
+        }
+        heartbeat.receive_pong(&pong(&[PING]));
+        for _ in 0..2 {
+            heartbeat.next_ping().await.unwrap();
+        }
+        assert!(heartbeat.next_ping().await.is_err());
+    }
+}
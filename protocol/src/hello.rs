@@ -0,0 +1,415 @@
+//! Hello of a link: once the connection, and rats-tls if used, is set up, both
+//! sides send a hello before any frame and read the one of the peer, so that
+//! nodes running different releases either agree on how to talk or fail with
+//! a clear error instead of misreading each other's frames.
+//!
+//! A hello is `ENTH`, the highest and lowest version of the protocol the node
+//! speaks, and the length of the rest as a big-endian u16, followed by:
+//!
+//! - the kind of node, 1 for ENTA and 2 for ENTG
+//! - the features it uses on the link, as a big-endian u32 bitmask
+//! - the compression algorithms it accepts, as a bitmask defined by ENTG
+//! - the largest frame it accepts, as a big-endian u32
+//! - the length of its node id as a u8, followed by the id in UTF-8
+//!
+//! Later versions may append fields, which older nodes skip by the length.
+use std::fmt;
+use std::io;
+use std::ops::{BitAnd, BitOr};
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: &[u8; 4] = b"ENTH";
+
+/// Highest version of the protocol this release speaks
+pub const VERSION: u8 = 1;
+
+/// Lowest version of the protocol this release speaks
+pub const MIN_VERSION: u8 = 1;
+
+/// Bytes before the fields: the magic, the versions and the length of the
+/// fields
+const HEADER_LEN: usize = 8;
+
+/// Bytes of the fields of version 1 besides the node id
+const FIELDS_LEN: usize = 11;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The kind of a node on a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    Enta = 1,
+    Entg = 2,
+}
+
+impl TryFrom<u8> for Node {
+    type Error = io::Error;
+
+    fn try_from(kind: u8) -> io::Result<Node> {
+        match kind {
+            1 => Ok(Node::Enta),
+            2 => Ok(Node::Entg),
+            _ => Err(invalid(format!("unknown kind of node {}", kind))),
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Enta => write!(f, "ENTA"),
+            Node::Entg => write!(f, "ENTG"),
+        }
+    }
+}
+
+/// Features a node uses on a link. Bits unknown to this release are kept, but
+/// mean nothing to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Frames are compressed, and start with the byte telling how. Both sides
+    /// of the link need it.
+    pub const COMPRESSION: Features = Features(1 << 0);
+    /// Frames are written in batches
+    pub const BATCHING: Features = Features(1 << 1);
+    /// Frames may hold super-packets behind a virtio-net header of
+    /// `codec::VNET_HDR_LEN` bytes
+    pub const VNET_HDR: Features = Features(1 << 2);
+    /// Frames are padded, and cover frames may be sent
+    pub const PADDING: Features = Features(1 << 3);
+    /// The node pings the peer, and answers its pings
+    pub const HEARTBEAT: Features = Features(1 << 4);
+    /// Frames between ENTGs may be carried in UDP datagrams
+    pub const DATAGRAM: Features = Features(1 << 5);
+    /// The link may move to a new connection, see `relink`
+    pub const RELINK: Features = Features(1 << 6);
+
+    const NAMES: [(Features, &'static str); 7] = [
+        (Features::COMPRESSION, "compression"),
+        (Features::BATCHING, "batching"),
+        (Features::VNET_HDR, "vnet-hdr"),
+        (Features::PADDING, "padding"),
+        (Features::HEARTBEAT, "heartbeat"),
+        (Features::DATAGRAM, "datagram"),
+        (Features::RELINK, "relink"),
+    ];
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Adds `other` if `on`, and removes it otherwise
+    pub fn set(&mut self, other: Features, on: bool) {
+        match on {
+            true => self.0 |= other.0,
+            false => self.0 &= !other.0,
+        }
+    }
+
+    /// The features of `self` not in `other`
+    pub fn without(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Features;
+
+    fn bitand(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Features::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name);
+        match names.next() {
+            Some(name) => write!(f, "{}", name)?,
+            None => return write!(f, "none"),
+        }
+        for name in names {
+            write!(f, ",{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Id of a node, given in its hello, which names it in the logs of its peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeId(String);
+
+impl NodeId {
+    /// The host name, which is what identifies a node unless told otherwise
+    pub fn hostname() -> NodeId {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .and_then(|name| name.trim().parse().ok())
+            .unwrap_or_else(|| NodeId("unknown".to_owned()))
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            0 => Err("empty node id".to_owned()),
+            1..=255 => Ok(NodeId(s.to_owned())),
+            _ => Err(format!("node id of {} bytes, expect at most 255", s.len())),
+        }
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub min_version: u8,
+    pub node: Node,
+    pub node_id: NodeId,
+    pub features: Features,
+    /// Compression algorithms accepted, as a bitmask defined by ENTG
+    pub compression: u8,
+    /// Largest frame accepted
+    pub max_frame_size: u32,
+}
+
+impl Hello {
+    /// The hello of this release, for a node using `features`
+    pub fn new(node: Node, node_id: NodeId, features: Features, max_frame_size: usize) -> Hello {
+        Hello {
+            version: VERSION,
+            min_version: MIN_VERSION,
+            node,
+            node_id,
+            features,
+            compression: 0,
+            max_frame_size: max_frame_size as u32,
+        }
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        let id = self.node_id.0.as_bytes();
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + FIELDS_LEN + id.len());
+        buf.put_slice(MAGIC);
+        buf.put_u8(self.version);
+        buf.put_u8(self.min_version);
+        buf.put_u16((FIELDS_LEN + id.len()) as u16);
+        buf.put_u8(self.node as u8);
+        buf.put_u32(self.features.0);
+        buf.put_u8(self.compression);
+        buf.put_u32(self.max_frame_size);
+        buf.put_u8(id.len() as u8);
+        buf.put_slice(id);
+        buf
+    }
+
+    /// Decodes the fields of a hello, following `version` and `min_version`
+    /// from its header
+    pub fn decode(version: u8, min_version: u8, mut fields: &[u8]) -> io::Result<Hello> {
+        if fields.len() < FIELDS_LEN {
+            return Err(invalid(format!("hello of {} bytes", fields.len())));
+        }
+        let node = Node::try_from(fields.get_u8())?;
+        let features = Features(fields.get_u32());
+        let compression = fields.get_u8();
+        let max_frame_size = fields.get_u32();
+        let id_len = usize::from(fields.get_u8());
+        let node_id = fields
+            .get(..id_len)
+            .and_then(|id| std::str::from_utf8(id).ok())
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| invalid("invalid node id in hello".to_owned()))?;
+        Ok(Hello {
+            version,
+            min_version,
+            node,
+            node_id,
+            features,
+            compression,
+            max_frame_size,
+        })
+    }
+}
+
+/// Sends `ours` on `stream` and returns the hello of the peer.
+pub async fn exchange<S>(stream: &mut S, ours: &Hello) -> io::Result<Hello>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&ours.encode()).await?;
+    stream.flush().await?;
+
+    let mut header = [0; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    if &header[..4] != MAGIC {
+        return Err(invalid(
+            "the peer sent no hello, it may run a release which predates it".to_owned(),
+        ));
+    }
+    let mut fields = vec![0; usize::from(u16::from_be_bytes([header[6], header[7]]))];
+    stream.read_exact(&mut fields).await?;
+    Hello::decode(header[4], header[5], &fields)
+}
+
+/// What both sides of a link agreed on in their hellos
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Agreed {
+    /// Highest version of the protocol both speak
+    pub version: u8,
+    /// Features both use
+    pub features: Features,
+    /// Smallest of the largest frames the two accept
+    pub max_frame_size: u32,
+}
+
+/// Checks the hello of the peer against `ours`, and returns what the two agree
+/// on. Fails if they have no version in common, if the peer is not an
+/// `expected` node, or if only one of them compresses its frames.
+pub fn agree(ours: &Hello, theirs: &Hello, expected: Node) -> io::Result<Agreed> {
+    let version = ours.version.min(theirs.version);
+    if version < ours.min_version.max(theirs.min_version) {
+        return Err(invalid(format!(
+            "{} {} speaks versions {} to {} of the protocol, this node {} to {}",
+            theirs.node,
+            theirs.node_id,
+            theirs.min_version,
+            theirs.version,
+            ours.min_version,
+            ours.version
+        )));
+    }
+    if theirs.node != expected {
+        return Err(invalid(format!(
+            "expect {} but {} {} connected, check the address",
+            expected, theirs.node, theirs.node_id
+        )));
+    }
+    let compression = Features::COMPRESSION;
+    if ours.features.contains(compression) != theirs.features.contains(compression) {
+        let side = match ours.features.contains(compression) {
+            true => "this node",
+            false => "the peer",
+        };
+        return Err(invalid(format!(
+            "only {} compresses its frames, both sides of the link need compression",
+            side
+        )));
+    }
+    Ok(Agreed {
+        version,
+        features: ours.features & theirs.features,
+        max_frame_size: ours.max_frame_size.min(theirs.max_frame_size),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(node: Node, id: &str, features: Features) -> Hello {
+        Hello::new(node, id.parse().unwrap(), features, 1500)
+    }
+
+    #[tokio::test]
+    async fn exchange_hellos() {
+        let enta = hello(Node::Enta, "enta-0", Features::VNET_HDR | Features::HEARTBEAT);
+        let mut entg = hello(Node::Entg, "entg-0", Features::HEARTBEAT);
+        entg.max_frame_size = 9000;
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let (a, b) = tokio::join!(exchange(&mut a, &enta), exchange(&mut b, &entg));
+        assert_eq!(a.unwrap(), entg);
+        assert_eq!(b.unwrap(), enta);
+
+        let agreed = agree(&enta, &entg, Node::Entg).unwrap();
+        assert_eq!(agreed.version, VERSION);
+        assert_eq!(agreed.features, Features::HEARTBEAT);
+        assert_eq!(enta.features.without(agreed.features), Features::VNET_HDR);
+        assert!(entg.features.without(agreed.features).is_empty());
+        assert_eq!(agreed.max_frame_size, 1500);
+        assert_eq!(enta.features.to_string(), "vnet-hdr,heartbeat");
+        assert_eq!(Features::default().to_string(), "none");
+
+        // A peer of a later version with fields this one does not know
+        let mut later = entg.encode();
+        later[4] = VERSION + 1;
+        later[7] += 2;
+        later.put_u16(0xffff);
+        let (mut a, mut b) = tokio::io::duplex(64);
+        b.write_all(&later).await.unwrap();
+        let theirs = exchange(&mut a, &enta).await.unwrap();
+        assert_eq!(theirs.node_id, entg.node_id);
+        assert_eq!(agree(&enta, &theirs, Node::Entg).unwrap().version, VERSION);
+    }
+
+    #[tokio::test]
+    async fn reject_mismatches() {
+        let enta = hello(Node::Enta, "enta-0", Features::default());
+        let entg = hello(Node::Entg, "entg-0", Features::default());
+
+        // An ENTA connecting where an ENTG is expected
+        let err = agree(&entg, &enta, Node::Entg).unwrap_err();
+        assert!(err.to_string().contains("expect ENTG but ENTA enta-0"));
+
+        // No version in common
+        let mut later = entg.clone();
+        later.version = VERSION + 2;
+        later.min_version = VERSION + 1;
+        assert!(agree(&enta, &later, Node::Entg).is_err());
+        assert!(agree(&later, &enta, Node::Enta).is_err());
+
+        // Compression on one side only
+        let compressed = hello(Node::Entg, "entg-1", Features::COMPRESSION);
+        assert!(agree(&entg, &compressed, Node::Entg).is_err());
+        assert!(agree(&compressed, &entg, Node::Entg).is_err());
+
+        // A peer of a release without the hello starts with a frame
+        let (mut a, mut b) = tokio::io::duplex(64);
+        b.write_all(&[0, 0, 0, 20, 0x45, 0, 0, 20]).await.unwrap();
+        let err = exchange(&mut a, &enta).await.unwrap_err();
+        assert!(err.to_string().contains("no hello"));
+
+        assert!(Hello::decode(VERSION, MIN_VERSION, &[2, 0, 0]).is_err());
+        assert!("".parse::<NodeId>().is_err());
+        assert!("x".repeat(256).parse::<NodeId>().is_err());
+    }
+
+    #[test]
+    fn set_features() {
+        let mut features = Features::VNET_HDR;
+        features.set(Features::HEARTBEAT, true);
+        assert_eq!(features, Features::VNET_HDR | Features::HEARTBEAT);
+        features.set(Features::VNET_HDR, false);
+        assert_eq!(features, Features::HEARTBEAT);
+        // Clearing a feature which is not there changes nothing
+        features.set(Features::PADDING, false);
+        assert_eq!(features, Features::HEARTBEAT);
+    }
+}
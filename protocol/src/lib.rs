@@ -0,0 +1,30 @@
+//! The frames ENTA and ENTG exchange on their links, besides the `ENPacket`s
+//! themselves. A frame holds an IP packet, possibly preceded by a virtio-net
+//! header, unless the high nibble of its first byte is `0xf`, which marks the
+//! frames that carry no packet:
+//!
+//! - `0xf0`: cover traffic, dropped by the receiving ENTA
+//! - `0xf1`, `0xf2`: ping and pong of the heartbeat of a link
+//! - `0xf3`, `0xf4`: moving a link to a new connection, see `relink`
+//!
+//! Before the first frame, both sides of a link exchange a hello.
+pub mod codec;
+pub mod heartbeat;
+pub mod hello;
+pub mod padding;
+pub mod relink;
+
+/// First byte of a cover frame
+pub const COVER: u8 = 0xf0;
+
+/// First byte of a ping
+pub const PING: u8 = 0xf1;
+
+/// First byte of a pong
+pub const PONG: u8 = 0xf2;
+
+/// A frame asking the peer to connect again and move the link there
+pub const RELINK: u8 = 0xf3;
+
+/// The last frame written on a connection a link moves away from
+pub const MOVED: u8 = 0xf4;
@@ -0,0 +1,376 @@
+//! Padding of frames to size buckets, and shaping of links to a constant rate
+//! of frames, so that the lengths and timing of the frames on a link tell less
+//! about the packets they carry.
+//!
+//! Only frames holding an IP packet, bare or behind a virtio-net header, or a
+//! control frame are padded, with zeros after the packet, which `unpad()`
+//! cuts off again by the length in the IP header. Control frames are read up
+//! to their own length.
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+
+use crate::codec::VNET_HDR_LEN;
+use crate::COVER;
+
+/// Counters of the frames sent on a link with padding. They are shared with
+/// the task reporting them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Frames carrying packets
+    pub frames: AtomicU64,
+    pub cover_frames: AtomicU64,
+    /// Bytes of the packets before padding
+    pub packet_bytes: AtomicU64,
+    /// Bytes added to the packets
+    pub padding_bytes: AtomicU64,
+    pub cover_bytes: AtomicU64,
+    /// Frames sent as they are, larger than every size or holding no packet
+    /// that can be padded
+    pub unpadded: AtomicU64,
+    /// Frames larger than those of a shaped link, dropped rather than sent
+    /// as they are
+    pub dropped: AtomicU64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let packet_bytes = self.packet_bytes.load(Ordering::Relaxed);
+        let overhead =
+            self.padding_bytes.load(Ordering::Relaxed) + self.cover_bytes.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} frames, {} not padded, {} dropped as too large, {} cover frames, {} bytes of packets, {} bytes of padding, {} bytes of cover, overhead {:.1}%",
+            self.frames.load(Ordering::Relaxed),
+            self.unpadded.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.cover_frames.load(Ordering::Relaxed),
+            packet_bytes,
+            self.padding_bytes.load(Ordering::Relaxed),
+            self.cover_bytes.load(Ordering::Relaxed),
+            if packet_bytes == 0 {
+                0.0
+            } else {
+                overhead as f64 * 100.0 / packet_bytes as f64
+            }
+        )
+    }
+}
+
+/// Pads frames to the smallest of a set of sizes they fit in. Frames larger
+/// than all of them are sent as they are, except on shaped links.
+#[derive(Debug, Clone)]
+pub struct Padding {
+    buckets: Vec<usize>,
+    stats: Arc<Stats>,
+}
+
+impl Padding {
+    pub fn new(mut buckets: Vec<usize>) -> Result<Padding, String> {
+        buckets.sort_unstable();
+        buckets.dedup();
+        match buckets.first() {
+            None => return Err("no size to pad frames to".to_owned()),
+            Some(0) => return Err("frames cannot be padded to 0 bytes".to_owned()),
+            Some(_) => {}
+        }
+        Ok(Padding {
+            buckets,
+            stats: Arc::new(Stats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Largest size frames are padded to, which shaped links pad every frame to
+    pub fn largest(&self) -> usize {
+        *self.buckets.last().unwrap()
+    }
+
+    pub fn pad(&self, frame: Bytes) -> Bytes {
+        match self.buckets.iter().find(|&&size| size >= frame.len()) {
+            Some(&size) => self.pad_to(frame, size),
+            None => self.pad_to(frame, 0),
+        }
+    }
+
+    /// Pads `frame` to `size` bytes, if it holds an IP packet or a control
+    /// frame shorter than that
+    pub fn pad_to(&self, frame: Bytes, size: usize) -> Bytes {
+        if frame.first() == Some(&COVER) {
+            // Cover frames from ENTA come padded already
+            return frame;
+        }
+        self.stats.frames.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .packet_bytes
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        let paddable = match frame.first().map(|b| b >> 4) {
+            Some(4 | 6 | 0xf) => true,
+            // A packet behind a virtio-net header is cut by its IP length too
+            Some(0) => matches!(frame.get(VNET_HDR_LEN).map(|b| b >> 4), Some(4 | 6)),
+            _ => false,
+        };
+        if !paddable || frame.len() > size {
+            self.stats.unpadded.fetch_add(1, Ordering::Relaxed);
+            return frame;
+        }
+        if frame.len() == size {
+            return frame;
+        }
+        self.stats
+            .padding_bytes
+            .fetch_add((size - frame.len()) as u64, Ordering::Relaxed);
+        let mut padded = BytesMut::with_capacity(size);
+        padded.put_slice(&frame);
+        padded.resize(size, 0);
+        padded.freeze()
+    }
+
+    /// A cover frame of `size` bytes
+    pub fn cover(&self, size: usize) -> Bytes {
+        self.stats.cover_frames.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .cover_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+        let mut frame = BytesMut::zeroed(size.max(1));
+        frame[0] = COVER;
+        frame.freeze()
+    }
+}
+
+/// Returns the packet in `frame` without the padding after it, or `None` for
+/// a cover or control frame. Frames whose packet is not shorter than the frame
+/// are returned as they are, and none is cut shorter than an IP header.
+pub fn unpad(frame: Bytes) -> Option<Bytes> {
+    let offset = match frame.first()? >> 4 {
+        0xf => return None,
+        0 => VNET_HDR_LEN,
+        _ => 0,
+    };
+    let len = match frame.get(offset..).and_then(ip_len) {
+        Some(len) => offset + len,
+        None => return Some(frame),
+    };
+    if len < frame.len() {
+        Some(frame.slice(..len))
+    } else {
+        Some(frame)
+    }
+}
+
+/// Length of the IP packet at the start of `packet` by its header, and at
+/// least that of the header
+fn ip_len(packet: &[u8]) -> Option<usize> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 4 => {
+            Some(usize::from(u16::from_be_bytes([packet[2], packet[3]])).max(20))
+        }
+        6 if packet.len() >= 6 => {
+            Some(40 + usize::from(u16::from_be_bytes([packet[4], packet[5]])))
+        }
+        _ => None,
+    }
+}
+
+/// Sends frames at a constant rate: whenever frames are due, the packets
+/// queued are sent padded to the largest size of the padding, and cover frames
+/// take the place of those missing. Packets larger than that size are dropped,
+/// as their length would give them away.
+pub struct Shaper {
+    padding: Padding,
+    rate: u32,
+    start: Instant,
+    sent: u64,
+    /// Control frames, sent before the packets
+    control: VecDeque<Bytes>,
+}
+
+impl Shaper {
+    /// Shapes the link to `rate` frames per second
+    pub fn new(padding: Padding, rate: u32) -> Shaper {
+        Shaper {
+            padding,
+            rate,
+            start: Instant::now(),
+            sent: 0,
+            control: VecDeque::new(),
+        }
+    }
+
+    /// Queues a control frame, which takes the place of a packet in the next
+    /// frames due
+    pub fn push(&mut self, frame: Bytes) {
+        self.control.push_back(frame);
+    }
+
+    /// Waits for the next frames to be due and returns them. Returns `None`
+    /// once `rx` is closed and empty.
+    pub async fn next_frames(&mut self, rx: &mut Receiver<Bytes>) -> Option<Vec<Bytes>> {
+        let rate = u64::from(self.rate);
+        let next = self.start + Duration::from_secs_f64((self.sent + 1) as f64 / rate as f64);
+        tokio::time::sleep_until(next).await;
+        // Timers of tokio fire once a millisecond at best, so higher rates are
+        // met by sending the frames due since the last tick together
+        let due = (self.start.elapsed().as_secs_f64() * rate as f64) as u64;
+        // A link stalled for more than a second does not make up for all of it
+        let count = due.saturating_sub(self.sent).clamp(1, rate);
+        self.sent = due.max(self.sent + 1);
+
+        let size = self.padding.largest();
+        let mut frames = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if let Some(frame) = self.control.pop_front() {
+                frames.push(self.padding.pad_to(frame, size));
+                continue;
+            }
+            let frame = loop {
+                match rx.try_recv() {
+                    Ok(packet) if packet.len() > size => {
+                        self.padding.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(packet) => break Some(self.padding.pad_to(packet, size)),
+                    Err(TryRecvError::Empty) => break Some(self.padding.cover(size)),
+                    Err(TryRecvError::Disconnected) => break None,
+                }
+            };
+            match frame {
+                Some(frame) => frames.push(frame),
+                None if frames.is_empty() => return None,
+                None => break,
+            }
+        }
+        Some(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(len: usize) -> Bytes {
+        let mut packet = vec![0; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        Bytes::from(packet)
+    }
+
+    fn ipv6_packet(len: usize) -> Bytes {
+        let mut packet = vec![0; len];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&(len as u16 - 40).to_be_bytes());
+        Bytes::from(packet)
+    }
+
+    #[test]
+    fn pad_and_unpad() {
+        let padding = Padding::new(vec![1500, 128, 512]).unwrap();
+        for (packet, size) in [
+            (ipv4_packet(60), 128),
+            (ipv4_packet(128), 128),
+            (ipv6_packet(600), 1500),
+            (ipv6_packet(2000), 2000),
+        ] {
+            let padded = padding.pad(packet.clone());
+            assert_eq!(padded.len(), size);
+            assert_eq!(unpad(padded), Some(packet));
+        }
+        // Frames holding no IP packet cannot be cut by its length
+        let vnet = Bytes::from(vec![1; 100]);
+        assert_eq!(padding.pad(vnet.clone()), vnet);
+        assert_eq!(unpad(vnet.clone()), Some(vnet));
+        // nor can packets claiming to be shorter than their header
+        let mut bogus = vec![0; 100];
+        bogus[0] = 0x45;
+        assert_eq!(unpad(Bytes::from(bogus)).unwrap().len(), 20);
+        let bogus = Bytes::from(vec![0x40, 0, 0, 0]);
+        assert_eq!(unpad(bogus.clone()), Some(bogus));
+
+        let cover = padding.cover(512);
+        assert_eq!(cover.len(), 512);
+        assert_eq!(unpad(cover.clone()), None);
+        assert_eq!(padding.pad(cover.clone()), cover);
+
+        let stats = padding.stats();
+        assert_eq!(stats.frames.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.cover_frames.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.packet_bytes.load(Ordering::Relaxed), 2888);
+        assert_eq!(stats.padding_bytes.load(Ordering::Relaxed), 68 + 900);
+        assert_eq!(stats.unpadded.load(Ordering::Relaxed), 2);
+
+        assert!(Padding::new(vec![]).is_err());
+        assert!(Padding::new(vec![0, 100]).is_err());
+    }
+
+    #[test]
+    fn pad_behind_vnet_header() {
+        let padding = Padding::new(vec![128, 1500]).unwrap();
+        for packet in [ipv4_packet(60), ipv6_packet(600)] {
+            let mut frame = BytesMut::zeroed(VNET_HDR_LEN);
+            // VIRTIO_NET_HDR_F_NEEDS_CSUM
+            frame[0] = 1;
+            frame.put_slice(&packet);
+            let frame = frame.freeze();
+            let padded = padding.pad(frame.clone());
+            assert!(padded.len() == 128 || padded.len() == 1500);
+            assert_eq!(&padded[..frame.len()], &frame[..]);
+            assert_eq!(unpad(padded), Some(frame));
+        }
+        assert_eq!(padding.stats().unpadded.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shape_to_constant_rate() {
+        let padding = Padding::new(vec![1000]).unwrap();
+        let mut shaper = Shaper::new(padding, 10000);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        for _ in 0..15 {
+            tx.send(ipv4_packet(100)).await.unwrap();
+        }
+
+        // 10 frames are due every millisecond, filled with packets first
+        let start = Instant::now();
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            frames.extend(shaper.next_frames(&mut rx).await.unwrap());
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(3));
+        assert_eq!(frames.len(), 30);
+        assert!(frames.iter().all(|frame| frame.len() == 1000));
+        assert_eq!(
+            frames
+                .iter()
+                .filter_map(|frame| unpad(frame.clone()))
+                .count(),
+            15
+        );
+
+        // Control frames go first, padded as well
+        tx.send(ipv4_packet(100)).await.unwrap();
+        shaper.push(Bytes::from_static(&[crate::PING, 1]));
+        let frames = shaper.next_frames(&mut rx).await.unwrap();
+        assert_eq!(&frames[0][..2], &[crate::PING, 1]);
+        assert_eq!(frames[0].len(), 1000);
+        assert_eq!(unpad(frames[1].clone()).unwrap(), ipv4_packet(100));
+
+        // Packets larger than the frames are dropped, not sent as they are
+        tx.send(ipv4_packet(1200)).await.unwrap();
+        tx.send(ipv4_packet(100)).await.unwrap();
+        let frames = shaper.next_frames(&mut rx).await.unwrap();
+        assert!(frames.iter().all(|frame| frame.len() == 1000));
+        assert_eq!(unpad(frames[0].clone()).unwrap(), ipv4_packet(100));
+        assert_eq!(shaper.padding.stats().dropped.load(Ordering::Relaxed), 1);
+
+        drop(tx);
+        assert!(shaper.next_frames(&mut rx).await.is_none());
+    }
+}
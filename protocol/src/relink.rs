@@ -0,0 +1,211 @@
+//! Moving a link to a new connection, so that the peer can be attested again
+//! with fresh evidence while packets keep flowing over the old one.
+//!
+//! The side which connects opens the new connection next to the old one, with
+//! a rats-tls handshake and hellos of its own, and the side which accepts it
+//! checks that the hellos agree as they did on the old one. Each side then
+//! writes `MOVED` as the last frame on the old connection and carries on
+//! writing on the new one. Each side also reads the old connection up to the
+//! `MOVED` of the peer before it turns to the new one, so no frame is lost or
+//! reordered on the way. The side which accepts asks the other to connect
+//! again with `RELINK`.
+//!
+//! Both are one-byte frames meant for the peer alone, written as they are:
+//! neither compressed nor padded, and never forwarded.
+use std::io;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+use crate::{MOVED, RELINK};
+
+/// What the writer of a link is told by the task moving the link
+#[derive(Debug)]
+pub enum Relink<W> {
+    /// Write `RELINK`, asking the peer to connect again
+    Ask,
+    /// Write `MOVED`, then carry on with the writer of the new connection
+    MoveTo(W),
+}
+
+/// Reads the frames of a link, following it to the connections it moves to
+pub struct LinkReader<R> {
+    frames: FramedRead<R, LengthDelimitedCodec>,
+    follow: Option<Follow<R>>,
+}
+
+struct Follow<R> {
+    readers: mpsc::Receiver<R>,
+    asked_tx: mpsc::Sender<()>,
+    timeout: Duration,
+}
+
+impl<R: AsyncRead + Unpin> LinkReader<R> {
+    /// Reads the frames on `reader` with `codec`. `MOVED` and `RELINK` are
+    /// returned like any other frame, unless the reader follows the link.
+    pub fn new(reader: R, codec: LengthDelimitedCodec) -> LinkReader<R> {
+        LinkReader {
+            frames: FramedRead::new(reader, codec),
+            follow: None,
+        }
+    }
+
+    /// Turns to the next reader from `readers` on each `MOVED`, and fails if
+    /// none comes within `timeout`. Each `RELINK` is passed on to `asked_tx`
+    /// rather than returned.
+    pub fn follow(
+        mut self,
+        readers: mpsc::Receiver<R>,
+        asked_tx: mpsc::Sender<()>,
+        timeout: Duration,
+    ) -> LinkReader<R> {
+        self.follow = Some(Follow {
+            readers,
+            asked_tx,
+            timeout,
+        });
+        self
+    }
+
+    /// The next frame on the link, or `None` once the connection it is on
+    /// is closed
+    pub async fn next(&mut self) -> Option<io::Result<BytesMut>> {
+        loop {
+            let frame = match self.frames.next().await? {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+            let follow = match &mut self.follow {
+                Some(follow) => follow,
+                None => return Some(Ok(frame)),
+            };
+            match frame.first() {
+                Some(&MOVED) => {
+                    let reader = tokio::time::timeout(follow.timeout, follow.readers.recv()).await;
+                    let reader = match reader {
+                        Ok(Some(reader)) => reader,
+                        _ => {
+                            return Some(Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "the link moved to a connection which did not come up",
+                            )))
+                        }
+                    };
+                    let codec = self.frames.decoder().clone();
+                    self.frames = FramedRead::new(reader, codec);
+                }
+                Some(&RELINK) => {
+                    // A request which does not fit waits behind another one
+                    let _ = follow.asked_tx.try_send(());
+                }
+                _ => return Some(Ok(frame)),
+            }
+        }
+    }
+
+    /// The frames on the link as a stream
+    pub fn into_stream(self) -> impl Stream<Item = io::Result<BytesMut>> {
+        futures::stream::unfold(self, |mut reader| async move {
+            reader.next().await.map(|frame| (frame, reader))
+        })
+    }
+}
+
+/// The last frame on a connection the link moves away from
+pub fn moved() -> Bytes {
+    Bytes::from_static(&[MOVED])
+}
+
+/// The frame asking the peer to connect again and move the link there
+pub fn relink() -> Bytes {
+    Bytes::from_static(&[RELINK])
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::FramedWrite;
+
+    use super::*;
+    use crate::codec::frame_codec;
+
+    fn sink(stream: DuplexStream) -> FramedWrite<DuplexStream, LengthDelimitedCodec> {
+        FramedWrite::new(stream, LengthDelimitedCodec::new())
+    }
+
+    #[tokio::test]
+    async fn follow_moves_without_losing_frames() {
+        let (readers_tx, readers_rx) = mpsc::channel(1);
+        let (asked_tx, mut asked_rx) = mpsc::channel(1);
+        let (near, far) = tokio::io::duplex(64);
+        let mut reader = LinkReader::new(far, frame_codec(1500)).follow(
+            readers_rx,
+            asked_tx,
+            Duration::from_secs(1),
+        );
+        // Frames keep being written while the link moves twice, with the new
+        // connection coming up before and after the old one ends
+        let write = async move {
+            let mut writer = sink(near);
+            for i in 0..300u16 {
+                if i == 120 {
+                    writer.send(relink()).await.unwrap();
+                }
+                if i == 100 || i == 200 {
+                    let (near, far) = tokio::io::duplex(64);
+                    if i == 100 {
+                        readers_tx.send(far).await.unwrap();
+                        writer.send(moved()).await.unwrap();
+                    } else {
+                        writer.send(moved()).await.unwrap();
+                        readers_tx.send(far).await.unwrap();
+                    }
+                    writer = sink(near);
+                }
+                writer
+                    .send(Bytes::copy_from_slice(&i.to_be_bytes()))
+                    .await
+                    .unwrap();
+            }
+        };
+        let read = async {
+            let mut frames = Vec::new();
+            while let Some(frame) = reader.next().await {
+                frames.push(u16::from_be_bytes(frame.unwrap()[..].try_into().unwrap()));
+            }
+            frames
+        };
+        let ((), frames) = tokio::join!(write, read);
+        assert_eq!(frames, (0..300).collect::<Vec<_>>());
+        assert!(asked_rx.try_recv().is_ok());
+        assert!(asked_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fail_on_move_to_nowhere() {
+        let (near, far) = tokio::io::duplex(64);
+        let (_, readers_rx) = mpsc::channel::<DuplexStream>(1);
+        let (asked_tx, _asked_rx) = mpsc::channel(1);
+        let mut reader = LinkReader::new(far, frame_codec(1500)).follow(
+            readers_rx,
+            asked_tx,
+            Duration::from_secs(1),
+        );
+        let mut writer = sink(near);
+        writer.send(moved()).await.unwrap();
+        let err = reader.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Frames are returned as they are by a reader which does not follow
+        let (near, far) = tokio::io::duplex(64);
+        let mut reader = LinkReader::new(far, frame_codec(1500));
+        let mut writer = sink(near);
+        writer.send(moved()).await.unwrap();
+        assert_eq!(&reader.next().await.unwrap().unwrap()[..], &[MOVED]);
+    }
+}
@@ -0,0 +1,66 @@
+//! Throughput of a rats-tls connection over loopback for bursts of MTU-sized
+//! writes, as produced by forwarding packets, with different buffer sizes.
+//! It runs on the mock backend, so it measures the bridge between the socket
+//! and the stream rather than the TLS stack of librats_tls:
+//!
+//!     cargo bench -p rats-tls --features mock
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rats_tls::{Config, RatsTls, Role, MAX_BUFFER_SIZE};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+const PACKET_SIZE: usize = 1400;
+const PACKETS: usize = 4096;
+
+async fn connect(buffer_size: usize) -> (DuplexStream, DuplexStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut tls = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        tls.set_buffer_size(buffer_size).unwrap();
+        tls.negotiate_async(stream).await.unwrap()
+    });
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut tls = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+    tls.set_buffer_size(buffer_size).unwrap();
+    let client = tls.negotiate_async(stream).await.unwrap();
+    (client, server.await.unwrap())
+}
+
+async fn transfer(client: &mut DuplexStream, server: &mut DuplexStream) {
+    let send = async {
+        let packet = [0x5a; PACKET_SIZE];
+        for _ in 0..PACKETS {
+            client.write_all(&packet).await.unwrap();
+        }
+    };
+    let receive = async {
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+        while received < PACKET_SIZE * PACKETS {
+            received += server.read(&mut buf).await.unwrap();
+        }
+    };
+    tokio::join!(send, receive);
+}
+
+fn throughput(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("throughput");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes((PACKET_SIZE * PACKETS) as u64));
+    for buffer_size in [1024, 4096, MAX_BUFFER_SIZE] {
+        let (mut client, mut server) = rt.block_on(connect(buffer_size));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &buffer_size,
+            |b, _| b.iter(|| rt.block_on(transfer(&mut client, &mut server))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -1,3 +1,137 @@
-fn main(){
-    println!("cargo:rustc-link-lib=rats_tls");
-}
\ No newline at end of file
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Structs passed between Rust and librats_tls, whose layout must match the
+/// headers of the library being linked
+const SHARED_STRUCTS: &[&str] = &[
+    "rats_tls_conf_t",
+    "rtls_evidence_t",
+    "rtls_sgx_evidence_t",
+    "ehd_t",
+];
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let include_dir = include_dir();
+    let header = include_dir.join("rats-tls/api.h");
+    println!("cargo:rerun-if-env-changed=RATS_TLS_INCLUDE_DIR");
+    println!("cargo:rerun-if-changed={}", include_dir.display());
+
+    if env::var_os("CARGO_FEATURE_BINDGEN").is_some() {
+        generate_bindings(&include_dir, &header, &out_dir);
+    }
+
+    let mut layout = String::new();
+    if env::var_os("CARGO_FEATURE_DLOPEN").is_none() && env::var_os("CARGO_FEATURE_MOCK").is_none()
+    {
+        println!("cargo:rustc-link-lib=rats_tls");
+
+        if !header.exists() {
+            println!(
+                "cargo:warning=rats-tls headers not found in {}, struct layouts are not checked",
+                include_dir.display()
+            );
+        } else if env::var("TARGET").unwrap() != env::var("HOST").unwrap() {
+            println!(
+                "cargo:warning=struct layouts of librats_tls are not checked when cross compiling"
+            );
+        } else {
+            layout = check_layout(&include_dir, &header, &out_dir);
+        }
+    }
+    fs::write(out_dir.join("layout.rs"), layout).unwrap();
+}
+
+/// The headers of deps/rats-tls, which both the host and the occlum mode
+/// librats_tls.so are built from, unless `RATS_TLS_INCLUDE_DIR` says otherwise.
+fn include_dir() -> PathBuf {
+    match env::var_os("RATS_TLS_INCLUDE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap())
+            .join("../deps/rats-tls/src/include"),
+    }
+}
+
+#[cfg(feature = "bindgen")]
+fn generate_bindings(include_dir: &Path, header: &Path, out_dir: &Path) {
+    if !header.exists() {
+        panic!(
+            "{} does not exist, check out the deps/rats-tls submodule or set RATS_TLS_INCLUDE_DIR",
+            header.display()
+        );
+    }
+    // Functions are declared by hand in ffi.rs, as they are also resolved by
+    // the loader with the dlopen feature. Only the items of librats_tls are
+    // taken, leaving out what its headers pull in from libc.
+    bindgen::Builder::default()
+        .header(header.to_str().unwrap())
+        .clang_arg(format!("-I{}", include_dir.display()))
+        .allowlist_type("rats_tls_.*|rtls_.*|ehd.*|enclave_.*|quote_sgx_.*|.*_err_t|size_t")
+        .allowlist_var("RATS_TLS_.*|ENCLAVE_.*|TLS_WRAPPER_.*|CRYPTO_WRAPPER_.*|SGX_.*|ERR_CODE_.*|VERIFICATION_TYPE_.*|.*_TYPE_NAME_SIZE|SHA256_HASH_SIZE")
+        .derive_default(true)
+        // Implemented in ffi.rs to start from the default log level
+        .no_default("rats_tls_conf_t")
+        .generate()
+        .expect("Failed to generate bindings of librats_tls")
+        .write_to_file(out_dir.join("bindings.rs"))
+        .expect("Failed to write bindings of librats_tls");
+}
+
+#[cfg(not(feature = "bindgen"))]
+fn generate_bindings(_include_dir: &Path, _header: &Path, _out_dir: &Path) {
+    unreachable!()
+}
+
+/// Compiles and runs a probe printing the size and alignment of the shared
+/// structs as seen by C, and returns assertions that fail the build if the
+/// Rust definitions differ.
+fn check_layout(include_dir: &Path, header: &Path, out_dir: &Path) -> String {
+    let mut probe =
+        String::from("#include <stdio.h>\n#include <rats-tls/api.h>\n\nint main(void)\n{\n");
+    for name in SHARED_STRUCTS {
+        probe += &format!(
+            "\tprintf(\"%zu %zu\\n\", sizeof({0}), _Alignof({0}));\n",
+            name
+        );
+    }
+    probe += "\treturn 0;\n}\n";
+    let source = out_dir.join("layout_probe.c");
+    let binary = out_dir.join("layout_probe");
+    fs::write(&source, probe).unwrap();
+
+    let compiler = cc::Build::new().cargo_metadata(false).get_compiler();
+    let output = compiler
+        .to_command()
+        .arg(format!("-I{}", include_dir.display()))
+        .arg(&source)
+        .arg("-o")
+        .arg(&binary)
+        .output()
+        .expect("Failed to run the C compiler");
+    if !output.status.success() {
+        panic!(
+            "Failed to compile the layout probe against {}:\n{}",
+            header.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let output = Command::new(&binary)
+        .output()
+        .expect("Failed to run the layout probe");
+    let output = String::from_utf8(output.stdout).unwrap();
+
+    let mut checks = String::new();
+    for (name, line) in SHARED_STRUCTS.iter().zip(output.lines()) {
+        let (size, align) = line.split_once(' ').unwrap();
+        checks += &format!(
+            "const _: () = assert!(\n    ::std::mem::size_of::<{0}>() == {1} && ::std::mem::align_of::<{0}>() == {2},\n    \"{0} does not match {3}, regenerate the bindings with the `bindgen` feature\"\n);\n",
+            name,
+            size,
+            align,
+            header.display()
+        );
+    }
+    checks
+}
@@ -0,0 +1,155 @@
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use tokio::io::DuplexStream;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::policy::AppraisalPolicy;
+use crate::RatsTls;
+
+#[cfg(feature = "mock")]
+use crate::mock::Credentials;
+
+/// librats_tls generates the certificate and evidence in rats_tls_init(), its
+/// API takes no certificate from outside, and a handle carries the TLS session
+/// of the one connection it negotiated, so with the native backend there is
+/// nothing but the config that sessions can share.
+struct Shared {
+    config: Config,
+    #[cfg(feature = "mock")]
+    credentials: Credentials,
+}
+
+impl Shared {
+    fn with_policy(&self, policy: Arc<AppraisalPolicy>) -> Shared {
+        Shared {
+            config: Config {
+                policy: Some(policy),
+                ..self.config.clone()
+            },
+            #[cfg(feature = "mock")]
+            credentials: self.credentials.clone(),
+        }
+    }
+
+    fn new(config: Config) -> Result<Shared, Error> {
+        config.validate()?;
+        Ok(Shared {
+            #[cfg(feature = "mock")]
+            credentials: Credentials::new(&config)?,
+            config,
+        })
+    }
+
+    async fn negotiate<S>(&self, stream: S) -> std::io::Result<(Arc<RatsTls>, DuplexStream)>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        let tls = RatsTls::from_config(&self.config)?;
+        #[cfg(feature = "mock")]
+        let tls = {
+            let mut tls = tls;
+            tls.set_credentials(&self.credentials);
+            tls
+        };
+        tls.negotiate_shared(stream).await
+    }
+}
+
+/// Negotiates rats-tls on accepted connections, reusing one configuration for
+/// all of them. Only the mock backend also reuses one certificate and
+/// evidence; with librats_tls every connection still gets its own handle, and
+/// with it a fresh certificate and quote.
+///
+/// The role in the handshake is taken from the config, and is independent of
+/// which side accepted the connection.
+#[derive(Clone)]
+pub struct RatsTlsAcceptor {
+    inner: Arc<Shared>,
+}
+
+impl RatsTlsAcceptor {
+    pub fn new(config: Config) -> Result<RatsTlsAcceptor, Error> {
+        Ok(RatsTlsAcceptor {
+            inner: Arc::new(Shared::new(config)?),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    /// The same acceptor, with `policy` for the sessions negotiated from now
+    /// on, e.g. once it has been reloaded from disk
+    pub fn with_policy(&self, policy: Arc<AppraisalPolicy>) -> RatsTlsAcceptor {
+        RatsTlsAcceptor {
+            inner: Arc::new(self.inner.with_policy(policy)),
+        }
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> std::io::Result<DuplexStream>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        self.accept_shared(stream)
+            .await
+            .map(|(_, duplex_stream)| duplex_stream)
+    }
+
+    /// Same as `accept()`, but also hands back the session, see
+    /// `RatsTls::negotiate_shared()`.
+    pub async fn accept_shared<S>(&self, stream: S) -> std::io::Result<(Arc<RatsTls>, DuplexStream)>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        self.inner.negotiate(stream).await
+    }
+}
+
+/// Negotiates rats-tls on outgoing connections, the counterpart of
+/// `RatsTlsAcceptor`.
+#[derive(Clone)]
+pub struct RatsTlsConnector {
+    inner: Arc<Shared>,
+}
+
+impl RatsTlsConnector {
+    pub fn new(config: Config) -> Result<RatsTlsConnector, Error> {
+        Ok(RatsTlsConnector {
+            inner: Arc::new(Shared::new(config)?),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    /// The same connector, see `RatsTlsAcceptor::with_policy()`
+    pub fn with_policy(&self, policy: Arc<AppraisalPolicy>) -> RatsTlsConnector {
+        RatsTlsConnector {
+            inner: Arc::new(self.inner.with_policy(policy)),
+        }
+    }
+
+    pub async fn connect<S>(&self, stream: S) -> std::io::Result<DuplexStream>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        self.connect_shared(stream)
+            .await
+            .map(|(_, duplex_stream)| duplex_stream)
+    }
+
+    /// Same as `connect()`, but also hands back the session, see
+    /// `RatsTls::negotiate_shared()`.
+    pub async fn connect_shared<S>(
+        &self,
+        stream: S,
+    ) -> std::io::Result<(Arc<RatsTls>, DuplexStream)>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        self.inner.negotiate(stream).await
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::policy::{parse_hex, AppraisalPolicy};
+
+/// Role of one side in the rats-tls handshake. It is independent of which side
+/// initiated the underlying connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Role::Client),
+            "server" => Ok(Role::Server),
+            _ => Err(format!("invalid role '{}', expect 'client' or 'server'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Client => write!(f, "client"),
+            Role::Server => write!(f, "server"),
+        }
+    }
+}
+
+/// Largest amount of plaintext a TLS record can carry
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024;
+pub const DEFAULT_BUFFER_SIZE: usize = MAX_BUFFER_SIZE;
+
+/// Where `sgx_ecdsa` quotes are verified: by the Quote Verification Library
+/// linked into the verifier, or by the Quote Verification Enclave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteVerification {
+    Qvl,
+    Qel,
+}
+
+impl Default for QuoteVerification {
+    fn default() -> Self {
+        QuoteVerification::Qvl
+    }
+}
+
+impl FromStr for QuoteVerification {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qvl" => Ok(QuoteVerification::Qvl),
+            "qel" => Ok(QuoteVerification::Qel),
+            _ => Err(format!(
+                "invalid quote verification '{}', expect 'qvl' or 'qel'",
+                s
+            )),
+        }
+    }
+}
+
+/// Options for `sgx_ecdsa` quotes. Without them librats_tls uses its defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgxEcdsaQuote {
+    /// Type of the certification data in the quote, 1 to 7 as defined by the
+    /// SGX ECDSA quote format, e.g. 5 for the PCK certificate chain
+    pub cert_type: u8,
+    pub verification: QuoteVerification,
+}
+
+impl Default for SgxEcdsaQuote {
+    fn default() -> Self {
+        SgxEcdsaQuote {
+            cert_type: 5,
+            verification: QuoteVerification::default(),
+        }
+    }
+}
+
+/// Service Provider ID registered with Intel for EPID attestation, written as
+/// 32 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spid(pub [u8; 16]);
+
+impl FromStr for Spid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s)?
+            .try_into()
+            .map(Spid)
+            .map_err(|_| format!("expect 32 hex digits, got '{}'", s.trim_start_matches("0x")))
+    }
+}
+
+/// Options for `sgx_epid` quotes, which cannot be produced without them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgxEpidQuote {
+    pub spid: Spid,
+    pub linkable: bool,
+}
+
+/// Everything needed to set up one side of a rats-tls connection.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub role: Role,
+    pub enclave_id: u64,
+    pub tls_type: String,
+    pub crypto: String,
+    pub attester: String,
+    pub verifier: String,
+    pub mutual: bool,
+    pub policy: Option<Arc<AppraisalPolicy>>,
+    /// Application data to bind into the evidence presented to the peer. Only
+    /// the mock backend can, `validate()` refuses it with librats_tls.
+    pub user_data: Option<Vec<u8>>,
+    pub sgx_ecdsa: Option<SgxEcdsaQuote>,
+    pub sgx_epid: Option<SgxEpidQuote>,
+    /// Size of the buffers between the socket and the stream returned by
+    /// `negotiate_async()`, which bounds the data passed to librats_tls in
+    /// one call and so the size of the TLS records sent
+    pub buffer_size: usize,
+    /// Longest the peer may take to answer during negotiation. It applies to
+    /// each read and write on the socket rather than to the whole handshake,
+    /// and fails the handshake with a peer that stops responding instead of
+    /// leaving it blocked forever.
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Config {
+    /// The defaults let a server running in an SGX enclave prove itself to a
+    /// client running outside of any enclave.
+    pub fn new(role: Role) -> Config {
+        let (attester, verifier) = match role {
+            Role::Server => ("sgx_ecdsa", "nullverifier"),
+            Role::Client => ("nullattester", "sgx_ecdsa"),
+        };
+        Config {
+            role,
+            enclave_id: 0,
+            tls_type: "openssl".to_owned(),
+            crypto: "openssl".to_owned(),
+            attester: attester.to_owned(),
+            verifier: verifier.to_owned(),
+            mutual: false,
+            policy: None,
+            user_data: None,
+            sgx_ecdsa: None,
+            sgx_epid: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            handshake_timeout: None,
+        }
+    }
+
+    /// The defaults let two SGX enclaves attest each other, with each side
+    /// appraising the evidence of the peer against its own policy.
+    pub fn new_mutual(role: Role) -> Config {
+        Config {
+            attester: "sgx_ecdsa".to_owned(),
+            verifier: "sgx_ecdsa".to_owned(),
+            mutual: true,
+            ..Config::new(role)
+        }
+    }
+
+    /// `new()` or `new_mutual()` with the plugins given on the command line of
+    /// ENTA or ENTG, keeping the default attester and verifier when left out.
+    pub fn with_plugins(
+        role: Role,
+        mutual: bool,
+        tls_type: String,
+        crypto: String,
+        attester: Option<String>,
+        verifier: Option<String>,
+    ) -> Config {
+        let config = if mutual {
+            Config::new_mutual(role)
+        } else {
+            Config::new(role)
+        };
+        Config {
+            tls_type,
+            crypto,
+            attester: attester.unwrap_or(config.attester),
+            verifier: verifier.unwrap_or(config.verifier),
+            ..config
+        }
+    }
+
+    /// Quote options left unset are up to librats_tls. They are validated along
+    /// with the rest of the config.
+    pub fn set_quote_options(
+        &mut self,
+        verification: Option<QuoteVerification>,
+        cert_type: Option<u8>,
+        spid: Option<Spid>,
+        linkable: bool,
+    ) {
+        if verification.is_some() || cert_type.is_some() {
+            let default = SgxEcdsaQuote::default();
+            self.sgx_ecdsa = Some(SgxEcdsaQuote {
+                cert_type: cert_type.unwrap_or(default.cert_type),
+                verification: verification.unwrap_or(default.verification),
+            });
+        }
+        self.sgx_epid = spid.map(|spid| SgxEpidQuote { spid, linkable });
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.mutual && (self.attester == "nullattester" || self.verifier == "nullverifier") {
+            return Err(Error::Config(format!(
+                "mutual attestation needs a real attester and verifier, got '{}' and '{}'",
+                self.attester, self.verifier
+            )));
+        }
+
+        // librats_tls has no API to put user data into the evidence, so the
+        // config is refused here rather than when negotiating
+        if cfg!(not(feature = "mock")) && self.user_data.is_some() {
+            return Err(Error::Config(
+                "user data in the evidence is not supported by librats_tls".to_owned(),
+            ));
+        }
+        if let Some(policy) = &self.policy {
+            policy.check_supported().map_err(Error::Config)?;
+        }
+
+        validate_buffer_size(self.buffer_size)?;
+        if self.handshake_timeout == Some(Duration::ZERO) {
+            return Err(Error::Config(
+                "handshake timeout must not be zero".to_owned(),
+            ));
+        }
+
+        let uses =
+            |prefix: &str| self.attester.starts_with(prefix) || self.verifier.starts_with(prefix);
+        if let Some(ecdsa) = &self.sgx_ecdsa {
+            if !uses("sgx_ecdsa") {
+                return Err(Error::Config(
+                    "SGX ECDSA quote options need an sgx_ecdsa attester or verifier".to_owned(),
+                ));
+            }
+            if !(1..=7).contains(&ecdsa.cert_type) {
+                return Err(Error::Config(format!(
+                    "invalid SGX ECDSA certification data type {}, expect 1 to 7",
+                    ecdsa.cert_type
+                )));
+            }
+        }
+        if let Some(epid) = &self.sgx_epid {
+            if self.attester != "sgx_epid" {
+                return Err(Error::Config(
+                    "SGX EPID quote options need the sgx_epid attester".to_owned(),
+                ));
+            }
+            if epid.spid.0 == [0; 16] {
+                return Err(Error::Config("SPID must not be all zeros".to_owned()));
+            }
+        } else if self.attester == "sgx_epid" {
+            return Err(Error::Config(
+                "the sgx_epid attester needs an SPID".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn validate_buffer_size(size: usize) -> Result<(), Error> {
+    if size == 0 || size > MAX_BUFFER_SIZE {
+        return Err(Error::Config(format!(
+            "buffer size must be between 1 and {} bytes, got {}",
+            MAX_BUFFER_SIZE, size
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_mutual() {
+        assert!(Config::new(Role::Server).validate().is_ok());
+        assert!(Config::new_mutual(Role::Client).validate().is_ok());
+
+        let config = Config {
+            mutual: true,
+            ..Config::new(Role::Server)
+        };
+        assert!(matches!(config.validate(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn config_from_options() {
+        let mut config = Config::with_plugins(
+            Role::Client,
+            true,
+            "openssl".to_owned(),
+            "openssl".to_owned(),
+            None,
+            Some("sgx_la".to_owned()),
+        );
+        assert_eq!(config.attester, "sgx_ecdsa");
+        assert_eq!(config.verifier, "sgx_la");
+        assert!(config.mutual);
+
+        config.set_quote_options(None, None, None, true);
+        assert_eq!(config.sgx_ecdsa, None);
+        assert_eq!(config.sgx_epid, None);
+        config.set_quote_options(None, Some(3), Some(Spid([1; 16])), true);
+        let ecdsa = config.sgx_ecdsa.unwrap();
+        assert_eq!(
+            (ecdsa.cert_type, ecdsa.verification),
+            (3, QuoteVerification::Qvl)
+        );
+        assert!(config.sgx_epid.unwrap().linkable);
+    }
+
+    #[test]
+    fn validate_user_data() {
+        let config = Config {
+            user_data: Some(b"gateway-1".to_vec()),
+            ..Config::new(Role::Server)
+        };
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "mock"));
+    }
+
+    #[test]
+    fn validate_buffer_size() {
+        for (buffer_size, valid) in [
+            (1024, true),
+            (MAX_BUFFER_SIZE, true),
+            (0, false),
+            (MAX_BUFFER_SIZE + 1, false),
+        ] {
+            let config = Config {
+                buffer_size,
+                ..Config::new(Role::Client)
+            };
+            assert_eq!(config.validate().is_ok(), valid, "{}", buffer_size);
+        }
+    }
+
+    #[test]
+    fn validate_handshake_timeout() {
+        let config = |handshake_timeout| Config {
+            handshake_timeout,
+            ..Config::new(Role::Server)
+        };
+        assert!(config(Some(Duration::from_secs(30))).validate().is_ok());
+        assert!(config(Some(Duration::ZERO)).validate().is_err());
+    }
+
+    #[test]
+    fn validate_quote_options() {
+        let ecdsa = |cert_type| Config {
+            sgx_ecdsa: Some(SgxEcdsaQuote {
+                cert_type,
+                verification: QuoteVerification::Qel,
+            }),
+            ..Config::new(Role::Client)
+        };
+        assert!(ecdsa(5).validate().is_ok());
+        assert!(ecdsa(0).validate().is_err());
+        assert!(ecdsa(8).validate().is_err());
+        let config = Config {
+            verifier: "nullverifier".to_owned(),
+            ..ecdsa(5)
+        };
+        assert!(config.validate().is_err());
+
+        let epid = |spid: &str| Config {
+            attester: "sgx_epid".to_owned(),
+            sgx_epid: Some(SgxEpidQuote {
+                spid: spid.parse().unwrap(),
+                linkable: true,
+            }),
+            ..Config::new(Role::Server)
+        };
+        assert!(epid("0x00112233445566778899aabbccddeeff")
+            .validate()
+            .is_ok());
+        assert!(epid("00000000000000000000000000000000").validate().is_err());
+        let config = Config {
+            sgx_epid: None,
+            ..epid("00112233445566778899aabbccddeeff")
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            attester: "sgx_ecdsa".to_owned(),
+            ..epid("00112233445566778899aabbccddeeff")
+        };
+        assert!(config.validate().is_err());
+
+        assert!("0011".parse::<Spid>().is_err());
+        assert_eq!("qel".parse(), Ok(QuoteVerification::Qel));
+        assert!("QVL".parse::<QuoteVerification>().is_err());
+    }
+}
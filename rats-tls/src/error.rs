@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::ffi::rats_tls_err_t;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error code returned by librats_tls
+    Native(rats_tls_err_t),
+    /// The evidence of the peer was rejected by the appraisal policy
+    Rejected(String),
+    /// The appraisal policy could not be loaded
+    Policy(String),
+    /// The configuration was refused before reaching librats_tls
+    Config(String),
+    /// librats_tls could not be loaded at runtime
+    Library(String),
+    /// TLS failure reported by the mock backend
+    Tls(String),
+    /// The operation is not offered by the librats_tls API
+    Unsupported(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Native(err) => write!(f, "rats-tls error code: {:#x}", err),
+            Error::Rejected(reason) => write!(f, "peer evidence rejected by policy: {}", reason),
+            Error::Policy(reason) => write!(f, "invalid appraisal policy: {}", reason),
+            Error::Config(reason) => write!(f, "invalid rats-tls configuration: {}", reason),
+            Error::Library(reason) => write!(f, "failed to load librats_tls: {}", reason),
+            Error::Tls(reason) => write!(f, "tls error: {}", reason),
+            Error::Unsupported(what) => write!(f, "{} is not supported by librats_tls", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        std::io::Error::new(std::io::ErrorKind::Other, err)
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Deserialize;
+
+#[cfg(not(feature = "mock"))]
+use crate::ffi::*;
+
+/// Bit of the SGX attributes flags that is set for debug enclaves.
+const SGX_FLAGS_DEBUG: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvidenceType {
+    SgxEcdsa,
+    Tdx,
+    Unknown(u32),
+}
+
+/// TCB level status as reported by the quote verification library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TcbStatus {
+    UpToDate,
+    #[serde(rename = "SWHardeningNeeded")]
+    SwHardeningNeeded,
+    ConfigurationNeeded,
+    #[serde(rename = "ConfigurationAndSWHardeningNeeded")]
+    ConfigurationAndSwHardeningNeeded,
+    OutOfDate,
+    OutOfDateConfigurationNeeded,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SgxEvidence {
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub product_id: u32,
+    pub security_version: u32,
+    pub attributes: [u8; 16],
+}
+
+impl SgxEvidence {
+    pub fn is_debug(&self) -> bool {
+        self.attributes[0] & SGX_FLAGS_DEBUG != 0
+    }
+}
+
+/// Evidence of the peer, copied out of the `rtls_evidence_t` that librats_tls
+/// hands to the verification callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evidence {
+    pub evidence_type: EvidenceType,
+    pub sgx: Option<SgxEvidence>,
+    /// librats_tls does not pass the quote verification result to the
+    /// callback, so this is only known for backends that report it, and
+    /// policies checking it are refused otherwise.
+    pub tcb_status: Option<TcbStatus>,
+    /// Application data whose hash is bound into the evidence together with
+    /// the TLS public key, e.g. a gateway id or a configuration hash.
+    pub user_data: Vec<u8>,
+    /// Application data carried along with the evidence but not covered by it,
+    /// so it must not be trusted.
+    pub unhashed: Vec<u8>,
+}
+
+#[cfg(not(feature = "mock"))]
+impl Evidence {
+    /// # Safety
+    ///
+    /// `ev` must point to a valid `rtls_evidence_t` whose pointer fields are
+    /// either null or point to buffers of the sizes defined by SGX.
+    pub(crate) unsafe fn from_ffi(ev: &rtls_evidence_t) -> Evidence {
+        let evidence_type = match ev.type_ {
+            t if t == enclave_evidence_type_t_SGX_ECDSA => EvidenceType::SgxEcdsa,
+            t if t == enclave_evidence_type_t_TDX => EvidenceType::Tdx,
+            other => EvidenceType::Unknown(other),
+        };
+
+        let sgx = if evidence_type == EvidenceType::SgxEcdsa {
+            let sgx = &ev.__bindgen_anon_1.sgx;
+            match (
+                copy_array::<32>(sgx.mr_enclave),
+                copy_array::<32>(sgx.mr_signer),
+                copy_array::<16>(sgx.attributes),
+            ) {
+                (Some(mr_enclave), Some(mr_signer), Some(attributes)) => Some(SgxEvidence {
+                    mr_enclave,
+                    mr_signer,
+                    product_id: sgx.product_id,
+                    security_version: sgx.security_version,
+                    attributes,
+                }),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Evidence {
+            evidence_type,
+            sgx,
+            tcb_status: None,
+            user_data: copy_bytes(ev.ehd.user_data as *const u8, ev.ehd.user_data_size),
+            unhashed: copy_bytes(ev.ehd.unhashed as *const u8, ev.ehd.unhashed_size),
+        }
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe fn copy_array<const N: usize>(ptr: *const u8) -> Option<[u8; N]> {
+    if ptr.is_null() {
+        None
+    } else {
+        std::slice::from_raw_parts(ptr, N).try_into().ok()
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe fn copy_bytes(ptr: *const u8, size: ::std::os::raw::c_int) -> Vec<u8> {
+    if ptr.is_null() || size <= 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(ptr, size as usize).to_vec()
+    }
+}
@@ -4,136 +4,124 @@
  */
 use std::io::{Read, Write};
 use std::net::Shutdown;
-use std::ops::{Deref, DerefMut};
-use std::os::unix::io::RawFd;
-use std::os::unix::prelude::AsRawFd;
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
-use std::ptr::NonNull;
 use std::sync::Arc;
+use std::time::Duration;
 
-use foreign_types::{ForeignType, ForeignTypeRef, Opaque};
+use log::debug;
 use pin_project::{pin_project, pinned_drop};
+use socket2::SockRef;
 use tokio::io::{AsyncWrite, AsyncWriteExt, DuplexStream};
-use tokio::net::TcpStream;
 use tokio_util::io::SyncIoBridge;
 
+mod acceptor;
+mod config;
+mod error;
+mod evidence;
 mod ffi;
-use ffi::*;
+#[cfg(feature = "dlopen")]
+// Unit tests of the native backend run against a fake librats_tls instead
+#[cfg_attr(test, allow(dead_code))]
+mod loader;
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(not(feature = "mock"))]
+mod native;
+mod policy;
+
+pub use acceptor::{RatsTlsAcceptor, RatsTlsConnector};
+pub use config::{
+    Config, QuoteVerification, Role, SgxEcdsaQuote, SgxEpidQuote, Spid, DEFAULT_BUFFER_SIZE,
+    MAX_BUFFER_SIZE,
+};
+pub use error::Error;
+pub use evidence::{Evidence, EvidenceType, SgxEvidence, TcbStatus};
+#[cfg(feature = "dlopen")]
+pub use loader::{load_library, DEFAULT_LIBRARY_PATH};
+#[cfg(feature = "mock")]
+pub use mock::RatsTls;
+#[cfg(not(feature = "mock"))]
+pub use native::{RatsTls, RatsTlsRef};
+pub use policy::{AppraisalPolicy, Measurement};
 
-pub struct RatsTlsRef(Opaque);
-
-unsafe impl ForeignTypeRef for RatsTlsRef {
-    type CType = rats_tls_handle;
-}
-
-#[derive(Clone)]
-pub struct RatsTls(NonNull<rats_tls_handle>);
-
-unsafe impl Send for RatsTlsRef {}
-unsafe impl Sync for RatsTlsRef {}
-unsafe impl Send for RatsTls {}
-unsafe impl Sync for RatsTls {}
-
-unsafe impl ForeignType for RatsTls {
-    type CType = rats_tls_handle;
-    type Ref = RatsTlsRef;
-
-    unsafe fn from_ptr(ptr: *mut rats_tls_handle) -> RatsTls {
-        RatsTls(NonNull::new_unchecked(ptr))
+impl RatsTls {
+    pub fn from_config(config: &Config) -> Result<RatsTls, Error> {
+        config.validate()?;
+        let mut tls = RatsTls::with_config(config)?;
+        tls.policy = config.policy.clone();
+        tls.buffer_size = config.buffer_size;
+        tls.handshake_timeout = config.handshake_timeout;
+        if let Some(user_data) = &config.user_data {
+            tls.set_user_data(user_data.clone())?;
+        }
+        Ok(tls)
     }
 
-    fn as_ptr(&self) -> *mut rats_tls_handle {
-        self.0.as_ptr()
+    /// Set the policy that the evidence of the peer is appraised against during
+    /// negotiation. Without a policy, any evidence accepted by the verifier passes.
+    pub fn set_policy(&mut self, policy: AppraisalPolicy) {
+        self.policy = Some(Arc::new(policy));
     }
 
-    fn into_ptr(self) -> *mut rats_tls_handle {
-        let inner = self.as_ptr();
-        ::core::mem::forget(self);
-        inner
+    /// Set the size of the buffers used by `negotiate_async()`, see
+    /// `Config::buffer_size`.
+    pub fn set_buffer_size(&mut self, size: usize) -> Result<(), Error> {
+        config::validate_buffer_size(size)?;
+        self.buffer_size = size;
+        Ok(())
     }
-}
 
-impl Drop for RatsTls {
-    fn drop(&mut self) {
-        unsafe {
-            rats_tls_cleanup(self.as_ptr());
-        }
+    /// Set the timeout of negotiation, see `Config::handshake_timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
     }
-}
-
-impl Deref for RatsTls {
-    type Target = RatsTlsRef;
 
-    fn deref(&self) -> &RatsTlsRef {
-        unsafe { RatsTlsRef::from_ptr(self.as_ptr()) }
+    /// Appraise the evidence the peer presented during negotiation again, against
+    /// a policy reloaded since, e.g. after a TCB recovery raised the minimum ISV
+    /// SVN. Neither backend can renegotiate an established session, so this does
+    /// not obtain fresh evidence from the peer; a new session is needed for that.
+    pub fn reappraise_handshake_evidence(&self, policy: &AppraisalPolicy) -> Result<(), Error> {
+        let evidence = self
+            .peer_evidence()
+            .ok_or_else(|| Error::Rejected("the peer presented no evidence".to_owned()))?;
+        policy.appraise(&evidence).map_err(Error::Rejected)
     }
-}
 
-impl DerefMut for RatsTls {
-    fn deref_mut(&mut self) -> &mut RatsTlsRef {
-        unsafe { RatsTlsRef::from_ptr_mut(self.as_ptr()) }
+    /// Negotiate over any connected stream socket, e.g. a `tokio::net::TcpStream`
+    /// or `UnixStream`, and carry the decrypted data over the returned stream.
+    /// The socket is switched to blocking mode and used by librats_tls from
+    /// blocking tasks, so it must not be read or written by anyone else.
+    pub async fn negotiate_async<S>(self, stream: S) -> std::io::Result<DuplexStream>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        self.negotiate_shared(stream)
+            .await
+            .map(|(_, duplex_stream)| duplex_stream)
     }
-}
-
-impl RatsTls {
-    pub fn new(
-        server: bool,
-        enclave_id: u64,
-        tls_type: Option<&str>,
-        crypto: Option<&str>,
-        attester: Option<&str>,
-        verifier: Option<&str>,
-        mutual: bool,
-    ) -> Result<RatsTls, rats_tls_err_t> {
-        let mut conf: rats_tls_conf_t = Default::default();
-        conf.api_version = RATS_TLS_API_VERSION_DEFAULT;
-        conf.log_level = RATS_TLS_LOG_LEVEL_DEBUG;
-        if let Some(tls_type) = tls_type {
-            conf.tls_type[..tls_type.len()].copy_from_slice(tls_type.as_bytes());
-        }
-        if let Some(crypto) = crypto {
-            conf.crypto_type[..crypto.len()].copy_from_slice(crypto.as_bytes());
-        }
-        if let Some(attester) = attester {
-            conf.attester_type[..attester.len()].copy_from_slice(attester.as_bytes());
-        }
-        if let Some(verifier) = verifier {
-            conf.verifier_type[..verifier.len()].copy_from_slice(verifier.as_bytes());
-        }
-        conf.cert_algo = RATS_TLS_CERT_ALGO_DEFAULT;
-        conf.enclave_id = enclave_id;
-        if mutual {
-            conf.flags |= RATS_TLS_CONF_FLAGS_MUTUAL;
-        }
-        if server {
-            conf.flags |= RATS_TLS_CONF_FLAGS_SERVER;
-        }
 
-        let mut handle: rats_tls_handle = unsafe { std::mem::zeroed() };
-        let mut tls: *mut rats_tls_handle = &mut handle;
-        let err = unsafe { rats_tls_init(&conf, &mut tls) };
-        if err != RATS_TLS_ERR_NONE {
-            // error!("rats_tls_init() failed");
-            return Err(err);
+    /// Same as `negotiate_async()`, but also hands back the session so that it
+    /// can still be queried after negotiation, e.g. for `peer_evidence()`.
+    pub async fn negotiate_shared<S>(
+        self,
+        stream: S,
+    ) -> std::io::Result<(Arc<RatsTls>, DuplexStream)>
+    where
+        S: AsRawFd + Send + Sync + 'static,
+    {
+        // librats_tls does blocking I/O on the socket
+        let sock = SockRef::from(&stream);
+        sock.set_nonblocking(false)?;
+        let handshake_timeout = self.handshake_timeout;
+        if handshake_timeout.is_some() {
+            sock.set_read_timeout(handshake_timeout)?;
+            sock.set_write_timeout(handshake_timeout)?;
         }
 
-        let err = unsafe { rats_tls_set_verification_callback(&mut tls, None) };
-        if err == RATS_TLS_ERR_NONE {
-            Ok(unsafe { RatsTls::from_ptr(tls) })
-        } else {
-            Err(err)
-        }
-    }
-
-    pub async fn negotiate_async(self, stream: TcpStream) -> std::io::Result<DuplexStream> {
-        // Convert to std::net::TcpStream, then set socket to non-block
-        let std_tcp_stream = stream.into_std().and_then(|std_tcp_stream| {
-            std_tcp_stream
-                .set_nonblocking(false)
-                .and(Ok(std_tcp_stream))
-        })?;
-
-        let rats_tls_session = Arc::new((self, std_tcp_stream));
+        let buffer_size = self.buffer_size;
+        let rats_tls = Arc::new(self);
+        let rats_tls_session = Arc::new((rats_tls.clone(), stream));
 
         {
             let rats_tls_session = rats_tls_session.clone();
@@ -141,18 +129,22 @@ impl RatsTls {
                 rats_tls_session
                     .0
                     .negotiate(rats_tls_session.1.as_raw_fd())
-                    .map_err(|err| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("rats-tls error code: {}", err),
-                        )
-                    })
+                    .map_err(std::io::Error::from)
             })
             .await??;
         }
+        // The socket is then waited on for as long as the link is up
+        if handshake_timeout.is_some() {
+            let sock = SockRef::from(&rats_tls_session.1);
+            sock.set_read_timeout(None)?;
+            sock.set_write_timeout(None)?;
+        }
 
         // TODO: Introduce async mode for librats_tls.so to replace spawn_blocking
-        let (s1, s2) = tokio::io::duplex(1024);
+        // Whatever has been written to the stream when the writer task gets to
+        // read it is transmitted at once, so bursts of small writes, e.g. one
+        // per packet, are coalesced into records of up to `buffer_size` bytes.
+        let (s1, s2) = tokio::io::duplex(buffer_size);
 
         let (rh, wh) = tokio::io::split(s1);
 
@@ -168,7 +160,7 @@ impl RatsTls {
             // Writer task of rats-tls
             tokio::task::spawn_blocking(move || {
                 let mut rh = SyncIoBridge::new(rh);
-                let mut buf = vec![0; 1024];
+                let mut buf = vec![0; buffer_size];
                 'outer: while let Ok(r_len) = rh.read(&mut buf) {
                     if r_len == 0 {
                         break; // no more data to read
@@ -177,22 +169,21 @@ impl RatsTls {
                     while w_off < r_len {
                         match rats_tls_session.0.transmit(&buf[w_off..r_len]) {
                             Ok(w_len) => w_off += w_len,
-                            Err(_err) => {
-                                // TODO: Better way to show error message
-                                // error!("Failed in rats-tls teansmit(): error {}", err);
+                            Err(err) => {
+                                debug!("Failed in rats-tls transmit(): {}", err);
                                 break 'outer;
                             }
                         };
                     }
                 }
-                let _ = rats_tls_session.1.shutdown(Shutdown::Write);
+                let _ = SockRef::from(&rats_tls_session.1).shutdown(Shutdown::Write);
             });
         }
         {
             // Reader task of rats-tls
             tokio::task::spawn_blocking(move || {
                 let mut wh = SyncIoBridge::new(ShutdownOnDrop::new(wh));
-                let mut buf = vec![0; 1024];
+                let mut buf = vec![0; buffer_size];
                 loop {
                     match rats_tls_session.0.receive(&mut buf) {
                         Ok(r_len) => {
@@ -200,14 +191,14 @@ impl RatsTls {
                                 break;
                             }
                         }
-                        Err(_err) => {
-                            // TODO: Better way to show error message
-                            // error!("Failed in rats-tls receive(): error {}", err);
+                        Err(err) => {
+                            // Also how a closed connection ends up here
+                            debug!("Failed in rats-tls receive(): {}", err);
                             break;
                         }
                     };
                 }
-                let _ = rats_tls_session.1.shutdown(Shutdown::Read);
+                let _ = SockRef::from(&rats_tls_session.1).shutdown(Shutdown::Read);
                 // Once we are unable to receive more data from rats-tls, we need to shutdown the writer
                 // of DuplexStream. However, SyncIoBridge does not expose shutdown() function for its
                 // inner AsyncWrite instance currently, at the time I wrote this. So I use ShutdownOnDrop
@@ -217,48 +208,7 @@ impl RatsTls {
             });
         }
 
-        Ok(s2)
-    }
-
-    pub fn negotiate(&self, fd: RawFd) -> Result<(), rats_tls_err_t> {
-        let err = unsafe { rats_tls_negotiate(self.as_ptr(), fd) };
-        if err == RATS_TLS_ERR_NONE {
-            Ok(())
-        } else {
-            Err(err)
-        }
-    }
-
-    pub fn receive(&self, buf: &mut [u8]) -> Result<usize, rats_tls_err_t> {
-        let mut len: size_t = buf.len() as size_t;
-        let err = unsafe {
-            rats_tls_receive(
-                self.as_ptr(),
-                buf.as_mut_ptr() as *mut ::std::os::raw::c_void,
-                &mut len,
-            )
-        };
-        if err == RATS_TLS_ERR_NONE {
-            Ok(len as usize)
-        } else {
-            Err(err)
-        }
-    }
-
-    pub fn transmit(&self, buf: &[u8]) -> Result<usize, rats_tls_err_t> {
-        let mut len: size_t = buf.len() as size_t;
-        let err = unsafe {
-            rats_tls_transmit(
-                self.as_ptr(),
-                buf.as_ptr() as *const ::std::os::raw::c_void,
-                &mut len,
-            )
-        };
-        if err == RATS_TLS_ERR_NONE {
-            Ok(len as usize)
-        } else {
-            Err(err)
-        }
+        Ok((rats_tls, s2))
     }
 }
 
@@ -0,0 +1,203 @@
+//! Load librats_tls at runtime instead of linking to it, so that the same
+//! binary can pick up the host or occlum build of the library.
+use std::path::{Path, PathBuf};
+
+use libloading::Library;
+use once_cell::sync::OnceCell;
+
+use crate::error::Error;
+use crate::ffi::*;
+
+/// Where `make rats-tls-host` installs the library, and where `occlum.yaml`
+/// copies it to inside the occlum image.
+pub const DEFAULT_LIBRARY_PATH: &str = "/usr/local/lib/rats-tls/librats_tls.so.0";
+
+/// Major version of librats_tls the FFI bindings were generated for.
+const SUPPORTED_MAJOR_VERSION: &str = "0";
+
+pub(crate) struct RatsTlsLibrary {
+    pub path: PathBuf,
+    pub init:
+        unsafe extern "C" fn(*const rats_tls_conf_t, *mut *mut rats_tls_handle) -> rats_tls_err_t,
+    pub set_verification_callback:
+        unsafe extern "C" fn(*mut *mut rats_tls_handle, rats_tls_callback_t) -> rats_tls_err_t,
+    pub negotiate:
+        unsafe extern "C" fn(*const rats_tls_handle, ::std::os::raw::c_int) -> rats_tls_err_t,
+    pub receive: unsafe extern "C" fn(
+        *const rats_tls_handle,
+        *mut ::std::os::raw::c_void,
+        *mut size_t,
+    ) -> rats_tls_err_t,
+    pub transmit: unsafe extern "C" fn(
+        *const rats_tls_handle,
+        *const ::std::os::raw::c_void,
+        *mut size_t,
+    ) -> rats_tls_err_t,
+    pub cleanup: unsafe extern "C" fn(*mut rats_tls_handle) -> rats_tls_err_t,
+    // Keep the library mapped as long as the function pointers above are used.
+    _library: Library,
+}
+
+static LIBRARY: OnceCell<RatsTlsLibrary> = OnceCell::new();
+
+/// Load librats_tls from `path`. This must be called before the first
+/// `RatsTls` is created, otherwise `DEFAULT_LIBRARY_PATH` is loaded.
+pub fn load_library(path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let library = LIBRARY.get_or_try_init(|| open(path))?;
+    if library.path != path {
+        return Err(Error::Library(format!(
+            "{} is already loaded",
+            library.path.display()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn library() -> Result<&'static RatsTlsLibrary, Error> {
+    LIBRARY.get_or_try_init(|| open(Path::new(DEFAULT_LIBRARY_PATH)))
+}
+
+fn open(path: &Path) -> Result<RatsTlsLibrary, Error> {
+    check_version(path)?;
+
+    let library = unsafe { Library::new(path) }
+        .map_err(|err| Error::Library(format!("failed to load {}: {}", path.display(), err)))?;
+
+    macro_rules! symbol {
+        ($name:literal) => {
+            *unsafe { library.get(concat!($name, "\0").as_bytes()) }.map_err(|err| {
+                Error::Library(format!(
+                    "symbol {} not found in {}: {}",
+                    $name,
+                    path.display(),
+                    err
+                ))
+            })?
+        };
+    }
+
+    Ok(RatsTlsLibrary {
+        path: path.to_owned(),
+        init: symbol!("rats_tls_init"),
+        set_verification_callback: symbol!("rats_tls_set_verification_callback"),
+        negotiate: symbol!("rats_tls_negotiate"),
+        receive: symbol!("rats_tls_receive"),
+        transmit: symbol!("rats_tls_transmit"),
+        cleanup: symbol!("rats_tls_cleanup"),
+        _library: library,
+    })
+}
+
+/// Check the major version in the file name the path resolves to, e.g.
+/// `librats_tls.so.0.6.4`. Unversioned file names are accepted as is.
+fn check_version(path: &Path) -> Result<(), Error> {
+    let real_path = std::fs::canonicalize(path)
+        .map_err(|err| Error::Library(format!("failed to load {}: {}", path.display(), err)))?;
+    let file_name = real_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    match file_name.split(".so.").nth(1) {
+        Some(version) if version.split('.').next() != Some(SUPPORTED_MAJOR_VERSION) => {
+            Err(Error::Library(format!(
+                "{} has version {}, expect {}.x",
+                real_path.display(),
+                version,
+                SUPPORTED_MAJOR_VERSION
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Functions with the same signatures as the `extern "C"` block in ffi.rs,
+// dispatching to the loaded library.
+
+pub unsafe fn rats_tls_init(
+    conf: *const rats_tls_conf_t,
+    handle: *mut *mut rats_tls_handle,
+) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.init)(conf, handle),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+pub unsafe fn rats_tls_set_verification_callback(
+    handle: *mut *mut rats_tls_handle,
+    user_callback: rats_tls_callback_t,
+) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.set_verification_callback)(handle, user_callback),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+pub unsafe fn rats_tls_negotiate(
+    handle: *const rats_tls_handle,
+    fd: ::std::os::raw::c_int,
+) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.negotiate)(handle, fd),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+pub unsafe fn rats_tls_receive(
+    handle: *const rats_tls_handle,
+    buf: *mut ::std::os::raw::c_void,
+    buf_size: *mut size_t,
+) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.receive)(handle, buf, buf_size),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+pub unsafe fn rats_tls_transmit(
+    handle: *const rats_tls_handle,
+    buf: *const ::std::os::raw::c_void,
+    buf_size: *mut size_t,
+) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.transmit)(handle, buf, buf_size),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+pub unsafe fn rats_tls_cleanup(handle: *mut rats_tls_handle) -> rats_tls_err_t {
+    match library() {
+        Ok(library) => (library.cleanup)(handle),
+        Err(_) => RATS_TLS_ERR_DLOPEN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_library_version() {
+        let dir = std::env::temp_dir().join(format!("rats-tls-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, ok) in [
+            ("librats_tls.so.0.6.4", true),
+            ("librats_tls.so", true),
+            ("librats_tls.so.1.0.0", false),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, b"").unwrap();
+            assert_eq!(check_version(&path).is_ok(), ok, "{}", name);
+        }
+        assert!(check_version(&dir.join("missing.so")).is_err());
+
+        // A library that can not be mapped is reported, not panicked on
+        assert!(matches!(
+            open(&dir.join("librats_tls.so")),
+            Err(Error::Library(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
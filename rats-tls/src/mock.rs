@@ -0,0 +1,837 @@
+//! A pure-Rust stand-in for librats_tls with the same API as the native
+//! backend. It does real TLS with rustls, and every attester other than
+//! `nullattester` presents a self-signed certificate carrying fake SGX
+//! evidence, so that attested links can be tested without SGX.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use once_cell::sync::OnceCell;
+use ring::digest::{Context, Digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, DistinguishedNames, PrivateKey,
+    ServerConfig, ServerConnection, ServerName,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{Config, Role, DEFAULT_BUFFER_SIZE};
+use crate::error::Error;
+use crate::evidence::{Evidence, EvidenceType, SgxEvidence, TcbStatus};
+use crate::policy::AppraisalPolicy;
+
+/// Arbitrary OID of the certificate extension carrying the mock evidence,
+/// which is never meant to be seen outside of tests.
+const MOCK_EVIDENCE_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 99999, 1];
+const MOCK_EVIDENCE_OID_STR: &str = "1.3.6.1.4.1.99999.1";
+const MOCK_EVIDENCE_VERSION: u8 = 1;
+/// Size of the evidence without the variable-length user data
+const MOCK_EVIDENCE_MIN_SIZE: usize = 131;
+
+const SERVER_NAME: &str = "rats-tls";
+
+pub struct RatsTls {
+    role: Role,
+    attester: String,
+    verifier: String,
+    mutual: bool,
+    pub(crate) policy: Option<Arc<AppraisalPolicy>>,
+    pub(crate) buffer_size: usize,
+    pub(crate) handshake_timeout: Option<Duration>,
+    evidence: Evidence,
+    peer_evidence: Mutex<Option<Evidence>>,
+    credentials: Option<Credentials>,
+    session: OnceCell<Session>,
+}
+
+/// Certificate with embedded evidence and its key, shared by the sessions of
+/// a `RatsTlsAcceptor` or `RatsTlsConnector`.
+#[derive(Clone)]
+pub(crate) struct Credentials(Arc<(Certificate, PrivateKey)>);
+
+impl Credentials {
+    pub(crate) fn new(config: &Config) -> Result<Credentials, Error> {
+        let tls = RatsTls::from_config(config)?;
+        Ok(Credentials(Arc::new(tls.generate_certificate()?)))
+    }
+}
+
+struct Session {
+    conn: Mutex<Connection>,
+    sock: File,
+    /// Records read from the socket and not yet handed to `conn`
+    records: Mutex<Vec<u8>>,
+}
+
+impl RatsTls {
+    /// `tls_type`, `crypto` and `enclave_id` are ignored by the mock backend.
+    pub fn new(
+        server: bool,
+        _enclave_id: u64,
+        _tls_type: Option<&str>,
+        _crypto: Option<&str>,
+        attester: Option<&str>,
+        verifier: Option<&str>,
+        mutual: bool,
+    ) -> Result<RatsTls, Error> {
+        Ok(RatsTls {
+            role: if server { Role::Server } else { Role::Client },
+            attester: attester.unwrap_or("nullattester").to_owned(),
+            verifier: verifier.unwrap_or("nullverifier").to_owned(),
+            mutual,
+            policy: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            handshake_timeout: None,
+            evidence: Evidence {
+                evidence_type: EvidenceType::SgxEcdsa,
+                sgx: Some(SgxEvidence {
+                    mr_enclave: [0; 32],
+                    mr_signer: [0; 32],
+                    product_id: 0,
+                    security_version: 0,
+                    attributes: [0; 16],
+                }),
+                tcb_status: Some(TcbStatus::UpToDate),
+                user_data: Vec::new(),
+                unhashed: Vec::new(),
+            },
+            peer_evidence: Mutex::new(None),
+            credentials: None,
+            session: OnceCell::new(),
+        })
+    }
+
+    /// Same as `new()` with the settings of a validated `config`. No real quotes
+    /// are produced, so the SGX quote options are ignored.
+    pub(crate) fn with_config(config: &Config) -> Result<RatsTls, Error> {
+        RatsTls::new(
+            config.role == Role::Server,
+            config.enclave_id,
+            Some(&config.tls_type),
+            Some(&config.crypto),
+            Some(&config.attester),
+            Some(&config.verifier),
+            config.mutual,
+        )
+    }
+
+    /// Set the evidence presented to the peer, e.g. to mimic a debug enclave
+    /// or an out-of-date TCB.
+    pub fn set_mock_evidence(&mut self, evidence: Evidence) {
+        self.evidence = evidence;
+    }
+
+    pub fn set_user_data(&mut self, user_data: Vec<u8>) -> Result<(), Error> {
+        self.evidence.user_data = user_data;
+        Ok(())
+    }
+
+    /// Evidence of the peer as seen by the verifier during the last negotiation.
+    pub fn peer_evidence(&self) -> Option<Evidence> {
+        self.peer_evidence.lock().unwrap().clone()
+    }
+
+    pub fn negotiate(&self, fd: RawFd) -> Result<(), Error> {
+        // Like librats_tls, borrow the fd instead of taking ownership of it
+        let sock = unsafe { ManuallyDrop::new(File::from_raw_fd(fd)) }
+            .try_clone()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        let verifier = Arc::new(MockVerifier {
+            verify: self.verifier != "nullverifier",
+            policy: self.policy.clone(),
+            evidence: Mutex::new(None),
+            rejection: Mutex::new(None),
+        });
+        let (cert, key) = self.certificate()?;
+        let mut conn: Connection = match self.role {
+            Role::Server => {
+                let builder = ServerConfig::builder().with_safe_defaults();
+                let builder = if self.mutual {
+                    builder.with_client_cert_verifier(verifier.clone())
+                } else {
+                    builder.with_no_client_auth()
+                };
+                let config = builder
+                    .with_single_cert(vec![cert], key)
+                    .map_err(tls_error)?;
+                ServerConnection::new(Arc::new(config))
+                    .map_err(tls_error)?
+                    .into()
+            }
+            Role::Client => {
+                let builder = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_custom_certificate_verifier(verifier.clone());
+                let config = if self.mutual {
+                    builder
+                        .with_single_cert(vec![cert], key)
+                        .map_err(tls_error)?
+                } else {
+                    builder.with_no_client_auth()
+                };
+                let server_name = ServerName::try_from(SERVER_NAME).map_err(tls_error)?;
+                ClientConnection::new(Arc::new(config), server_name)
+                    .map_err(tls_error)?
+                    .into()
+            }
+        };
+
+        let mut io = &sock;
+        let mut result = Ok(());
+        while result.is_ok() && conn.is_handshaking() {
+            result = conn.complete_io(&mut io).map(|_| ());
+        }
+        while result.is_ok() && conn.wants_write() {
+            result = conn.write_tls(&mut io).map(|_| ());
+        }
+        *self.peer_evidence.lock().unwrap() = verifier.evidence.lock().unwrap().take();
+        if let Err(err) = result {
+            return Err(match verifier.rejection.lock().unwrap().take() {
+                Some(reason) => Error::Rejected(reason),
+                None => Error::Tls(err.to_string()),
+            });
+        }
+
+        self.session
+            .set(Session {
+                conn: Mutex::new(conn),
+                sock,
+                records: Mutex::new(Vec::new()),
+            })
+            .map_err(|_| Error::Tls("session is already negotiated".to_owned()))
+    }
+
+    pub fn receive(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let session = self.session()?;
+        let mut tls_buf = vec![0; 4096];
+        loop {
+            {
+                let mut conn = session.conn.lock().unwrap();
+                let mut records = session.records.lock().unwrap();
+                // rustls fails on records once it holds more than 16 KiB of
+                // plaintext, so none is handed over until that of the ones
+                // before is read.
+                let mut unread = &records[..];
+                while !unread.is_empty() {
+                    conn.read_tls(&mut unread)
+                        .map_err(|err| Error::Tls(err.to_string()))?;
+                    let state = conn.process_new_packets().map_err(tls_error)?;
+                    if state.plaintext_bytes_to_read() > 0 {
+                        break;
+                    }
+                }
+                let handed = records.len() - unread.len();
+                records.drain(..handed);
+                flush(&mut conn, &session.sock)?;
+
+                match conn.reader().read(buf) {
+                    Ok(0) if !buf.is_empty() => return Err(closed()),
+                    Ok(len) => return Ok(len),
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(Error::Tls(err.to_string())),
+                }
+            }
+
+            // Wait for more records without holding the lock, so that
+            // transmit() is not blocked meanwhile.
+            let len = (&session.sock)
+                .read(&mut tls_buf)
+                .map_err(|err| Error::Tls(err.to_string()))?;
+            if len == 0 {
+                return Err(closed());
+            }
+            session
+                .records
+                .lock()
+                .unwrap()
+                .extend_from_slice(&tls_buf[..len]);
+        }
+    }
+
+    pub fn transmit(&self, buf: &[u8]) -> Result<usize, Error> {
+        let session = self.session()?;
+        let mut conn = session.conn.lock().unwrap();
+        let len = conn
+            .writer()
+            .write(buf)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        flush(&mut conn, &session.sock)?;
+        Ok(len)
+    }
+
+    fn session(&self) -> Result<&Session, Error> {
+        self.session
+            .get()
+            .ok_or_else(|| Error::Tls("session is not negotiated".to_owned()))
+    }
+
+    pub(crate) fn set_credentials(&mut self, credentials: &Credentials) {
+        self.credentials = Some(credentials.clone());
+    }
+
+    fn certificate(&self) -> Result<(Certificate, PrivateKey), Error> {
+        match &self.credentials {
+            Some(credentials) => Ok(credentials.0.as_ref().clone()),
+            None => self.generate_certificate(),
+        }
+    }
+
+    fn generate_certificate(&self) -> Result<(Certificate, PrivateKey), Error> {
+        let key_pair =
+            rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).map_err(tls_error)?;
+        let mut params = rcgen::CertificateParams::new(vec![SERVER_NAME.to_owned()]);
+        if self.attester != "nullattester" {
+            let report_data = report_data(&key_pair.public_key_der(), &self.evidence.user_data);
+            params
+                .custom_extensions
+                .push(rcgen::CustomExtension::from_oid_content(
+                    MOCK_EVIDENCE_OID,
+                    encode_evidence(&self.evidence, report_data.as_ref()),
+                ));
+        }
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(key_pair);
+
+        let cert = rcgen::Certificate::from_params(params).map_err(tls_error)?;
+        Ok((
+            Certificate(cert.serialize_der().map_err(tls_error)?),
+            PrivateKey(cert.serialize_private_key_der()),
+        ))
+    }
+}
+
+fn flush(conn: &mut Connection, mut sock: &File) -> Result<(), Error> {
+    while conn.wants_write() {
+        conn.write_tls(&mut sock)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+    }
+    Ok(())
+}
+
+fn closed() -> Error {
+    Error::Tls("connection closed by peer".to_owned())
+}
+
+fn tls_error<E: std::fmt::Display>(err: E) -> Error {
+    Error::Tls(err.to_string())
+}
+
+/// Plays the role of the verifier instance and of the verification callback.
+struct MockVerifier {
+    verify: bool,
+    policy: Option<Arc<AppraisalPolicy>>,
+    evidence: Mutex<Option<Evidence>>,
+    rejection: Mutex<Option<String>>,
+}
+
+impl MockVerifier {
+    fn verify_cert(&self, end_entity: &Certificate) -> Result<(), rustls::Error> {
+        if !self.verify {
+            return Ok(());
+        }
+        let evidence = extract_evidence(&end_entity.0).map_err(rustls::Error::General)?;
+        let result = match &self.policy {
+            Some(policy) => policy.appraise(&evidence),
+            None => Ok(()),
+        };
+        *self.evidence.lock().unwrap() = Some(evidence);
+        result.map_err(|reason| {
+            *self.rejection.lock().unwrap() = Some(reason.clone());
+            rustls::Error::General(reason)
+        })
+    }
+}
+
+impl ServerCertVerifier for MockVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify_cert(end_entity)
+            .map(|_| ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for MockVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify_cert(end_entity)
+            .map(|_| ClientCertVerified::assertion())
+    }
+}
+
+/// Bind the certificate key and the user data into the evidence, the way the
+/// report data of an SGX quote binds them.
+fn report_data(public_key: &[u8], user_data: &[u8]) -> Digest {
+    let mut ctx = Context::new(&SHA256);
+    ctx.update(public_key);
+    ctx.update(user_data);
+    ctx.finish()
+}
+
+/// Extract the evidence from a certificate and check that it is bound to the
+/// key of the certificate.
+fn extract_evidence(der: &[u8]) -> Result<Evidence, String> {
+    let (_, cert) =
+        X509Certificate::from_der(der).map_err(|err| format!("invalid certificate: {}", err))?;
+    let ext = cert
+        .extensions()
+        .iter()
+        .find(|ext| ext.oid.to_id_string() == MOCK_EVIDENCE_OID_STR)
+        .ok_or_else(|| "peer certificate carries no evidence".to_owned())?;
+
+    let (evidence, expected) = decode_evidence(ext.value)?;
+    if report_data(cert.public_key().raw, &evidence.user_data).as_ref() != expected {
+        return Err("evidence is not bound to the certificate key and user data".to_owned());
+    }
+    Ok(evidence)
+}
+
+fn encode_evidence(evidence: &Evidence, report_data: &[u8]) -> Vec<u8> {
+    let evidence_type: u32 = match evidence.evidence_type {
+        EvidenceType::SgxEcdsa => 1,
+        EvidenceType::Tdx => 2,
+        EvidenceType::Unknown(other) => other,
+    };
+    let sgx = evidence.sgx.clone().unwrap_or(SgxEvidence {
+        mr_enclave: [0; 32],
+        mr_signer: [0; 32],
+        product_id: 0,
+        security_version: 0,
+        attributes: [0; 16],
+    });
+    let tcb_status = match evidence.tcb_status {
+        None => 0,
+        Some(TcbStatus::UpToDate) => 1,
+        Some(TcbStatus::SwHardeningNeeded) => 2,
+        Some(TcbStatus::ConfigurationNeeded) => 3,
+        Some(TcbStatus::ConfigurationAndSwHardeningNeeded) => 4,
+        Some(TcbStatus::OutOfDate) => 5,
+        Some(TcbStatus::OutOfDateConfigurationNeeded) => 6,
+        Some(TcbStatus::Revoked) => 7,
+    };
+
+    let mut out = Vec::with_capacity(
+        MOCK_EVIDENCE_MIN_SIZE + evidence.user_data.len() + evidence.unhashed.len(),
+    );
+    out.push(MOCK_EVIDENCE_VERSION);
+    out.extend_from_slice(&evidence_type.to_le_bytes());
+    out.push(evidence.sgx.is_some() as u8);
+    out.extend_from_slice(&sgx.mr_enclave);
+    out.extend_from_slice(&sgx.mr_signer);
+    out.extend_from_slice(&sgx.product_id.to_le_bytes());
+    out.extend_from_slice(&sgx.security_version.to_le_bytes());
+    out.extend_from_slice(&sgx.attributes);
+    out.push(tcb_status);
+    out.extend_from_slice(report_data);
+    out.extend_from_slice(&(evidence.user_data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&evidence.user_data);
+    out.extend_from_slice(&evidence.unhashed);
+    out
+}
+
+fn decode_evidence(data: &[u8]) -> Result<(Evidence, &[u8]), String> {
+    if data.len() < MOCK_EVIDENCE_MIN_SIZE || data[0] != MOCK_EVIDENCE_VERSION {
+        return Err("malformed mock evidence".to_owned());
+    }
+    let u32_at = |off: usize| u32::from_le_bytes(data[off..off + 4].try_into().unwrap());
+    let user_data_end = MOCK_EVIDENCE_MIN_SIZE + u32_at(127) as usize;
+    if data.len() < user_data_end {
+        return Err("malformed mock evidence".to_owned());
+    }
+
+    let evidence_type = match u32_at(1) {
+        1 => EvidenceType::SgxEcdsa,
+        2 => EvidenceType::Tdx,
+        other => EvidenceType::Unknown(other),
+    };
+    let sgx = if data[5] != 0 {
+        Some(SgxEvidence {
+            mr_enclave: data[6..38].try_into().unwrap(),
+            mr_signer: data[38..70].try_into().unwrap(),
+            product_id: u32_at(70),
+            security_version: u32_at(74),
+            attributes: data[78..94].try_into().unwrap(),
+        })
+    } else {
+        None
+    };
+    let tcb_status = match data[94] {
+        1 => Some(TcbStatus::UpToDate),
+        2 => Some(TcbStatus::SwHardeningNeeded),
+        3 => Some(TcbStatus::ConfigurationNeeded),
+        4 => Some(TcbStatus::ConfigurationAndSwHardeningNeeded),
+        5 => Some(TcbStatus::OutOfDate),
+        6 => Some(TcbStatus::OutOfDateConfigurationNeeded),
+        7 => Some(TcbStatus::Revoked),
+        _ => None,
+    };
+
+    Ok((
+        Evidence {
+            evidence_type,
+            sgx,
+            tcb_status,
+            user_data: data[MOCK_EVIDENCE_MIN_SIZE..user_data_end].to_vec(),
+            unhashed: data[user_data_end..].to_vec(),
+        },
+        &data[95..127],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Measurement;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Negotiate a pair of rats-tls sessions over loopback, returning the
+    /// result of the server and the client side.
+    async fn negotiate(
+        server: RatsTls,
+        client: RatsTls,
+    ) -> (
+        std::io::Result<tokio::io::DuplexStream>,
+        std::io::Result<tokio::io::DuplexStream>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.negotiate_async(stream).await
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let client = client.negotiate_async(stream).await;
+        (server.await.unwrap(), client)
+    }
+
+    fn rejection(result: std::io::Result<tokio::io::DuplexStream>) -> String {
+        let err = result.unwrap_err();
+        match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+            Some(Error::Rejected(reason)) => reason.clone(),
+            _ => panic!("expect a rejection, got {}", err),
+        }
+    }
+
+    fn mrenclave_policy(mr_enclave: [u8; 32]) -> AppraisalPolicy {
+        AppraisalPolicy {
+            mrenclave: vec![Measurement(mr_enclave)],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_data() {
+        let server = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        let mut client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        client.set_policy(mrenclave_policy([0; 32]));
+
+        let (server, client) = negotiate(server, client).await;
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        let data = vec![0x5a; 64 * 1024];
+        client.write_all(&data).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+
+        server.write_all(b"pong").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn transfer_data_read_late() {
+        let server = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        let client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        let (server, client) = negotiate(server, client).await;
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        // Full records followed by small ones pile up on the socket while
+        // the server reads nothing
+        let mut data = Vec::new();
+        for i in 0..32u8 {
+            let chunk = [vec![i; 16 * 1024], vec![i]].concat();
+            client.write_all(&chunk).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            data.extend(chunk);
+        }
+        let mut buf = vec![0; data.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert!(buf == data);
+    }
+
+    #[tokio::test]
+    async fn time_out_handshake() {
+        let config = Config {
+            handshake_timeout: Some(Duration::from_millis(200)),
+            ..Config::new(Role::Server)
+        };
+
+        // A client which connects and says nothing
+        let server = RatsTls::from_config(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(10), server.negotiate_async(stream));
+        assert!(result
+            .await
+            .expect("the handshake is not timed out")
+            .is_err());
+
+        // The timeout does not apply to the link once it is established
+        let server = RatsTls::from_config(&config).unwrap();
+        let client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        let (server, client) = negotiate(server, client).await;
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn transfer_over_unix_socket() {
+        let (server_sock, client_sock) = tokio::net::UnixStream::pair().unwrap();
+        let server = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        let client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        let (server, client) = tokio::join!(
+            server.negotiate_async(server_sock),
+            client.negotiate_async(client_sock)
+        );
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn reuse_credentials() {
+        let acceptor = crate::RatsTlsAcceptor::new(Config::new(Role::Server)).unwrap();
+        let connector = crate::RatsTlsConnector::new(Config::new(Role::Client)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                stream.write_all(b"hi").await.unwrap();
+            }
+        });
+        for _ in 0..2 {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector.connect(stream).await.unwrap();
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+        }
+        server.await.unwrap();
+
+        // Sessions sharing credentials present the same certificate
+        let credentials = Credentials::new(&Config::new(Role::Server)).unwrap();
+        let certificate = || {
+            let mut tls = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+            tls.set_credentials(&credentials);
+            tls.certificate().unwrap().0
+        };
+        assert_eq!(certificate(), certificate());
+    }
+
+    #[tokio::test]
+    async fn replace_policy() {
+        let acceptor = crate::RatsTlsAcceptor::new(Config::new(Role::Server)).unwrap();
+        let connector = crate::RatsTlsConnector::new(Config::new(Role::Client)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let strict = connector.with_policy(Arc::new(mrenclave_policy([0x11; 32])));
+        assert!(rejection(strict.connect(stream).await).contains("MRENCLAVE"));
+        assert!(strict.config().policy.is_some());
+        // The original connector keeps its policy
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(connector.connect(stream).await.is_ok());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_by_policy() {
+        let mut server = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        let mut evidence = server.evidence.clone();
+        evidence.sgx.as_mut().unwrap().mr_enclave = [0x11; 32];
+        server.set_mock_evidence(evidence);
+        let mut client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        client.set_policy(mrenclave_policy([0x22; 32]));
+
+        let (_, client) = negotiate(server, client).await;
+        assert!(rejection(client).contains("MRENCLAVE"));
+    }
+
+    #[tokio::test]
+    async fn reject_debug_enclave() {
+        let mut server = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+        let mut evidence = server.evidence.clone();
+        evidence.sgx.as_mut().unwrap().attributes[0] = 0x02;
+        server.set_mock_evidence(evidence);
+        let mut client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        client.set_policy(AppraisalPolicy {
+            reject_debug: true,
+            ..Default::default()
+        });
+
+        let (_, client) = negotiate(server, client).await;
+        assert!(rejection(client).contains("debug"));
+    }
+
+    #[tokio::test]
+    async fn bind_user_data() {
+        let server_config = Config {
+            user_data: Some(b"gateway-1".to_vec()),
+            ..Config::new(Role::Server)
+        };
+        let expect = |user_data: &[u8]| {
+            let mut client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+            client.set_policy(AppraisalPolicy {
+                user_data: Some(user_data.to_vec()),
+                ..Default::default()
+            });
+            client
+        };
+
+        let server = RatsTls::from_config(&server_config).unwrap();
+        let (_, client) = negotiate(server, expect(b"gateway-1")).await;
+        assert!(client.is_ok());
+
+        let server = RatsTls::from_config(&server_config).unwrap();
+        let (_, client) = negotiate(server, expect(b"gateway-2")).await;
+        assert!(rejection(client).contains("user data"));
+    }
+
+    #[tokio::test]
+    async fn reappraise_established_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls = RatsTls::from_config(&Config::new(Role::Server)).unwrap();
+            tls.negotiate_shared(stream).await.unwrap()
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+        client.set_policy(mrenclave_policy([0; 32]));
+        let (client, mut client_stream) = client.negotiate_shared(stream).await.unwrap();
+        let (server, mut server_stream) = server.await.unwrap();
+
+        // The attested evidence of the server is available on the client
+        assert!(client.peer_evidence().unwrap().sgx.is_some());
+        assert!(client
+            .reappraise_handshake_evidence(&mrenclave_policy([0; 32]))
+            .is_ok());
+        let err = client
+            .reappraise_handshake_evidence(&mrenclave_policy([0x11; 32]))
+            .unwrap_err();
+        assert!(matches!(err, Error::Rejected(reason) if reason.contains("MRENCLAVE")));
+        // The server did not verify the client
+        assert!(server
+            .reappraise_handshake_evidence(&AppraisalPolicy::default())
+            .is_err());
+
+        // The session is not disturbed
+        client_stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn reject_missing_evidence() {
+        let config = Config {
+            attester: "nullattester".to_owned(),
+            ..Config::new(Role::Server)
+        };
+        let server = RatsTls::from_config(&config).unwrap();
+        let client = RatsTls::from_config(&Config::new(Role::Client)).unwrap();
+
+        let (_, client) = negotiate(server, client).await;
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn mutual_attestation() {
+        let mut server = RatsTls::from_config(&Config::new_mutual(Role::Server)).unwrap();
+        server.set_policy(mrenclave_policy([0; 32]));
+        let mut client = RatsTls::from_config(&Config::new_mutual(Role::Client)).unwrap();
+        client.set_policy(mrenclave_policy([0; 32]));
+        let (server_result, client_result) = negotiate(server, client).await;
+        assert!(server_result.is_ok() && client_result.is_ok());
+
+        // The client is appraised by the server as well
+        let mut server = RatsTls::from_config(&Config::new_mutual(Role::Server)).unwrap();
+        let mut client = RatsTls::from_config(&Config::new_mutual(Role::Client)).unwrap();
+        let mut evidence = client.evidence.clone();
+        evidence.tcb_status = Some(TcbStatus::OutOfDate);
+        client.set_mock_evidence(evidence);
+        server.set_policy(AppraisalPolicy {
+            tcb_status: vec![TcbStatus::UpToDate],
+            ..Default::default()
+        });
+        let (server_result, _) = negotiate(server, client).await;
+        assert!(rejection(server_result).contains("OutOfDate"));
+    }
+
+    #[test]
+    fn evidence_roundtrip() {
+        let evidence = Evidence {
+            evidence_type: EvidenceType::SgxEcdsa,
+            sgx: Some(SgxEvidence {
+                mr_enclave: [0x11; 32],
+                mr_signer: [0x22; 32],
+                product_id: 3,
+                security_version: 4,
+                attributes: [0x02; 16],
+            }),
+            tcb_status: Some(TcbStatus::SwHardeningNeeded),
+            user_data: b"gateway-1".to_vec(),
+            unhashed: b"nonce".to_vec(),
+        };
+        let encoded = encode_evidence(&evidence, &[0x33; 32]);
+        assert_eq!(encoded.len(), MOCK_EVIDENCE_MIN_SIZE + 14);
+        let (decoded, report_data) = decode_evidence(&encoded).unwrap();
+        assert_eq!(decoded, evidence);
+        assert_eq!(report_data, &[0x33; 32]);
+        assert!(decode_evidence(&encoded[1..]).is_err());
+        assert!(decode_evidence(&encoded[..MOCK_EVIDENCE_MIN_SIZE + 8]).is_err());
+    }
+}
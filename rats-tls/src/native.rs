@@ -0,0 +1,640 @@
+/* Copyright (c) 2020-2021 Alibaba Cloud and Intel Corporation
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::cell::RefCell;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use foreign_types::{ForeignType, ForeignTypeRef, Opaque};
+
+use crate::config::{Config, QuoteVerification, Role, DEFAULT_BUFFER_SIZE};
+use crate::error::Error;
+use crate::evidence::Evidence;
+use crate::ffi::*;
+use crate::policy::AppraisalPolicy;
+#[cfg(test)]
+use tests::fake_ffi::{
+    rats_tls_cleanup, rats_tls_init, rats_tls_negotiate, rats_tls_receive,
+    rats_tls_set_verification_callback, rats_tls_transmit,
+};
+
+pub struct RatsTlsRef(Opaque);
+
+unsafe impl ForeignTypeRef for RatsTlsRef {
+    type CType = rats_tls_handle;
+}
+
+/// Owned librats_tls handle, which is cleaned up on drop. It is deliberately
+/// not `Clone`; wrap it in an `Arc` to share it between threads, as
+/// `negotiate_async()` does for its reader and writer tasks.
+pub struct RatsTls {
+    handle: NonNull<rats_tls_handle>,
+    pub(crate) policy: Option<Arc<AppraisalPolicy>>,
+    pub(crate) buffer_size: usize,
+    pub(crate) handshake_timeout: Option<Duration>,
+    peer_evidence: Mutex<Option<Evidence>>,
+}
+
+unsafe impl Send for RatsTlsRef {}
+unsafe impl Sync for RatsTlsRef {}
+unsafe impl Send for RatsTls {}
+unsafe impl Sync for RatsTls {}
+
+unsafe impl ForeignType for RatsTls {
+    type CType = rats_tls_handle;
+    type Ref = RatsTlsRef;
+
+    unsafe fn from_ptr(ptr: *mut rats_tls_handle) -> RatsTls {
+        RatsTls {
+            handle: NonNull::new_unchecked(ptr),
+            policy: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            handshake_timeout: None,
+            peer_evidence: Mutex::new(None),
+        }
+    }
+
+    fn as_ptr(&self) -> *mut rats_tls_handle {
+        self.handle.as_ptr()
+    }
+
+    fn into_ptr(self) -> *mut rats_tls_handle {
+        let mut this = ManuallyDrop::new(self);
+        this.policy.take();
+        this.peer_evidence.get_mut().unwrap().take();
+        this.as_ptr()
+    }
+}
+
+impl Drop for RatsTls {
+    fn drop(&mut self) {
+        unsafe {
+            rats_tls_cleanup(self.as_ptr());
+        }
+    }
+}
+
+impl Deref for RatsTls {
+    type Target = RatsTlsRef;
+
+    fn deref(&self) -> &RatsTlsRef {
+        unsafe { RatsTlsRef::from_ptr(self.as_ptr()) }
+    }
+}
+
+impl DerefMut for RatsTls {
+    fn deref_mut(&mut self) -> &mut RatsTlsRef {
+        unsafe { RatsTlsRef::from_ptr_mut(self.as_ptr()) }
+    }
+}
+
+/// The librats_tls log level matching what the `log` filter lets through for
+/// the `rats_tls` target, e.g. `RUST_LOG=rats_tls=debug`. librats_tls prints
+/// straight to stderr and has no hook to capture its messages, so they cannot
+/// be re-emitted through `log`; this only keeps the amount of them in line.
+fn log_level() -> rats_tls_log_level_t {
+    let enabled = |level| log::log_enabled!(target: "rats_tls", level);
+    if enabled(log::Level::Debug) {
+        RATS_TLS_LOG_LEVEL_DEBUG
+    } else if enabled(log::Level::Info) {
+        RATS_TLS_LOG_LEVEL_INFO
+    } else if enabled(log::Level::Warn) {
+        RATS_TLS_LOG_LEVEL_WARN
+    } else if enabled(log::Level::Error) {
+        RATS_TLS_LOG_LEVEL_ERROR
+    } else {
+        RATS_TLS_LOG_LEVEL_NONE
+    }
+}
+
+impl RatsTls {
+    pub fn new(
+        server: bool,
+        enclave_id: u64,
+        tls_type: Option<&str>,
+        crypto: Option<&str>,
+        attester: Option<&str>,
+        verifier: Option<&str>,
+        mutual: bool,
+    ) -> Result<RatsTls, Error> {
+        let conf = new_conf(
+            server, enclave_id, tls_type, crypto, attester, verifier, mutual,
+        )?;
+        RatsTls::init(&conf)
+    }
+
+    /// Same as `new()` with the settings of a validated `config`, including
+    /// the options of SGX quotes which `new()` leaves to librats_tls.
+    pub(crate) fn with_config(config: &Config) -> Result<RatsTls, Error> {
+        let mut conf = new_conf(
+            config.role == Role::Server,
+            config.enclave_id,
+            Some(&config.tls_type),
+            Some(&config.crypto),
+            Some(&config.attester),
+            Some(&config.verifier),
+            config.mutual,
+        )?;
+        if let Some(ecdsa) = &config.sgx_ecdsa {
+            conf.quote_sgx_ecdsa.valid = true;
+            conf.quote_sgx_ecdsa.cert_type = ecdsa.cert_type;
+            conf.quote_sgx_ecdsa.verification_type = match ecdsa.verification {
+                QuoteVerification::Qvl => VERIFICATION_TYPE_QVL,
+                QuoteVerification::Qel => VERIFICATION_TYPE_QEL,
+            };
+        }
+        if let Some(epid) = &config.sgx_epid {
+            conf.quote_sgx_epid.valid = true;
+            conf.quote_sgx_epid.spid = epid.spid.0;
+            conf.quote_sgx_epid.linkable = epid.linkable;
+        }
+        RatsTls::init(&conf)
+    }
+
+    fn init(conf: &rats_tls_conf_t) -> Result<RatsTls, Error> {
+        #[cfg(all(feature = "dlopen", not(test)))]
+        crate::loader::library()?;
+
+        // rats_tls_init() allocates the handle and stores it into `tls`
+        let mut tls: *mut rats_tls_handle = std::ptr::null_mut();
+        let err = unsafe { rats_tls_init(conf, &mut tls) };
+        if err != RATS_TLS_ERR_NONE {
+            return Err(Error::Native(err));
+        }
+        if tls.is_null() {
+            return Err(Error::Native(RATS_TLS_ERR_INIT));
+        }
+        // From here on the handle is released by Drop, also on the error path below
+        let tls = unsafe { RatsTls::from_ptr(tls) };
+
+        let mut handle = tls.as_ptr();
+        let err =
+            unsafe { rats_tls_set_verification_callback(&mut handle, Some(verification_callback)) };
+        if err == RATS_TLS_ERR_NONE {
+            Ok(tls)
+        } else {
+            Err(Error::Native(err))
+        }
+    }
+
+    /// librats_tls has no API to put user data into the evidence it produces.
+    /// `Config::validate()` refuses `Config::user_data` for the same reason.
+    pub fn set_user_data(&mut self, _user_data: Vec<u8>) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "custom user data in evidence".to_owned(),
+        ))
+    }
+
+    /// Evidence of the peer as seen by the verifier during the last negotiation.
+    pub fn peer_evidence(&self) -> Option<Evidence> {
+        self.peer_evidence.lock().unwrap().clone()
+    }
+
+    pub fn negotiate(&self, fd: RawFd) -> Result<(), Error> {
+        // The verification callback is invoked on this thread from inside
+        // rats_tls_negotiate(), so the policy is handed over by a thread local.
+        VERIFY_CONTEXT.with(|ctx| {
+            *ctx.borrow_mut() = Some(VerifyContext {
+                policy: self.policy.clone(),
+                evidence: None,
+                rejection: None,
+            })
+        });
+        let err = unsafe { rats_tls_negotiate(self.as_ptr(), fd) };
+        let ctx = VERIFY_CONTEXT.with(|ctx| ctx.borrow_mut().take());
+        let rejection = ctx.and_then(|ctx| {
+            *self.peer_evidence.lock().unwrap() = ctx.evidence;
+            ctx.rejection
+        });
+
+        if let Some(reason) = rejection {
+            Err(Error::Rejected(reason))
+        } else if err == RATS_TLS_ERR_NONE {
+            Ok(())
+        } else {
+            Err(Error::Native(err))
+        }
+    }
+
+    pub fn receive(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut len: size_t = buf.len() as size_t;
+        let err = unsafe {
+            rats_tls_receive(
+                self.as_ptr(),
+                buf.as_mut_ptr() as *mut ::std::os::raw::c_void,
+                &mut len,
+            )
+        };
+        if err == RATS_TLS_ERR_NONE {
+            Ok(len as usize)
+        } else {
+            Err(Error::Native(err))
+        }
+    }
+
+    pub fn transmit(&self, buf: &[u8]) -> Result<usize, Error> {
+        let mut len: size_t = buf.len() as size_t;
+        let err = unsafe {
+            rats_tls_transmit(
+                self.as_ptr(),
+                buf.as_ptr() as *const ::std::os::raw::c_void,
+                &mut len,
+            )
+        };
+        if err == RATS_TLS_ERR_NONE {
+            Ok(len as usize)
+        } else {
+            Err(Error::Native(err))
+        }
+    }
+}
+
+/// Copy the name of a rats-tls instance type into a NUL-terminated array of `rats_tls_conf_t`.
+fn new_conf(
+    server: bool,
+    enclave_id: u64,
+    tls_type: Option<&str>,
+    crypto: Option<&str>,
+    attester: Option<&str>,
+    verifier: Option<&str>,
+    mutual: bool,
+) -> Result<rats_tls_conf_t, Error> {
+    let mut conf: rats_tls_conf_t = Default::default();
+    conf.api_version = RATS_TLS_API_VERSION_DEFAULT;
+    conf.log_level = log_level();
+    if let Some(tls_type) = tls_type {
+        copy_type_name(&mut conf.tls_type, tls_type)?;
+    }
+    if let Some(crypto) = crypto {
+        copy_type_name(&mut conf.crypto_type, crypto)?;
+    }
+    if let Some(attester) = attester {
+        copy_type_name(&mut conf.attester_type, attester)?;
+    }
+    if let Some(verifier) = verifier {
+        copy_type_name(&mut conf.verifier_type, verifier)?;
+    }
+    conf.cert_algo = RATS_TLS_CERT_ALGO_DEFAULT;
+    conf.enclave_id = enclave_id;
+    if mutual {
+        conf.flags |= RATS_TLS_CONF_FLAGS_MUTUAL;
+    }
+    if server {
+        conf.flags |= RATS_TLS_CONF_FLAGS_SERVER;
+    }
+    Ok(conf)
+}
+
+fn copy_type_name(dst: &mut [u8], name: &str) -> Result<(), Error> {
+    if name.len() >= dst.len() || name.as_bytes().contains(&0) {
+        return Err(Error::Config(format!(
+            "instance type name '{}' must be shorter than {} bytes",
+            name,
+            dst.len()
+        )));
+    }
+    dst[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(())
+}
+
+struct VerifyContext {
+    policy: Option<Arc<AppraisalPolicy>>,
+    evidence: Option<Evidence>,
+    rejection: Option<String>,
+}
+
+thread_local! {
+    static VERIFY_CONTEXT: RefCell<Option<VerifyContext>> = RefCell::new(None);
+}
+
+/// Called by librats_tls with the `rtls_evidence_t` of the peer once the quote
+/// itself has been verified. Returns non-zero to accept the peer.
+unsafe extern "C" fn verification_callback(
+    args: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    if args.is_null() {
+        return 0;
+    }
+    let evidence = Evidence::from_ffi(&*(args as *const rtls_evidence_t));
+
+    let accepted = std::panic::catch_unwind(|| {
+        VERIFY_CONTEXT.with(|ctx| match ctx.borrow_mut().as_mut() {
+            Some(ctx) => {
+                let result = match &ctx.policy {
+                    Some(policy) => policy.appraise(&evidence),
+                    None => Ok(()),
+                };
+                ctx.evidence = Some(evidence);
+                match result {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        ctx.rejection = Some(reason);
+                        false
+                    }
+                }
+            }
+            None => true,
+        })
+    });
+    matches!(accepted, Ok(true)) as ::std::os::raw::c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{SgxEcdsaQuote, SgxEpidQuote, Spid};
+    use std::sync::atomic::Ordering;
+
+    /// Stands in for librats_tls, with the same signatures as the functions in
+    /// ffi.rs. Handles are leaked on cleanup so that the number of cleanups of
+    /// each handle can still be checked afterwards.
+    pub(super) mod fake_ffi {
+        use std::cell::Cell;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        use crate::ffi::*;
+
+        pub struct FakeHandle {
+            pub out_param_was_null: bool,
+            pub conf: rats_tls_conf_t,
+            pub fail_callback: bool,
+            pub callback: Mutex<rats_tls_callback_t>,
+            pub negotiated: AtomicBool,
+            pub cleanups: AtomicUsize,
+        }
+
+        thread_local! {
+            /// Last handle allocated on this thread, to inspect handles that
+            /// never made it out of `RatsTls::new()`.
+            pub static LAST_HANDLE: Cell<*const FakeHandle> = Cell::new(std::ptr::null());
+        }
+
+        pub unsafe fn handle<'a>(ptr: *const rats_tls_handle) -> &'a FakeHandle {
+            &*(ptr as *const FakeHandle)
+        }
+
+        fn attester(conf: &rats_tls_conf_t) -> &[u8] {
+            let len = conf.attester_type.iter().position(|&c| c == 0).unwrap();
+            &conf.attester_type[..len]
+        }
+
+        pub unsafe fn rats_tls_init(
+            conf: *const rats_tls_conf_t,
+            handle: *mut *mut rats_tls_handle,
+        ) -> rats_tls_err_t {
+            if conf.is_null() || handle.is_null() {
+                return RATS_TLS_ERR_INVALID;
+            }
+            if attester(&*conf) == b"fail_init" {
+                return RATS_TLS_ERR_INIT;
+            }
+            let fake = Box::into_raw(Box::new(FakeHandle {
+                out_param_was_null: (*handle).is_null(),
+                conf: *conf,
+                fail_callback: attester(&*conf) == b"fail_callback",
+                callback: Mutex::new(None),
+                negotiated: AtomicBool::new(false),
+                cleanups: AtomicUsize::new(0),
+            }));
+            LAST_HANDLE.with(|last| last.set(fake));
+            *handle = fake as *mut rats_tls_handle;
+            RATS_TLS_ERR_NONE
+        }
+
+        pub unsafe fn rats_tls_set_verification_callback(
+            handle: *mut *mut rats_tls_handle,
+            user_callback: rats_tls_callback_t,
+        ) -> rats_tls_err_t {
+            let fake = self::handle(*handle);
+            if fake.fail_callback {
+                return RATS_TLS_ERR_INVALID;
+            }
+            *fake.callback.lock().unwrap() = user_callback;
+            RATS_TLS_ERR_NONE
+        }
+
+        pub unsafe fn rats_tls_negotiate(
+            handle: *const rats_tls_handle,
+            _fd: ::std::os::raw::c_int,
+        ) -> rats_tls_err_t {
+            let fake = self::handle(handle);
+            let mut mr_enclave = [0x11u8; 32];
+            let mut mr_signer = [0x22u8; 32];
+            let mut attributes = [0u8; 16];
+            let mut user_data = *b"gateway-1";
+            let mut ev: rtls_evidence_t = std::mem::zeroed();
+            ev.ehd.user_data = user_data.as_mut_ptr() as *mut _;
+            ev.ehd.user_data_size = user_data.len() as _;
+            ev.type_ = enclave_evidence_type_t_SGX_ECDSA;
+            ev.__bindgen_anon_1.sgx.mr_enclave = mr_enclave.as_mut_ptr();
+            ev.__bindgen_anon_1.sgx.mr_signer = mr_signer.as_mut_ptr();
+            ev.__bindgen_anon_1.sgx.attributes = attributes.as_mut_ptr();
+
+            if let Some(callback) = *fake.callback.lock().unwrap() {
+                if callback(&mut ev as *mut rtls_evidence_t as *mut _) == 0 {
+                    return RATS_TLS_ERR_INVALID;
+                }
+            }
+            fake.negotiated.store(true, Ordering::SeqCst);
+            RATS_TLS_ERR_NONE
+        }
+
+        pub unsafe fn rats_tls_receive(
+            handle: *const rats_tls_handle,
+            _buf: *mut ::std::os::raw::c_void,
+            _buf_size: *mut size_t,
+        ) -> rats_tls_err_t {
+            if self::handle(handle).negotiated.load(Ordering::SeqCst) {
+                RATS_TLS_ERR_NONE
+            } else {
+                RATS_TLS_ERR_INVALID
+            }
+        }
+
+        pub unsafe fn rats_tls_transmit(
+            handle: *const rats_tls_handle,
+            _buf: *const ::std::os::raw::c_void,
+            _buf_size: *mut size_t,
+        ) -> rats_tls_err_t {
+            if self::handle(handle).negotiated.load(Ordering::SeqCst) {
+                RATS_TLS_ERR_NONE
+            } else {
+                RATS_TLS_ERR_INVALID
+            }
+        }
+
+        pub unsafe fn rats_tls_cleanup(handle: *mut rats_tls_handle) -> rats_tls_err_t {
+            self::handle(handle).cleanups.fetch_add(1, Ordering::SeqCst);
+            RATS_TLS_ERR_NONE
+        }
+    }
+
+    fn new_tls(attester: &str) -> Result<RatsTls, Error> {
+        RatsTls::new(true, 0, None, None, Some(attester), None, false)
+    }
+
+    fn cleanups(ptr: *const rats_tls_handle) -> usize {
+        unsafe { fake_ffi::handle(ptr) }
+            .cleanups
+            .load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn handle_lifecycle() {
+        let tls = new_tls("sgx_ecdsa").unwrap();
+        let ptr = tls.as_ptr();
+        // The handle is allocated by rats_tls_init() rather than by the caller
+        assert!(unsafe { fake_ffi::handle(ptr) }.out_param_was_null);
+        assert_eq!(cleanups(ptr), 0);
+        drop(tls);
+        assert_eq!(cleanups(ptr), 1);
+
+        let ptr = new_tls("sgx_ecdsa").unwrap().into_ptr();
+        assert_eq!(cleanups(ptr), 0);
+        drop(unsafe { RatsTls::from_ptr(ptr) });
+        assert_eq!(cleanups(ptr), 1);
+    }
+
+    #[test]
+    fn log_level_follows_log_filter() {
+        /// Lets through up to info for the `rats_tls` target only
+        struct Logger;
+        impl log::Log for Logger {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.target() == "rats_tls" && metadata.level() <= log::Level::Info
+            }
+            fn log(&self, _record: &log::Record) {}
+            fn flush(&self) {}
+        }
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let tls = new_tls("sgx_ecdsa").unwrap();
+        let fake = unsafe { fake_ffi::handle(tls.as_ptr()) };
+        assert_eq!(fake.conf.log_level, RATS_TLS_LOG_LEVEL_INFO);
+    }
+
+    #[test]
+    fn quote_options() {
+        let tls = new_tls("sgx_ecdsa").unwrap();
+        let conf = unsafe { fake_ffi::handle(tls.as_ptr()) }.conf;
+        assert!(!conf.quote_sgx_ecdsa.valid);
+        assert!(!conf.quote_sgx_epid.valid);
+
+        let config = Config {
+            attester: "sgx_epid".to_owned(),
+            sgx_ecdsa: Some(SgxEcdsaQuote {
+                cert_type: 5,
+                verification: QuoteVerification::Qel,
+            }),
+            sgx_epid: Some(SgxEpidQuote {
+                spid: Spid([0x11; 16]),
+                linkable: true,
+            }),
+            ..Config::new_mutual(Role::Server)
+        };
+        let tls = RatsTls::from_config(&config).unwrap();
+        let conf = unsafe { fake_ffi::handle(tls.as_ptr()) }.conf;
+        assert!(conf.quote_sgx_ecdsa.valid);
+        assert_eq!(conf.quote_sgx_ecdsa.cert_type, 5);
+        assert_eq!(
+            conf.quote_sgx_ecdsa.verification_type,
+            VERIFICATION_TYPE_QEL
+        );
+        assert!(conf.quote_sgx_epid.valid);
+        assert_eq!(conf.quote_sgx_epid.spid, [0x11; 16]);
+        assert!(conf.quote_sgx_epid.linkable);
+
+        // Invalid options never reach librats_tls
+        let config = Config {
+            sgx_ecdsa: Some(SgxEcdsaQuote {
+                cert_type: 0,
+                ..Default::default()
+            }),
+            ..config
+        };
+        assert!(matches!(
+            RatsTls::from_config(&config),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn init_failures() {
+        assert!(matches!(
+            new_tls("fail_init"),
+            Err(Error::Native(RATS_TLS_ERR_INIT))
+        ));
+
+        // A handle that fails to be set up is still released
+        assert!(matches!(
+            new_tls("fail_callback"),
+            Err(Error::Native(RATS_TLS_ERR_INVALID))
+        ));
+        let ptr = fake_ffi::LAST_HANDLE.with(|last| last.get()) as *const rats_tls_handle;
+        assert_eq!(cleanups(ptr), 1);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let tls = Arc::new(new_tls("sgx_ecdsa").unwrap());
+        let ptr = tls.as_ptr();
+        tls.negotiate(-1).unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let tls = tls.clone();
+                std::thread::spawn(move || {
+                    tls.transmit(b"data").unwrap();
+                    tls.receive(&mut [0; 4]).unwrap();
+                })
+            })
+            .collect();
+        drop(tls);
+        assert_eq!(cleanups(ptr), 0);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(cleanups(ptr), 1);
+    }
+
+    #[test]
+    fn negotiate_with_policy() {
+        let mut tls = new_tls("sgx_ecdsa").unwrap();
+        tls.set_policy(AppraisalPolicy {
+            mrenclave: vec![crate::Measurement([0x11; 32])],
+            ..Default::default()
+        });
+        assert!(tls.negotiate(-1).is_ok());
+        let evidence = tls.peer_evidence().unwrap();
+        assert_eq!(evidence.sgx.unwrap().mr_signer, [0x22; 32]);
+        assert_eq!(evidence.user_data, b"gateway-1");
+
+        let policy = AppraisalPolicy {
+            mrsigner: vec![crate::Measurement([0x33; 32])],
+            ..Default::default()
+        };
+        assert!(matches!(
+            tls.reappraise_handshake_evidence(&policy),
+            Err(Error::Rejected(_))
+        ));
+        tls.set_policy(policy);
+        assert!(matches!(tls.negotiate(-1), Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn unsupported_by_librats_tls() {
+        let mut tls = new_tls("sgx_ecdsa").unwrap();
+        tls.negotiate(-1).unwrap();
+        assert!(matches!(
+            tls.set_user_data(b"gateway-1".to_vec()),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::error::Error;
+use crate::evidence::{Evidence, TcbStatus};
+
+/// A 256-bit enclave measurement, written as a hex string in the policy file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Measurement(pub [u8; 32]);
+
+impl fmt::Debug for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl std::str::FromStr for Measurement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_hex(s)?;
+        bytes
+            .try_into()
+            .map(Measurement)
+            .map_err(|_| format!("expect 64 hex digits, got '{}'", s.trim_start_matches("0x")))
+    }
+}
+
+/// Parse a string of hex digits with an optional `0x` prefix.
+pub(crate) fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let s = s.trim_start_matches("0x");
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits in '{}'", s))
+        })
+        .collect()
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_hex(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+impl<'de> Deserialize<'de> for Measurement {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Reference values the evidence of the peer is appraised against. Empty
+/// lists and unset fields are not checked.
+///
+/// ```json
+/// {
+///     "mrenclave": ["<64 hex digits>"],
+///     "mrsigner": ["<64 hex digits>"],
+///     "min_isv_svn": 1,
+///     "reject_debug": true,
+///     "tcb_status": ["UpToDate", "SWHardeningNeeded"],
+///     "user_data": "<hex digits>"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppraisalPolicy {
+    pub mrenclave: Vec<Measurement>,
+    pub mrsigner: Vec<Measurement>,
+    pub min_isv_svn: Option<u32>,
+    pub reject_debug: bool,
+    pub tcb_status: Vec<TcbStatus>,
+    /// Expected user data bound into the evidence, so that evidence produced
+    /// for another deployment is not accepted here.
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub user_data: Option<Vec<u8>>,
+}
+
+impl AppraisalPolicy {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<AppraisalPolicy, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::Policy(format!("{}: {}", path.display(), err)))?;
+        let policy: AppraisalPolicy = serde_json::from_str(&content)
+            .map_err(|err| Error::Policy(format!("{}: {}", path.display(), err)))?;
+        policy
+            .check_supported()
+            .map_err(|reason| Error::Policy(format!("{}: {}", path.display(), reason)))?;
+        Ok(policy)
+    }
+
+    /// Refuses the fields the backend cannot check. librats_tls does not pass
+    /// the result of quote verification to the verification callback, so the
+    /// TCB status of a peer is never known and `tcb_status` would reject every
+    /// peer.
+    pub(crate) fn check_supported(&self) -> Result<(), String> {
+        if cfg!(not(feature = "mock")) && !self.tcb_status.is_empty() {
+            return Err(
+                "tcb_status cannot be checked, librats_tls does not report the TCB status of the peer"
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    fn requires_sgx(&self) -> bool {
+        !self.mrenclave.is_empty()
+            || !self.mrsigner.is_empty()
+            || self.min_isv_svn.is_some()
+            || self.reject_debug
+    }
+
+    /// Check the evidence against the reference values, returning the reason
+    /// for the first mismatch found.
+    pub fn appraise(&self, evidence: &Evidence) -> Result<(), String> {
+        if !self.tcb_status.is_empty() {
+            match evidence.tcb_status {
+                Some(status) if self.tcb_status.contains(&status) => {}
+                Some(status) => return Err(format!("TCB status {:?} is not accepted", status)),
+                None => return Err("TCB status is not reported by the verifier".to_owned()),
+            }
+        }
+
+        if let Some(user_data) = &self.user_data {
+            if &evidence.user_data != user_data {
+                return Err("user data does not match the expected value".to_owned());
+            }
+        }
+
+        if !self.requires_sgx() {
+            return Ok(());
+        }
+        let sgx = evidence.sgx.as_ref().ok_or_else(|| {
+            format!(
+                "{:?} evidence carries no SGX enclave report",
+                evidence.evidence_type
+            )
+        })?;
+
+        if !self.mrenclave.is_empty() && !self.mrenclave.contains(&Measurement(sgx.mr_enclave)) {
+            return Err(format!(
+                "MRENCLAVE {} is not in the allowed list",
+                Measurement(sgx.mr_enclave)
+            ));
+        }
+        if !self.mrsigner.is_empty() && !self.mrsigner.contains(&Measurement(sgx.mr_signer)) {
+            return Err(format!(
+                "MRSIGNER {} is not in the allowed list",
+                Measurement(sgx.mr_signer)
+            ));
+        }
+        if let Some(min_isv_svn) = self.min_isv_svn {
+            if sgx.security_version < min_isv_svn {
+                return Err(format!(
+                    "ISV SVN {} is lower than the minimum {}",
+                    sgx.security_version, min_isv_svn
+                ));
+            }
+        }
+        if self.reject_debug && sgx.is_debug() {
+            return Err("peer is a debug enclave".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evidence::{EvidenceType, SgxEvidence};
+
+    const SGX_DEBUG: u8 = 0x02;
+
+    fn evidence(attributes0: u8, security_version: u32) -> Evidence {
+        Evidence {
+            evidence_type: EvidenceType::SgxEcdsa,
+            sgx: Some(SgxEvidence {
+                mr_enclave: [0x11; 32],
+                mr_signer: [0x22; 32],
+                product_id: 0,
+                security_version,
+                attributes: [attributes0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            }),
+            tcb_status: Some(TcbStatus::UpToDate),
+            user_data: b"gateway-1".to_vec(),
+            unhashed: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_policy() {
+        let policy: AppraisalPolicy = serde_json::from_str(&format!(
+            r#"{{"mrenclave": ["{}"], "min_isv_svn": 2, "reject_debug": true, "tcb_status": ["UpToDate", "SWHardeningNeeded"], "user_data": "0x6777"}}"#,
+            "11".repeat(32)
+        ))
+        .unwrap();
+        assert_eq!(policy.mrenclave, vec![Measurement([0x11; 32])]);
+        assert!(policy.mrsigner.is_empty());
+        assert_eq!(policy.min_isv_svn, Some(2));
+        assert!(policy.reject_debug);
+        assert_eq!(
+            policy.tcb_status,
+            vec![TcbStatus::UpToDate, TcbStatus::SwHardeningNeeded]
+        );
+        assert_eq!(policy.user_data, Some(b"gw".to_vec()));
+
+        assert!(serde_json::from_str::<AppraisalPolicy>(r#"{"mrenclave": ["1234"]}"#).is_err());
+        assert!(serde_json::from_str::<AppraisalPolicy>(r#"{"unknown": 1}"#).is_err());
+        assert!(serde_json::from_str::<AppraisalPolicy>(r#"{"user_data": "123"}"#).is_err());
+    }
+
+    #[test]
+    fn refuse_unsupported_fields() {
+        let path = std::env::temp_dir().join(format!("policy-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"tcb_status": ["UpToDate"]}"#).unwrap();
+        let loaded = AppraisalPolicy::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        if cfg!(feature = "mock") {
+            assert!(loaded.is_ok());
+        } else {
+            assert!(matches!(loaded, Err(Error::Policy(reason)) if reason.contains("tcb_status")));
+        }
+    }
+
+    #[test]
+    fn appraise_evidence() {
+        let policy = AppraisalPolicy {
+            mrenclave: vec![Measurement([0x11; 32])],
+            mrsigner: vec![Measurement([0x22; 32])],
+            min_isv_svn: Some(2),
+            reject_debug: true,
+            tcb_status: vec![TcbStatus::UpToDate],
+            user_data: Some(b"gateway-1".to_vec()),
+        };
+        assert!(policy.appraise(&evidence(0, 2)).is_ok());
+        assert!(policy
+            .appraise(&evidence(0, 1))
+            .unwrap_err()
+            .contains("ISV SVN"));
+        assert!(policy
+            .appraise(&evidence(SGX_DEBUG, 2))
+            .unwrap_err()
+            .contains("debug"));
+
+        let mut ev = evidence(0, 2);
+        ev.sgx.as_mut().unwrap().mr_enclave = [0x33; 32];
+        assert!(policy.appraise(&ev).unwrap_err().contains("MRENCLAVE"));
+
+        let mut ev = evidence(0, 2);
+        ev.tcb_status = Some(TcbStatus::OutOfDate);
+        assert!(policy.appraise(&ev).unwrap_err().contains("OutOfDate"));
+        ev.tcb_status = None;
+        assert!(policy.appraise(&ev).is_err());
+
+        let mut ev = evidence(0, 2);
+        ev.user_data = b"gateway-2".to_vec();
+        assert!(policy.appraise(&ev).unwrap_err().contains("user data"));
+
+        let ev = Evidence {
+            evidence_type: EvidenceType::Tdx,
+            sgx: None,
+            tcb_status: Some(TcbStatus::UpToDate),
+            user_data: b"gateway-1".to_vec(),
+            unhashed: Vec::new(),
+        };
+        assert!(policy.appraise(&ev).is_err());
+        assert!(AppraisalPolicy::default().appraise(&ev).is_ok());
+    }
+}
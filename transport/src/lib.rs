@@ -0,0 +1,284 @@
+//! Stream sockets that ENTA and ENTG talk over. Besides TCP, Unix domain
+//! sockets and AF_VSOCK are supported for TEEs such as Occlum and VM-based
+//! enclaves, selected by the scheme of the address:
+//!
+//! - `host:port` or `tcp:host:port`, or just `port` for all IPv4 interfaces
+//! - `unix:<path>`
+//! - `vsock:<cid>:<port>`, where `<cid>` may be `any` when listening
+mod vsock;
+
+use std::fmt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+pub use vsock::{VsockListener, VsockStream};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+    Vsock { cid: u32, port: u32 },
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("missing socket path in '{}'", s));
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+
+        if let Some(addr) = s.strip_prefix("vsock:") {
+            let (cid, port) = addr
+                .split_once(':')
+                .ok_or_else(|| format!("expect 'vsock:<cid>:<port>', got '{}'", s))?;
+            let cid = match cid {
+                "any" => libc::VMADDR_CID_ANY,
+                cid => cid
+                    .parse()
+                    .map_err(|_| format!("invalid vsock CID in '{}'", s))?,
+            };
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid vsock port in '{}'", s))?;
+            return Ok(Address::Vsock { cid, port });
+        }
+
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        if let Ok(port) = addr.parse::<u16>() {
+            return Ok(Address::Tcp(format!("0.0.0.0:{}", port)));
+        }
+        if !addr.contains(':') {
+            return Err(format!(
+                "expect 'host:port', 'unix:<path>' or 'vsock:<cid>:<port>', got '{}'",
+                s
+            ));
+        }
+        Ok(Address::Tcp(addr.to_owned()))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::Vsock { cid, port } if *cid == libc::VMADDR_CID_ANY => {
+                write!(f, "vsock:any:{}", port)
+            }
+            Address::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+        }
+    }
+}
+
+/// A connected stream of any of the supported kinds.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Vsock(VsockStream),
+}
+
+impl Stream {
+    pub fn peer_addr(&self) -> io::Result<Address> {
+        match self {
+            Stream::Tcp(stream) => Ok(Address::Tcp(stream.peer_addr()?.to_string())),
+            Stream::Unix(stream) => Ok(Address::Unix(
+                stream
+                    .peer_addr()?
+                    .as_pathname()
+                    .map(PathBuf::from)
+                    .unwrap_or_default(),
+            )),
+            Stream::Vsock(stream) => {
+                let (cid, port) = stream.peer_addr()?;
+                Ok(Address::Vsock { cid, port })
+            }
+        }
+    }
+}
+
+pub async fn connect(addr: &Address) -> io::Result<Stream> {
+    match addr {
+        Address::Tcp(addr) => TcpStream::connect(addr).await.map(Stream::Tcp),
+        Address::Unix(path) => UnixStream::connect(path).await.map(Stream::Unix),
+        Address::Vsock { cid, port } => VsockStream::connect(*cid, *port).await.map(Stream::Vsock),
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Vsock(VsockListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &Address) -> io::Result<Listener> {
+        match addr {
+            Address::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            Address::Unix(path) => {
+                // Remove the socket left behind by a previous run
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            Address::Vsock { cid, port } => VsockListener::bind(*cid, *port).map(Listener::Vsock),
+        }
+    }
+
+    /// Accepts a new connection, returning it with the address of the peer.
+    pub async fn accept(&self) -> io::Result<(Stream, Address)> {
+        let stream = match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept().await?.0),
+            Listener::Unix(listener) => Stream::Unix(listener.accept().await?.0),
+            Listener::Vsock(listener) => Stream::Vsock(listener.accept().await?.0),
+        };
+        let peer_addr = stream.peer_addr()?;
+        Ok((stream, peer_addr))
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $call:expr) => {
+        match $self.get_mut() {
+            Stream::Tcp($stream) => {
+                let $stream = Pin::new($stream);
+                $call
+            }
+            Stream::Unix($stream) => {
+                let $stream = Pin::new($stream);
+                $call
+            }
+            Stream::Vsock($stream) => {
+                let $stream = Pin::new($stream);
+                $call
+            }
+        }
+    };
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, stream => stream.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => stream.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => stream.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => stream.poll_shutdown(cx))
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Vsock(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn parse_address() {
+        for (s, addr) in [
+            ("127.0.0.1:6980", Address::Tcp("127.0.0.1:6980".to_owned())),
+            (
+                "tcp:localhost:6980",
+                Address::Tcp("localhost:6980".to_owned()),
+            ),
+            ("6979", Address::Tcp("0.0.0.0:6979".to_owned())),
+            (
+                "unix:/run/entg.sock",
+                Address::Unix("/run/entg.sock".into()),
+            ),
+            ("vsock:3:6979", Address::Vsock { cid: 3, port: 6979 }),
+            (
+                "vsock:any:6979",
+                Address::Vsock {
+                    cid: libc::VMADDR_CID_ANY,
+                    port: 6979,
+                },
+            ),
+        ] {
+            assert_eq!(s.parse::<Address>().unwrap(), addr, "{}", s);
+        }
+        assert_eq!(
+            "vsock:any:6979".parse::<Address>().unwrap().to_string(),
+            "vsock:any:6979"
+        );
+
+        for s in ["unix:", "vsock:3", "vsock:x:1", "localhost", "99999"] {
+            assert!(s.parse::<Address>().is_err(), "{}", s);
+        }
+    }
+
+    async fn echo(addr: Address) {
+        let listener = Listener::bind(&addr).await.unwrap();
+        let addr = match &listener {
+            Listener::Tcp(listener) => Address::Tcp(listener.local_addr().unwrap().to_string()),
+            _ => addr,
+        };
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut stream = connect(&addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn echo_over_tcp() {
+        echo(Address::Tcp("127.0.0.1:0".to_owned())).await;
+    }
+
+    #[tokio::test]
+    async fn echo_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("transport-{}.sock", std::process::id()));
+        // A stale socket file is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        echo(Address::Unix(path.clone())).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! AF_VSOCK stream sockets, which tokio does not provide.
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub struct VsockStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockStream {
+    pub async fn connect(cid: u32, port: u32) -> io::Result<VsockStream> {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&SockAddr::vsock(cid, port)?) {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(err) => return Err(err),
+        }
+
+        // Wait for the connection to be established, as for non-blocking TCP
+        let inner = AsyncFd::new(socket)?;
+        inner.writable().await?.retain_ready();
+        if let Some(err) = inner.get_ref().take_error()? {
+            return Err(err);
+        }
+        Ok(VsockStream { inner })
+    }
+
+    /// Returns the CID and port of the peer.
+    pub fn peer_addr(&self) -> io::Result<(u32, u32)> {
+        vsock_address(self.inner.get_ref().peer_addr()?)
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.inner.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.inner.poll_write_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            match guard.try_io(|inner| inner.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+pub struct VsockListener {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockListener {
+    pub fn bind(cid: u32, port: u32) -> io::Result<VsockListener> {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SockAddr::vsock(cid, port)?)?;
+        socket.listen(128)?;
+        Ok(VsockListener {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Accepts a new connection, returning it with the CID and port of the peer.
+    pub async fn accept(&self) -> io::Result<(VsockStream, (u32, u32))> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().accept()) {
+                Ok(Ok((socket, addr))) => {
+                    socket.set_nonblocking(true)?;
+                    let stream = VsockStream {
+                        inner: AsyncFd::new(socket)?,
+                    };
+                    return Ok((stream, vsock_address(addr)?));
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn vsock_address(addr: SockAddr) -> io::Result<(u32, u32)> {
+    addr.vsock_address()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not a vsock address"))
+}